@@ -0,0 +1,439 @@
+use crossterm::event::KeyCode;
+use std::collections::HashSet;
+
+use crate::p2p::{ChatMessage, ControlMessage, FrameData, ReactionMessage};
+use crate::status::LocalStatus;
+
+// Number keys that send a reaction while in a call.
+pub const REACTION_KEYS: [(char, &str); 5] = [
+    ('1', "👍"),
+    ('2', "❤️"),
+    ('3', "😂"),
+    ('4', "🎉"),
+    ('5', "👏"),
+];
+
+// What a handler wants sent to the other peers. Sending it, and drawing the
+// result, is left to the caller.
+#[derive(Debug)]
+pub enum OutgoingMessage {
+    Chat(ChatMessage),
+    Reaction(ReactionMessage),
+    Control(ControlMessage),
+}
+
+// A line typed into the chat box.
+#[derive(Debug, PartialEq)]
+pub enum ChatInput {
+    Message { message_id: u64, text: String },
+    Remove { peer: String, ban: bool },
+    // "/resend" lists the recent sends; "/resend 2" sends the second again.
+    Resend { number: Option<usize> },
+    // "/ignore <peer>" and "/unignore <peer>".
+    Ignore { peer: String, ignore: bool },
+}
+
+// The part of a call's state that reacts to keys and messages without
+// touching the terminal or the network, so it can be tested on its own.
+pub struct App {
+    local_peer_id: String,
+    nickname: Option<String>,
+    chat_only: bool,
+    read_receipts: bool,
+    pub is_audio_muted: bool,
+    pub is_video_muted: bool,
+    pub is_output_muted: bool,     // speakers; only ever local
+    output_toggled: bool,          // the user chose, so don't mute on their behalf
+    pub remote_video_hidden: bool, // draw placeholders instead of peers' video
+    pub hand_raised: bool,
+    pub mute_while_typing: bool,
+    typing_muted: bool, // the mic was muted for us when typing started
+    chat_seq: u64,
+    ignored: HashSet<String>, // peers whose media, chat and files we drop
+}
+
+impl App {
+    pub fn new(
+        local_peer_id: String,
+        nickname: Option<String>,
+        chat_only: bool,
+        read_receipts: bool,
+        mute_audio: bool,
+        mute_video: bool,
+        mute_output: bool,
+    ) -> Self {
+        Self {
+            local_peer_id,
+            nickname,
+            chat_only,
+            read_receipts,
+            is_audio_muted: mute_audio || chat_only,
+            is_video_muted: mute_video || chat_only,
+            is_output_muted: mute_output,
+            output_toggled: mute_output,
+            remote_video_hidden: false,
+            hand_raised: false,
+            mute_while_typing: false,
+            typing_muted: false,
+            chat_seq: 0,
+            ignored: HashSet::new(),
+        }
+    }
+
+    // Handles the keys that only change call state: m and v mute, d mutes
+    // the speakers, r hides peers' video, h raises a hand and the number
+    // keys react. State changes whether or not we're in a call yet, but
+    // nothing is sent before then.
+    pub fn on_key(&mut self, key: KeyCode, in_call: bool) -> Option<OutgoingMessage> {
+        match key {
+            KeyCode::Char(key @ ('m' | 'v')) if !self.chat_only => {
+                if key == 'm' {
+                    self.is_audio_muted = !self.is_audio_muted;
+                    // The user's choice stands once typing ends.
+                    self.typing_muted = false;
+                } else {
+                    self.is_video_muted = !self.is_video_muted;
+                }
+                self.mute_state(in_call)
+            }
+            KeyCode::Char('d') if !self.chat_only => {
+                self.is_output_muted = !self.is_output_muted;
+                self.output_toggled = true;
+                None
+            }
+            KeyCode::Char('r') if !self.chat_only => {
+                self.remote_video_hidden = !self.remote_video_hidden;
+                None
+            }
+            KeyCode::Char('h') if in_call => {
+                self.hand_raised = !self.hand_raised;
+                Some(OutgoingMessage::Control(ControlMessage::HandRaised {
+                    peer_id: self.local_peer_id.clone(),
+                    raised: self.hand_raised,
+                }))
+            }
+            KeyCode::Char(c) if in_call => {
+                let (_, emoji) = REACTION_KEYS.iter().find(|(key, _)| *key == c)?;
+                Some(OutgoingMessage::Reaction(ReactionMessage {
+                    peer_id: self.local_peer_id.clone(),
+                    emoji: emoji.to_string(),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    // With --mute-while-typing, mutes the mic while the chat box is open and
+    // unmutes it once it closes, unless the mic was already muted before.
+    pub fn on_typing(&mut self, typing: bool, in_call: bool) -> Option<OutgoingMessage> {
+        if typing && self.mute_while_typing && !self.is_audio_muted {
+            self.is_audio_muted = true;
+            self.typing_muted = true;
+        } else if !typing && self.typing_muted {
+            self.is_audio_muted = false;
+            self.typing_muted = false;
+        } else {
+            return None;
+        }
+        self.mute_state(in_call)
+    }
+
+    fn mute_state(&self, in_call: bool) -> Option<OutgoingMessage> {
+        in_call.then(|| {
+            OutgoingMessage::Control(ControlMessage::MuteState {
+                peer_id: self.local_peer_id.clone(),
+                is_audio_muted: self.is_audio_muted,
+                is_video_muted: self.is_video_muted,
+            })
+        })
+    }
+
+    // Turns a submitted line into a chat message or a /kick, /ban or
+    // /resend command. Only messages use up a message id.
+    pub fn on_submit(&mut self, text: &str) -> ChatInput {
+        match parse_command(text) {
+            Some((command @ ("/kick" | "/ban"), argument)) => {
+                return ChatInput::Remove {
+                    peer: argument.to_string(),
+                    ban: command == "/ban",
+                };
+            }
+            Some(("/resend", argument)) => {
+                return ChatInput::Resend {
+                    number: argument.parse().ok(),
+                };
+            }
+            Some((command @ ("/ignore" | "/unignore"), argument)) => {
+                return ChatInput::Ignore {
+                    peer: argument.to_string(),
+                    ignore: command == "/ignore",
+                };
+            }
+            _ => {}
+        }
+        self.chat_seq += 1;
+        ChatInput::Message {
+            message_id: self.chat_seq,
+            text: text.to_string(),
+        }
+    }
+
+    pub fn local_status(&self) -> LocalStatus {
+        LocalStatus {
+            mic_muted: self.is_audio_muted,
+            speakers_muted: self.is_output_muted,
+            camera_off: self.is_video_muted,
+            remote_video_hidden: self.remote_video_hidden,
+        }
+    }
+
+    // Two instances on one machine feed each other's speakers straight back
+    // into their microphones, so their speakers start muted. Returns true if
+    // they were muted now; a choice the user already made is left alone.
+    pub fn on_local_peer(&mut self) -> bool {
+        if self.output_toggled || self.is_output_muted || self.chat_only {
+            return false;
+        }
+        self.is_output_muted = true;
+        true
+    }
+
+    pub fn chat_message(&self, message_id: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            peer_id: self.local_peer_id.clone(),
+            nickname: self.nickname.clone(),
+            message: text.to_string(),
+            message_id,
+        }
+    }
+
+    pub fn ignore(&mut self, peer_id: &str) {
+        self.ignored.insert(peer_id.to_string());
+    }
+
+    // Takes the end of a peer id, like /kick, since an ignored peer may have
+    // left the call already. Returns the full id.
+    pub fn unignore(&mut self, query: &str) -> Result<String, String> {
+        let matches: Vec<&String> = self
+            .ignored
+            .iter()
+            .filter(|peer_id| !query.is_empty() && peer_id.ends_with(query))
+            .collect();
+        let peer_id = match matches.as_slice() {
+            [peer_id] => (*peer_id).clone(),
+            [] => return Err(format!("No ignored peer matches '{}'", query)),
+            _ => return Err(format!("'{}' matches more than one ignored peer", query)),
+        };
+        self.ignored.remove(&peer_id);
+        Ok(peer_id)
+    }
+
+    pub fn is_ignored(&self, peer_id: &str) -> bool {
+        self.ignored.contains(peer_id)
+    }
+
+    // Returns the line to show for a chat message from another peer, and the
+    // read receipt to send back if we send them. Our own messages come back
+    // through gossipsub and are ignored.
+    pub fn on_chat(&self, message: ChatMessage) -> Option<(String, Option<OutgoingMessage>)> {
+        if message.peer_id == self.local_peer_id || self.is_ignored(&message.peer_id) {
+            return None;
+        }
+        let sender = message
+            .nickname
+            .clone()
+            .unwrap_or_else(|| crate::util::short_peer_id(&message.peer_id).to_string());
+        let line = format!("{}: {}", sender, message.message);
+        let receipt = (self.read_receipts && message.message_id != 0).then(|| {
+            OutgoingMessage::Control(ControlMessage::Seen {
+                peer_id: self.local_peer_id.clone(),
+                sender: message.peer_id,
+                message_id: message.message_id,
+            })
+        });
+        Some((line, receipt))
+    }
+
+    // Whether a received frame should be shown; ours are already on screen.
+    pub fn on_frame(&self, frame: &FrameData) -> bool {
+        frame.peer_id != self.local_peer_id && !self.is_ignored(&frame.peer_id)
+    }
+}
+
+// Splits "/kick <peer>", "/resend [number]" and the other commands typed
+// into the chat box.
+fn parse_command(text: &str) -> Option<(&str, &str)> {
+    let (command, argument) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+    matches!(
+        command,
+        "/kick" | "/ban" | "/resend" | "/ignore" | "/unignore"
+    )
+    .then(|| (command, argument.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        App::new("me".to_string(), None, false, true, false, false, false)
+    }
+
+    fn chat_from(peer_id: &str, message_id: u64) -> ChatMessage {
+        ChatMessage {
+            peer_id: peer_id.to_string(),
+            nickname: Some("Ann".to_string()),
+            message: "hi".to_string(),
+            message_id,
+        }
+    }
+
+    #[test]
+    fn mute_toggles_are_only_announced_in_a_call() {
+        let mut app = app();
+        assert!(app.on_key(KeyCode::Char('m'), false).is_none());
+        assert!(app.is_audio_muted);
+        match app.on_key(KeyCode::Char('v'), true) {
+            Some(OutgoingMessage::Control(ControlMessage::MuteState {
+                is_audio_muted,
+                is_video_muted,
+                ..
+            })) => assert!(is_audio_muted && is_video_muted),
+            other => panic!("unexpected {:?}", other),
+        }
+        app.on_key(KeyCode::Char('m'), true);
+        assert!(!app.is_audio_muted);
+    }
+
+    #[test]
+    fn chat_only_peers_stay_muted() {
+        let mut app = App::new("me".to_string(), None, true, true, false, false, false);
+        assert!(app.on_key(KeyCode::Char('m'), true).is_none());
+        assert!(app.is_audio_muted && app.is_video_muted);
+    }
+
+    #[test]
+    fn speakers_mute_for_local_peers_unless_the_user_chose() {
+        let mut app = app();
+        assert!(app.on_local_peer());
+        assert!(app.is_output_muted && !app.is_audio_muted);
+        assert!(app.on_key(KeyCode::Char('d'), true).is_none());
+        assert!(!app.is_output_muted);
+        // Unmuted by hand, so another local peer doesn't mute again.
+        assert!(!app.on_local_peer());
+        assert!(!app.is_output_muted);
+    }
+
+    #[test]
+    fn local_switches_are_never_sent() {
+        let mut app = app();
+        assert!(app.on_key(KeyCode::Char('r'), true).is_none());
+        assert!(app.on_key(KeyCode::Char('d'), true).is_none());
+        let status = app.local_status();
+        assert!(status.remote_video_hidden && status.speakers_muted);
+        assert!(!status.mic_muted && !status.camera_off);
+    }
+
+    #[test]
+    fn reactions_and_hands_need_a_call() {
+        let mut app = app();
+        assert!(app.on_key(KeyCode::Char('1'), false).is_none());
+        assert!(app.on_key(KeyCode::Char('h'), false).is_none());
+        assert!(!app.hand_raised);
+        match app.on_key(KeyCode::Char('1'), true) {
+            Some(OutgoingMessage::Reaction(reaction)) => assert_eq!(reaction.emoji, "👍"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(app.on_key(KeyCode::Char('9'), true).is_none());
+        app.on_key(KeyCode::Char('h'), true);
+        assert!(app.hand_raised);
+    }
+
+    #[test]
+    fn submitted_lines_become_messages_or_removals() {
+        let mut app = app();
+        assert_eq!(
+            app.on_submit(" /ban  12D3Koo "),
+            ChatInput::Remove {
+                peer: "12D3Koo".to_string(),
+                ban: true
+            }
+        );
+        assert_eq!(
+            app.on_submit("/kick"),
+            ChatInput::Remove {
+                peer: String::new(),
+                ban: false
+            }
+        );
+        assert_eq!(
+            app.on_submit("/kicked out"),
+            ChatInput::Message {
+                message_id: 1,
+                text: "/kicked out".to_string()
+            }
+        );
+        assert!(matches!(
+            app.on_submit("hello"),
+            ChatInput::Message { message_id: 2, .. }
+        ));
+        assert_eq!(
+            app.on_submit("/resend 2"),
+            ChatInput::Resend { number: Some(2) }
+        );
+        assert_eq!(app.on_submit("/resend"), ChatInput::Resend { number: None });
+    }
+
+    #[test]
+    fn ignored_peers_are_silenced_until_unignored() {
+        let mut app = app();
+        assert_eq!(
+            app.on_submit("/ignore 12D3Koo"),
+            ChatInput::Ignore {
+                peer: "12D3Koo".to_string(),
+                ignore: true
+            }
+        );
+        app.ignore("them");
+        assert!(app.on_chat(chat_from("them", 1)).is_none());
+        assert!(app.on_chat(chat_from("others", 1)).is_some());
+        assert!(app.unignore("").is_err());
+        assert_eq!(app.unignore("em"), Ok("them".to_string()));
+        assert!(app.on_chat(chat_from("them", 1)).is_some());
+        assert!(app.unignore("em").is_err());
+    }
+
+    #[test]
+    fn incoming_chat_gets_a_receipt_unless_it_is_ours() {
+        let app = app();
+        assert!(app.on_chat(chat_from("me", 1)).is_none());
+        let (line, receipt) = app.on_chat(chat_from("them", 3)).unwrap();
+        assert_eq!(line, "Ann: hi");
+        assert!(matches!(
+            receipt,
+            Some(OutgoingMessage::Control(ControlMessage::Seen {
+                message_id: 3,
+                ..
+            }))
+        ));
+        // Old peers don't number their messages, so there's nothing to answer.
+        assert!(app.on_chat(chat_from("them", 0)).unwrap().1.is_none());
+    }
+
+    #[test]
+    fn typing_only_unmutes_what_it_muted() {
+        let mut app = app();
+        assert!(app.on_typing(true, true).is_none());
+        app.mute_while_typing = true;
+        assert!(app.on_typing(true, true).is_some());
+        assert!(app.is_audio_muted);
+        assert!(app.on_typing(false, true).is_some());
+        assert!(!app.is_audio_muted);
+
+        // Already muted: leaving the chat box keeps it that way.
+        app.on_key(KeyCode::Char('m'), true);
+        assert!(app.on_typing(true, true).is_none());
+        assert!(app.on_typing(false, true).is_none());
+        assert!(app.is_audio_muted);
+    }
+}
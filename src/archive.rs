@@ -0,0 +1,235 @@
+use flate2::{write::DeflateEncoder, Compression, Crc};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+// Just enough of the zip format to pack a folder for sending: deflated
+// files and directory entries, no zip64, no encryption.
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const VERSION: u16 = 20;
+const UTF8_NAMES: u16 = 1 << 11;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+// 1980-01-01 00:00, the earliest DOS timestamp. Peers only use the data.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+const DIRECTORY_ATTRIBUTE: u32 = 0x10;
+
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+    is_dir: bool,
+}
+
+// Zips a folder in memory. Entries are named relative to the folder's
+// parent, so the archive unpacks into a folder of the same name.
+pub fn zip_folder(folder: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let root = folder
+        .file_name()
+        .ok_or("Folder has no name")?
+        .to_string_lossy()
+        .into_owned();
+    let mut archive = Vec::new();
+    let mut entries = Vec::new();
+    add_folder(&mut archive, &mut entries, folder, &root)?;
+
+    let directory_offset = to_u32(archive.len())?;
+    for entry in &entries {
+        write_central_header(&mut archive, entry);
+    }
+    let directory_size = to_u32(archive.len())? - directory_offset;
+    let count = u16::try_from(entries.len()).map_err(|_| "Too many files to zip")?;
+    put_u32(&mut archive, END_OF_DIRECTORY_SIGNATURE);
+    put_u16(&mut archive, 0); // this disk
+    put_u16(&mut archive, 0); // disk with the central directory
+    put_u16(&mut archive, count);
+    put_u16(&mut archive, count);
+    put_u32(&mut archive, directory_size);
+    put_u32(&mut archive, directory_offset);
+    put_u16(&mut archive, 0); // comment length
+    Ok(archive)
+}
+
+fn add_folder(
+    archive: &mut Vec<u8>,
+    entries: &mut Vec<Entry>,
+    folder: &Path,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    add_entry(archive, entries, format!("{}/", name), None)?;
+
+    let mut children: Vec<_> = std::fs::read_dir(folder)?.collect::<Result<_, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let child_name = format!("{}/{}", name, child.file_name().to_string_lossy());
+        // Links could point outside the folder or back into it.
+        let file_type = child.file_type()?;
+        if file_type.is_dir() {
+            add_folder(archive, entries, &child.path(), &child_name)?;
+        } else if file_type.is_file() {
+            let contents = std::fs::read(child.path())?;
+            add_entry(archive, entries, child_name, Some(&contents))?;
+        } else {
+            log::info!("Not zipping {}: not a regular file", child.path().display());
+        }
+    }
+    Ok(())
+}
+
+// A None body adds a directory entry.
+fn add_entry(
+    archive: &mut Vec<u8>,
+    entries: &mut Vec<Entry>,
+    name: String,
+    contents: Option<&[u8]>,
+) -> Result<(), Box<dyn Error>> {
+    let is_dir = contents.is_none();
+    let contents = contents.unwrap_or_default();
+    let mut crc = Crc::new();
+    crc.update(contents);
+    let (method, data) = if is_dir {
+        (STORED, Vec::new())
+    } else {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents)?;
+        (DEFLATED, encoder.finish()?)
+    };
+    let entry = Entry {
+        method,
+        crc: crc.sum(),
+        compressed_size: to_u32(data.len())?,
+        size: to_u32(contents.len())?,
+        offset: to_u32(archive.len())?,
+        is_dir,
+        name,
+    };
+
+    put_u32(archive, LOCAL_HEADER_SIGNATURE);
+    put_u16(archive, VERSION);
+    put_u16(archive, UTF8_NAMES);
+    put_u16(archive, entry.method);
+    put_u16(archive, DOS_TIME);
+    put_u16(archive, DOS_DATE);
+    put_u32(archive, entry.crc);
+    put_u32(archive, entry.compressed_size);
+    put_u32(archive, entry.size);
+    put_u16(archive, entry.name.len() as u16);
+    put_u16(archive, 0); // extra field length
+    archive.extend_from_slice(entry.name.as_bytes());
+    archive.extend_from_slice(&data);
+    entries.push(entry);
+    Ok(())
+}
+
+fn write_central_header(archive: &mut Vec<u8>, entry: &Entry) {
+    put_u32(archive, CENTRAL_HEADER_SIGNATURE);
+    put_u16(archive, VERSION); // made by
+    put_u16(archive, VERSION); // needed to extract
+    put_u16(archive, UTF8_NAMES);
+    put_u16(archive, entry.method);
+    put_u16(archive, DOS_TIME);
+    put_u16(archive, DOS_DATE);
+    put_u32(archive, entry.crc);
+    put_u32(archive, entry.compressed_size);
+    put_u32(archive, entry.size);
+    put_u16(archive, entry.name.len() as u16);
+    put_u16(archive, 0); // extra field length
+    put_u16(archive, 0); // comment length
+    put_u16(archive, 0); // starting disk
+    put_u16(archive, 0); // internal attributes
+    put_u32(archive, if entry.is_dir { DIRECTORY_ATTRIBUTE } else { 0 });
+    put_u32(archive, entry.offset);
+    archive.extend_from_slice(entry.name.as_bytes());
+}
+
+fn to_u32(value: usize) -> Result<u32, Box<dyn Error>> {
+    u32::try_from(value).map_err(|_| "Folder is too large to zip".into())
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    // Reads the entries back by walking the central directory.
+    fn unzip(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), END_OF_DIRECTORY_SIGNATURE);
+        let count = u16_at(archive, end + 10);
+        let mut at = u32_at(archive, end + 16) as usize;
+        let mut files = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(archive, at), CENTRAL_HEADER_SIGNATURE);
+            let method = u16_at(archive, at + 10);
+            let crc = u32_at(archive, at + 16);
+            let compressed_size = u32_at(archive, at + 20) as usize;
+            let name_len = u16_at(archive, at + 28) as usize;
+            let offset = u32_at(archive, at + 42) as usize;
+            let name = String::from_utf8(archive[at + 46..at + 46 + name_len].to_vec()).unwrap();
+
+            assert_eq!(u32_at(archive, offset), LOCAL_HEADER_SIGNATURE);
+            let data_start = offset + 30 + u16_at(archive, offset + 26) as usize;
+            let data = &archive[data_start..data_start + compressed_size];
+            let contents = if method == DEFLATED {
+                let mut contents = Vec::new();
+                DeflateDecoder::new(data)
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                contents
+            } else {
+                data.to_vec()
+            };
+            let mut check = Crc::new();
+            check.update(&contents);
+            assert_eq!(check.sum(), crc);
+            files.push((name, contents));
+            at += 46 + name_len;
+        }
+        files
+    }
+
+    #[test]
+    fn zipped_folder_round_trips() {
+        let folder = std::env::temp_dir().join(format!("rust-meet-zip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(folder.join("notes/empty")).unwrap();
+        std::fs::write(folder.join("a.txt"), "hello hello hello").unwrap();
+        std::fs::write(folder.join("notes/b.bin"), [0u8, 1, 2, 255]).unwrap();
+
+        let archive = zip_folder(&folder).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        let root = folder.file_name().unwrap().to_string_lossy().into_owned();
+        let expected = vec![
+            (format!("{}/", root), Vec::new()),
+            (format!("{}/a.txt", root), b"hello hello hello".to_vec()),
+            (format!("{}/notes/", root), Vec::new()),
+            (format!("{}/notes/b.bin", root), vec![0, 1, 2, 255]),
+            (format!("{}/notes/empty/", root), Vec::new()),
+        ];
+        assert_eq!(unzip(&archive), expected);
+    }
+}
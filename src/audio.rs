@@ -1,65 +1,562 @@
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, Sample, SampleFormat, Stream, StreamConfig,
+    BufferSize, Device, Sample, SampleFormat, Stream, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfig,
 };
 use std::error::Error;
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Length of the audio in each published message.
+pub const DEFAULT_FRAME_MS: u32 = 20;
+pub const MIN_FRAME_MS: u32 = 5;
+pub const MAX_FRAME_MS: u32 = 100;
+const MAX_PLAYBACK_DELAY_MS: u32 = 200;
+
+// How much of the displayed level is kept on each meter tick.
+const LEVEL_DECAY: f32 = 0.85;
+// Floor for the dB readout so silence doesn't print -inf.
+const MIN_LEVEL_DB: f32 = -60.0;
+// Linear level of MIN_LEVEL_DB.
+const IDLE_LEVEL: f32 = 0.001;
+
+// Speech level the AGC aims for, and how far it may boost or cut to get there.
+const AGC_TARGET_RMS: f32 = 0.1;
+const AGC_MIN_GAIN: f32 = 0.1;
+const AGC_MAX_GAIN: f32 = 10.0;
+// Weight of each new chunk in the AGC's running level.
+const AGC_LEVEL_SMOOTHING: f32 = 0.1;
+// Most the gain may change per chunk (5%), so it follows the speaker
+// rather than each syllable.
+const AGC_MAX_STEP: f32 = 1.05;
+
+// Played samples kept to look for echoes in, about half a second at 48kHz,
+// which covers the speaker-to-mic delay of most setups.
+const ECHO_HISTORY: usize = 24_000;
+// The echo search only looks at every 4th sample and every 8th lag, which is
+// still precise enough for speech and keeps the cost per chunk down.
+const ECHO_DECIMATION: usize = 4;
+// In decimated samples.
+const ECHO_LAG_STEP: usize = 2;
+// Correlation with recent output above which the mic is taken to be
+// hearing the speakers rather than us.
+const ECHO_CORRELATION: f32 = 0.6;
+// Gain applied to echo, and how fast it's lifted again once it stops.
+const ECHO_ATTENUATION: f32 = 0.05;
+const ECHO_RELEASE: f32 = 1.5;
+
+// Peak/RMS meter for a stream of sample chunks. Jumps up immediately and
+// falls back gradually so it reads like a hardware VU meter.
+#[derive(Default, Clone)]
+pub struct LevelMeter {
+    peak: f32,
+    rms: f32,
+}
+
+impl LevelMeter {
+    pub fn update(&mut self, samples: &[f32]) {
+        let peak = samples.iter().fold(0.0f32, |acc, s| acc.max(s.abs()));
+        self.peak = self.peak.max(peak.min(1.0));
+        self.rms = self.rms.max(rms(samples).min(1.0));
+    }
+
+    pub fn decay(&mut self) {
+        self.peak *= LEVEL_DECAY;
+        self.rms *= LEVEL_DECAY;
+    }
+
+    // Below anything the gauge or dB readout can show.
+    pub fn is_idle(&self) -> bool {
+        self.peak < IDLE_LEVEL && self.rms < IDLE_LEVEL
+    }
+
+    pub fn level(&self) -> f32 {
+        self.peak
+    }
+
+    pub fn rms_db(&self) -> f32 {
+        if self.rms <= 0.0 {
+            return MIN_LEVEL_DB;
+        }
+        (20.0 * self.rms.log10()).max(MIN_LEVEL_DB)
+    }
+}
+
+// Automatic gain control for the microphone. Tracks a running level and
+// slews the gain towards whatever brings it to AGC_TARGET_RMS.
+pub struct Agc {
+    level: f32,
+    gain: f32,
+}
+
+impl Default for Agc {
+    fn default() -> Self {
+        Self {
+            level: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Agc {
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let chunk_rms = rms(samples);
+        // Pauses would otherwise wind the gain up to the maximum and blast
+        // the start of the next sentence.
+        if chunk_rms >= IDLE_LEVEL {
+            self.level = if self.level == 0.0 {
+                chunk_rms
+            } else {
+                self.level + (chunk_rms - self.level) * AGC_LEVEL_SMOOTHING
+            };
+            let wanted = (AGC_TARGET_RMS / self.level).clamp(AGC_MIN_GAIN, AGC_MAX_GAIN);
+            self.gain = wanted.clamp(self.gain / AGC_MAX_STEP, self.gain * AGC_MAX_STEP);
+        }
+        for sample in samples.iter_mut() {
+            *sample = (*sample * self.gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+// Fixed-size queue of samples between one producer and one consumer.
+// Neither end locks or allocates, so the audio callbacks can use it.
+struct SampleRing {
+    slots: Box<[AtomicU32]>,
+    // Samples ever pushed and popped; the difference is what's queued.
+    pushed: AtomicUsize,
+    popped: AtomicUsize,
+}
+
+impl SampleRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            pushed: AtomicUsize::new(0),
+            popped: AtomicUsize::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let popped = self.popped.load(Ordering::Acquire);
+        self.pushed.load(Ordering::Acquire).wrapping_sub(popped)
+    }
+
+    // Producer only. Whatever doesn't fit is dropped.
+    fn push(&self, samples: &[f32]) {
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        let queued = pushed.wrapping_sub(self.popped.load(Ordering::Acquire));
+        let fits = samples.len().min(self.slots.len() - queued);
+        for (i, sample) in samples[..fits].iter().enumerate() {
+            self.slots[pushed.wrapping_add(i) % self.slots.len()]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.pushed
+            .store(pushed.wrapping_add(fits), Ordering::Release);
+    }
+
+    // Consumer only.
+    fn pop(&self) -> Option<f32> {
+        let popped = self.popped.load(Ordering::Relaxed);
+        if popped == self.pushed.load(Ordering::Acquire) {
+            return None;
+        }
+        let bits = self.slots[popped % self.slots.len()].load(Ordering::Relaxed);
+        self.popped.store(popped.wrapping_add(1), Ordering::Release);
+        Some(f32::from_bits(bits))
+    }
+
+    // Consumer only. Drops the oldest samples until at most `keep` are left.
+    fn keep_newest(&self, keep: usize) {
+        let excess = self.len().saturating_sub(keep);
+        self.popped.fetch_add(excess, Ordering::Release);
+    }
+}
+
+// The last ECHO_HISTORY samples played. The output callback writes it while
+// the input callback reads it, and neither waits for the other. A read that
+// races a write may mix in a few newer samples, which the echo check shrugs
+// off.
+struct PlaybackHistory {
+    slots: Box<[AtomicU32]>,
+    written: AtomicUsize,
+}
+
+impl PlaybackHistory {
+    fn record(&self, sample: f32) {
+        let written = self.written.load(Ordering::Relaxed);
+        self.slots[written % ECHO_HISTORY].store(sample.to_bits(), Ordering::Relaxed);
+        self.written
+            .store(written.wrapping_add(1), Ordering::Release);
+    }
+
+    // Every `step`th sample still in the history, oldest first.
+    fn copy_into(&self, step: usize, out: &mut Vec<f32>) {
+        let written = self.written.load(Ordering::Acquire);
+        out.clear();
+        out.extend(
+            (written.saturating_sub(ECHO_HISTORY)..written)
+                .step_by(step)
+                .map(|i| f32::from_bits(self.slots[i % ECHO_HISTORY].load(Ordering::Relaxed))),
+        );
+    }
+}
+
+// Output side of the echo suppressor: records what went to the speakers.
+#[derive(Clone)]
+pub struct EchoReference {
+    history: Arc<PlaybackHistory>,
+}
+
+impl EchoReference {
+    // Takes samples as they're played, so the output callback needn't
+    // collect them first.
+    pub fn record(&self, samples: impl Iterator<Item = f32>) {
+        for sample in samples {
+            self.history.record(sample);
+        }
+    }
+}
+
+// Acoustic echo suppression for setups without it in hardware. Rather than
+// modelling the room, it gates the mic whenever its input closely follows
+// something played in the last ECHO_HISTORY samples.
+pub struct EchoSuppressor {
+    history: Arc<PlaybackHistory>,
+    gain: f32,
+    // Decimated copies of the input and the history, kept to be reused.
+    input: Vec<f32>,
+    played: Vec<f32>,
+}
+
+pub fn echo_suppressor() -> (EchoReference, EchoSuppressor) {
+    let history = Arc::new(PlaybackHistory {
+        slots: (0..ECHO_HISTORY).map(|_| AtomicU32::new(0)).collect(),
+        written: AtomicUsize::new(0),
+    });
+    let decimated = ECHO_HISTORY / ECHO_DECIMATION + 1;
+    (
+        EchoReference {
+            history: history.clone(),
+        },
+        EchoSuppressor {
+            history,
+            gain: 1.0,
+            input: Vec::with_capacity(decimated),
+            played: Vec::with_capacity(decimated),
+        },
+    )
+}
+
+impl EchoSuppressor {
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let target = if self.is_echo(samples) {
+            ECHO_ATTENUATION
+        } else {
+            1.0
+        };
+        // Cut at once so no echo slips through, but fade back in.
+        self.gain = if target < self.gain {
+            target
+        } else {
+            (self.gain * ECHO_RELEASE).min(target)
+        };
+        for sample in samples.iter_mut() {
+            *sample *= self.gain;
+        }
+    }
+
+    fn is_echo(&mut self, samples: &[f32]) -> bool {
+        // Longer than the history, it couldn't be matched anyway.
+        if samples.len() > ECHO_HISTORY || rms(samples) < IDLE_LEVEL {
+            return false;
+        }
+        self.input.clear();
+        self.input.extend(samples.iter().step_by(ECHO_DECIMATION));
+        self.history.copy_into(ECHO_DECIMATION, &mut self.played);
+        let (input, played) = (&self.input, &self.played);
+        if played.len() < input.len() {
+            return false;
+        }
+        let input_energy: f32 = input.iter().map(|s| s * s).sum();
+        // A sum of squares like the energies, over as many samples.
+        let idle_energy = IDLE_LEVEL * IDLE_LEVEL * input.len() as f32;
+        (0..=played.len() - input.len())
+            .step_by(ECHO_LAG_STEP)
+            .any(|lag| {
+                let window = &played[lag..lag + input.len()];
+                let window_energy: f32 = window.iter().map(|s| s * s).sum();
+                if window_energy < idle_energy {
+                    return false;
+                }
+                let dot: f32 = input.iter().zip(window).map(|(a, b)| a * b).sum();
+                dot / (input_energy * window_energy).sqrt() >= ECHO_CORRELATION
+            })
+    }
+}
+
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+// One line per audio device, marking the defaults and their stream formats.
+pub fn describe_devices() -> Result<Vec<String>, Box<dyn Error>> {
+    let host = cpal::default_host();
+    let mut lines = vec![format!("Host: {}", host.id().name())];
+
+    let default_input = host.default_input_device().and_then(|d| d.name().ok());
+    for device in host.input_devices()? {
+        let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+        let is_default = default_input.as_deref() == Some(name.as_str());
+        let format = device
+            .default_input_config()
+            .map(|c| describe_config(&c))
+            .unwrap_or_else(|e| format!("no usable config ({})", e));
+        lines.push(format!(
+            "Input{}: {} - {}",
+            if is_default { " (default)" } else { "" },
+            name,
+            format
+        ));
+    }
+
+    let default_output = host.default_output_device().and_then(|d| d.name().ok());
+    for device in host.output_devices()? {
+        let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+        let is_default = default_output.as_deref() == Some(name.as_str());
+        let format = device
+            .default_output_config()
+            .map(|c| describe_config(&c))
+            .unwrap_or_else(|e| format!("no usable config ({})", e));
+        lines.push(format!(
+            "Output{}: {} - {}",
+            if is_default { " (default)" } else { "" },
+            name,
+            format
+        ));
+    }
+
+    Ok(lines)
+}
+
+fn describe_config(config: &cpal::SupportedStreamConfig) -> String {
+    format!(
+        "{} Hz, {} ch, {:?}",
+        config.sample_rate().0,
+        config.channels(),
+        config.sample_format()
+    )
+}
+
+// Collects the device's buffers, whatever their size, into frames of a fixed
+// length so every audio message covers the same stretch of time.
+struct Framer {
+    frame_len: usize,
+    pending: Vec<f32>,
+}
+
+impl Framer {
+    fn new(frame_len: usize) -> Self {
+        Self {
+            frame_len: frame_len.max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(samples);
+        let full = self.pending.len() / self.frame_len * self.frame_len;
+        let frames = self.pending[..full]
+            .chunks(self.frame_len)
+            .map(<[f32]>::to_vec)
+            .collect();
+        self.pending.drain(..full);
+        frames
+    }
+}
+
+// Asks the device for buffers of one frame where it takes a fixed size, so
+// frames don't straddle callbacks; otherwise it keeps its own.
+fn frame_buffer_size(supported: &SupportedBufferSize, frame_samples: u32) -> BufferSize {
+    match supported {
+        SupportedBufferSize::Range { min, max } if (*min..=*max).contains(&frame_samples) => {
+            BufferSize::Fixed(frame_samples)
+        }
+        _ => BufferSize::Default,
+    }
+}
+
+// The running streams and the call loop's end of each: frames captured from
+// the mic, and a queue of samples for the speakers. The callbacks only ever
+// touch the rings, so they never lock or allocate.
+pub struct AudioStreams {
+    _input: Stream,
+    _output: Stream,
+    captured: Arc<SampleRing>,
+    framer: Framer,
+    playback: Arc<SampleRing>,
+}
+
+impl AudioStreams {
+    // Whole frames captured since the last call.
+    pub fn captured_frames(&mut self) -> Vec<Vec<f32>> {
+        let samples: Vec<f32> = (0..self.captured.len())
+            .map_while(|_| self.captured.pop())
+            .collect();
+        self.framer.push(&samples)
+    }
+
+    // Queues samples for the speakers. If they've stalled and the queue is
+    // full, the newest are dropped.
+    pub fn play(&self, samples: &[f32]) {
+        self.playback.push(samples);
+    }
+}
+
+// The input's samples go out as they are and peers' samples are played as
+// they arrive, so the output has to run at the input's rate and channels.
+fn matching_output_config(
+    device: &Device,
+    input: &SupportedStreamConfig,
+) -> Result<SupportedStreamConfig, Box<dyn Error>> {
+    let default = device.default_output_config()?;
+    if default.sample_rate() == input.sample_rate() && default.channels() == input.channels() {
+        return Ok(default);
+    }
+    device
+        .supported_output_configs()?
+        .find(|range| {
+            range.channels() == input.channels()
+                && range.sample_format() == SampleFormat::F32
+                && (range.min_sample_rate()..=range.max_sample_rate())
+                    .contains(&input.sample_rate())
+        })
+        .map(|range| range.with_sample_rate(input.sample_rate()))
+        .ok_or_else(|| {
+            format!(
+                "The output device can't play the input's format ({}), its default is {}",
+                describe_config(input),
+                describe_config(&default)
+            )
+            .into()
+        })
+}
 
 pub fn setup_audio_streams(
-    audio_sender: mpsc::UnboundedSender<Vec<f32>>,
-    audio_receiver: mpsc::UnboundedReceiver<Vec<f32>>,
-) -> Result<(Stream, Stream), Box<dyn Error>> {
+    agc: bool,
+    aec: bool,
+    frame_ms: u32,
+) -> Result<AudioStreams, Box<dyn Error>> {
     let host = cpal::default_host();
+    let (echo_reference, echo_suppressor) = match aec.then(echo_suppressor) {
+        Some((reference, suppressor)) => (Some(reference), Some(suppressor)),
+        None => (None, None),
+    };
 
     // Input stream
     let input_device = host
         .default_input_device()
         .ok_or("No input device available")?;
     let input_config = input_device.default_input_config()?;
-    let input_stream = match input_config.sample_format() {
-        SampleFormat::F32 => {
-            create_input_stream::<f32>(&input_device, &input_config.into(), audio_sender)
-        }
-        _ => Err("Unsupported sample format".into()),
-    }?;
-
-    // Output stream
     let output_device = host
         .default_output_device()
         .ok_or("No output device available")?;
-    let output_config = output_device.default_output_config()?;
-    let output_stream = match output_config.sample_format() {
-        SampleFormat::F32 => {
-            create_output_stream::<f32>(&output_device, &output_config.into(), audio_receiver)
-        }
+    let output_config = matching_output_config(&output_device, &input_config)?;
+
+    // Per channel; the samples we get are interleaved.
+    let frame_samples = input_config.sample_rate().0 * frame_ms / 1000;
+    let buffer_size = frame_buffer_size(input_config.buffer_size(), frame_samples);
+    let framer = Framer::new(frame_samples as usize * input_config.channels() as usize);
+    // A second of audio, far more than the call loop leaves between reads.
+    let captured = Arc::new(SampleRing::new(
+        input_config.sample_rate().0 as usize * input_config.channels() as usize,
+    ));
+    let input_stream = match input_config.sample_format() {
+        SampleFormat::F32 => create_input_stream::<f32>(
+            &input_device,
+            &StreamConfig {
+                buffer_size,
+                ..input_config.into()
+            },
+            captured.clone(),
+            agc,
+            echo_suppressor,
+        ),
         _ => Err("Unsupported sample format".into()),
     }?;
 
+    let output_config: StreamConfig = output_config.into();
+    let max_queued = (output_config.sample_rate.0 * MAX_PLAYBACK_DELAY_MS / 1000) as usize
+        * output_config.channels as usize;
+    let playback = Arc::new(SampleRing::new(max_queued * 2));
+    let output_stream = create_output_stream::<f32>(
+        &output_device,
+        &output_config,
+        playback.clone(),
+        max_queued,
+        echo_reference,
+    )?;
+
     input_stream.play()?;
     output_stream.play()?;
 
-    Ok((input_stream, output_stream))
+    Ok(AudioStreams {
+        _input: input_stream,
+        _output: output_stream,
+        captured,
+        framer,
+        playback,
+    })
+}
+
+// Calls can go ahead without audio (no sound card in containers or CI), so
+// a failed setup is only logged.
+pub fn try_setup_audio_streams(agc: bool, aec: bool, frame_ms: u32) -> Option<AudioStreams> {
+    setup_audio_streams(agc, aec, frame_ms)
+        .map_err(|e| {
+            log::warn!(
+                "Audio unavailable, continuing with video and chat only: {}",
+                e
+            )
+        })
+        .ok()
 }
 
 fn create_input_stream<T>(
     device: &Device,
     config: &StreamConfig,
-    sender: mpsc::UnboundedSender<Vec<f32>>,
+    captured: Arc<SampleRing>,
+    agc: bool,
+    mut echo_suppressor: Option<EchoSuppressor>,
 ) -> Result<Stream, Box<dyn Error>>
 where
     T: Sample + cpal::SizedSample,
     f32: cpal::FromSample<T>,
 {
+    let mut agc = agc.then(Agc::default);
+    // Reused for every callback. A tenth of a second covers any device's
+    // buffers; one handing over more grows it once.
+    let mut samples: Vec<f32> =
+        Vec::with_capacity(config.sample_rate.0 as usize / 10 * config.channels as usize);
     let stream = device.build_input_stream(
         config,
         move |data: &[T], _: &cpal::InputCallbackInfo| {
-            let samples: Vec<f32> = data.iter().map(|s| s.to_sample::<f32>()).collect();
-            if sender.send(samples).is_err() {
-                // eprintln!("Failed to send audio data");
+            samples.clear();
+            samples.extend(data.iter().map(|s| s.to_sample::<f32>()));
+            // Before the AGC, which would otherwise boost the echo first.
+            if let Some(echo_suppressor) = echo_suppressor.as_mut() {
+                echo_suppressor.process(&mut samples);
+            }
+            if let Some(agc) = agc.as_mut() {
+                agc.process(&mut samples);
             }
+            captured.push(&samples);
         },
         |err| eprintln!("An error occurred on the input audio stream: {}", err),
-        None
+        None,
     )?;
     Ok(stream)
 }
@@ -67,28 +564,166 @@ where
 fn create_output_stream<T>(
     device: &Device,
     config: &StreamConfig,
-    mut receiver: mpsc::UnboundedReceiver<Vec<f32>>,
+    playback: Arc<SampleRing>,
+    max_queued: usize,
+    echo_reference: Option<EchoReference>,
 ) -> Result<Stream, Box<dyn Error>>
 where
     T: Sample + cpal::SizedSample + cpal::FromSample<f32>,
 {
+    // Frames rarely match the device's buffer size, so what doesn't fit in
+    // one callback is played at the start of the next.
+    // Anything past MAX_PLAYBACK_DELAY_MS is dropped, oldest first, so
+    // several peers talking at once can't build up a growing delay.
     let stream = device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            if let Ok(audio_data) = receiver.try_recv() {
-                let len = std::cmp::min(data.len(), audio_data.len());
-                for (i, sample) in data.iter_mut().enumerate().take(len) {
-                    *sample = T::from_sample(audio_data[i]);
-                }
-            } else {
-                // Fill with silence if no data
-                for sample in data.iter_mut() {
-                    *sample = T::from_sample(0.0);
-                }
+            playback.keep_newest(max_queued.max(data.len()));
+            // Fill with silence once the queue runs dry.
+            let played = data.iter_mut().map(|sample| {
+                let value = playback.pop().unwrap_or(0.0);
+                *sample = T::from_sample(value);
+                value
+            });
+            // Keeps the history in step with what's actually playing.
+            match &echo_reference {
+                Some(echo_reference) => echo_reference.record(played),
+                None => played.for_each(|_| {}),
             }
         },
         |err| eprintln!("An error occurred on the output audio stream: {}", err),
-        None
+        None,
     )?;
     Ok(stream)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 512-sample chunks of a 440Hz sine at 48kHz with the given peak.
+    fn sine_chunk(amplitude: f32, chunk: usize) -> Vec<f32> {
+        (0..512)
+            .map(|i| {
+                let t = (chunk * 512 + i) as f32 / 48_000.0;
+                amplitude * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+            })
+            .collect()
+    }
+
+    fn settled_rms(amplitude: f32) -> f32 {
+        let mut agc = Agc::default();
+        let mut output = Vec::new();
+        for chunk in 0..200 {
+            output = sine_chunk(amplitude, chunk);
+            agc.process(&mut output);
+        }
+        rms(&output)
+    }
+
+    #[test]
+    fn framer_emits_whole_frames_only() {
+        let mut framer = Framer::new(4);
+        assert!(framer.push(&[0.0; 3]).is_empty());
+        let frames = framer.push(&[1.0; 6]);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], vec![0.0, 0.0, 0.0, 1.0]);
+        assert!(frames.iter().all(|frame| frame.len() == 4));
+        assert_eq!(framer.push(&[2.0; 3]), vec![vec![1.0, 2.0, 2.0, 2.0]]);
+    }
+
+    #[test]
+    fn sample_ring_drops_what_doesnt_fit() {
+        let ring = SampleRing::new(4);
+        ring.push(&[1.0, 2.0, 3.0]);
+        assert_eq!(ring.pop(), Some(1.0));
+        ring.push(&[4.0, 5.0, 6.0]);
+        assert_eq!(ring.len(), 4);
+        ring.keep_newest(3);
+        let drained: Vec<f32> = std::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(drained, vec![3.0, 4.0, 5.0]);
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn buffer_size_is_only_fixed_within_the_device_range() {
+        let range = SupportedBufferSize::Range { min: 64, max: 4096 };
+        assert_eq!(frame_buffer_size(&range, 960), BufferSize::Fixed(960));
+        assert_eq!(frame_buffer_size(&range, 8192), BufferSize::Default);
+        assert_eq!(
+            frame_buffer_size(&SupportedBufferSize::Unknown, 960),
+            BufferSize::Default
+        );
+    }
+
+    #[test]
+    fn agc_brings_quiet_and_loud_speakers_to_the_target() {
+        for amplitude in [0.03, 0.9] {
+            let level = settled_rms(amplitude);
+            assert!(
+                (level - AGC_TARGET_RMS).abs() < AGC_TARGET_RMS * 0.1,
+                "amplitude {} settled at {}",
+                amplitude,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn agc_gain_changes_gradually() {
+        let mut agc = Agc::default();
+        let mut previous = agc.gain;
+        for chunk in 0..50 {
+            agc.process(&mut sine_chunk(0.9, chunk));
+            assert!(agc.gain >= previous / AGC_MAX_STEP - f32::EPSILON);
+            previous = agc.gain;
+        }
+        assert!(agc.gain < 1.0);
+    }
+
+    // Deterministic noise standing in for a peer's voice.
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn echo_of_played_audio_is_suppressed() {
+        let (reference, mut suppressor) = echo_suppressor();
+        let played = noise(4096, 1);
+        reference.record(played.iter().copied());
+        // The mic hears the speakers a little later and quieter.
+        let mut echo: Vec<f32> = played[1000..1512].iter().map(|s| s * 0.3).collect();
+        let before = rms(&echo);
+        suppressor.process(&mut echo);
+        assert!(rms(&echo) < before * 0.1);
+    }
+
+    #[test]
+    fn speech_unlike_the_output_passes_through() {
+        let (reference, mut suppressor) = echo_suppressor();
+        reference.record(noise(4096, 1).into_iter());
+        let mut speech = noise(512, 2);
+        let expected = speech.clone();
+        suppressor.process(&mut speech);
+        assert_eq!(speech, expected);
+    }
+
+    #[test]
+    fn agc_holds_its_gain_through_silence() {
+        let mut agc = Agc::default();
+        for chunk in 0..100 {
+            agc.process(&mut sine_chunk(0.03, chunk));
+        }
+        let gain = agc.gain;
+        for _ in 0..100 {
+            agc.process(&mut [0.0; 512]);
+        }
+        assert_eq!(agc.gain, gain);
+    }
+}
@@ -0,0 +1,102 @@
+use image::ImageFormat;
+use std::error::Error;
+use std::io::{Cursor, Write};
+use std::process::{Command, Stdio};
+
+// Clipboard access goes through the platform's command line tools so we
+// don't need a windowing-system connection of our own.
+#[cfg(target_os = "macos")]
+const COPY_COMMANDS: &[&[&str]] = &[&["pbcopy"]];
+#[cfg(target_os = "windows")]
+const COPY_COMMANDS: &[&[&str]] = &[&["clip"]];
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const COPY_COMMANDS: &[&[&str]] = &[
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard"],
+    &["xsel", "--clipboard", "--input"],
+];
+
+// Each prints the clipboard's image to stdout, or fails if there isn't one.
+#[cfg(target_os = "macos")]
+const PASTE_IMAGE_COMMANDS: &[&[&str]] = &[&["pngpaste", "-"]];
+#[cfg(target_os = "windows")]
+const PASTE_IMAGE_COMMANDS: &[&[&str]] = &[&[
+    "powershell",
+    "-NoProfile",
+    "-Command",
+    "$i = Get-Clipboard -Format Image; if (!$i) { exit 1 }; \
+     $m = New-Object IO.MemoryStream; $i.Save($m, [Drawing.Imaging.ImageFormat]::Png); \
+     [Console]::OpenStandardOutput().Write($m.ToArray(), 0, $m.Length)",
+]];
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const PASTE_IMAGE_COMMANDS: &[&[&str]] = &[
+    &["wl-paste", "--no-newline", "--type", "image/png"],
+    &[
+        "xclip",
+        "-selection",
+        "clipboard",
+        "-target",
+        "image/png",
+        "-out",
+    ],
+];
+
+pub fn copy_text(text: &str) -> Result<(), Box<dyn Error>> {
+    for command in COPY_COMMANDS {
+        match pipe_to(command, text.as_bytes()) {
+            Ok(()) => return Ok(()),
+            Err(e) => log::debug!("Clipboard command {:?} failed: {}", command, e),
+        }
+    }
+    Err("No clipboard tool available".into())
+}
+
+// Returns the clipboard's image as PNG. Whatever the tool hands back is
+// decoded and re-encoded so peers always get a valid PNG.
+pub fn paste_image() -> Result<Vec<u8>, Box<dyn Error>> {
+    for command in PASTE_IMAGE_COMMANDS {
+        match read_from(command) {
+            Ok(data) if !data.is_empty() => {
+                let image = image::load_from_memory(&data)
+                    .map_err(|_| "The clipboard doesn't hold an image")?;
+                let mut png = Vec::new();
+                image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+                return Ok(png);
+            }
+            Ok(_) => log::debug!("Clipboard command {:?} returned nothing", command),
+            Err(e) => log::debug!("Clipboard command {:?} failed: {}", command, e),
+        }
+    }
+    Err("No image in the clipboard (or no clipboard tool available)".into())
+}
+
+fn read_from(command: &[&str]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let output = Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status).into());
+    }
+    Ok(output.stdout)
+}
+
+fn pipe_to(command: &[&str], input: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("Failed to open clipboard tool stdin")?
+        .write_all(input)?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+    Ok(())
+}
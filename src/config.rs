@@ -0,0 +1,317 @@
+use ratatui::style::Style;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::audio;
+use crate::p2p::{self, Transport};
+use crate::theme::{self, ColorDepth, ThemeName};
+use crate::video;
+
+// Every frame is sent to every peer, so there's a cap on the rate
+// regardless of how fast the loop ticks.
+pub const MAX_FPS: u32 = 20;
+// The main loop wakes once a tick to capture video if a frame is due, send
+// the audio captured since the last tick, and redraw. So video runs at most
+// at 1000 / tick_ms fps, and audio leaves in bursts up to a tick long.
+// Longer ticks save CPU; shorter ones make keys and audio more responsive.
+pub const DEFAULT_TICK_MS: u64 = 50;
+pub const MIN_TICK_MS: u64 = 10;
+pub const MAX_TICK_MS: u64 = 200;
+const DEFAULT_CHAT_HISTORY: usize = 1000;
+
+#[derive(Deserialize, clap::ValueEnum, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutMode {
+    // Equal tiles; the focus only moves when the user moves it.
+    #[default]
+    Grid,
+    // The loudest talker gets the large tile.
+    Speaker,
+}
+
+// Defaults for a call, read from the config file. Every field can be left
+// out of the file, and command line flags override whatever is set here.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub nickname: Option<String>,
+    // Fit the video to its pane; width and height only apply when this is off.
+    pub auto_fit: bool,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub tick_ms: u64,
+    pub keyframe_interval_ms: u64,
+    pub transport: Transport,
+    pub download_dir: Option<PathBuf>,
+    pub mute_audio: bool,
+    pub mute_video: bool,
+    pub mute_output: bool,
+    pub mute_while_typing: bool,
+    pub agc: bool,
+    pub aec: bool,
+    pub audio_frame_ms: u32,
+    pub ascii_ramp: String,
+    pub dither: bool,
+    pub invert: bool,
+    pub edges: bool,
+    pub char_aspect: f32,
+    pub resize: video::ResizeFilter,
+    pub render: video::RenderMode,
+    pub theme: ThemeName,
+    pub layout: LayoutMode,
+    // Tint for the whole video pane; unset leaves the terminal's own colors.
+    pub video_fg: Option<String>,
+    pub video_bg: Option<String>,
+    pub chat_only: bool,
+    pub chat_history: usize,
+    pub read_receipts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nickname: None,
+            auto_fit: true,
+            width: video::OUTPUT_WIDTH,
+            height: video::OUTPUT_HEIGHT,
+            fps: MAX_FPS,
+            tick_ms: DEFAULT_TICK_MS,
+            keyframe_interval_ms: p2p::DEFAULT_KEYFRAME_MS,
+            transport: Transport::default(),
+            download_dir: None,
+            mute_audio: false,
+            mute_video: false,
+            mute_output: false,
+            mute_while_typing: false,
+            agc: false,
+            aec: false,
+            audio_frame_ms: audio::DEFAULT_FRAME_MS,
+            ascii_ramp: video::ASCII_CHARS.iter().collect(),
+            dither: false,
+            invert: false,
+            edges: false,
+            char_aspect: video::CHAR_ASPECT,
+            resize: video::ResizeFilter::default(),
+            render: video::RenderMode::default(),
+            theme: ThemeName::default(),
+            layout: LayoutMode::default(),
+            video_fg: None,
+            video_bg: None,
+            chat_only: false,
+            chat_history: DEFAULT_CHAT_HISTORY,
+            read_receipts: false,
+        }
+    }
+}
+
+impl Config {
+    // An explicitly given path must exist; the default location is optional.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let config: Config = toml_edit::de::from_str(&contents)
+                    .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+                log::info!("Loaded config from {}", path.display());
+                Ok(config)
+            }
+            Err(e) if !required && e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read config file {}: {}", path.display(), e).into()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.width == 0 || self.height == 0 {
+            return Err("Video resolution must be at least 1x1".into());
+        }
+        if !(1..=MAX_FPS).contains(&self.fps) {
+            return Err(format!("fps must be between 1 and {}", MAX_FPS).into());
+        }
+        if !(MIN_TICK_MS..=MAX_TICK_MS).contains(&self.tick_ms) {
+            return Err(format!(
+                "tick_ms must be between {} and {}",
+                MIN_TICK_MS, MAX_TICK_MS
+            )
+            .into());
+        }
+        if !(p2p::MIN_KEYFRAME_MS..=p2p::MAX_KEYFRAME_MS).contains(&self.keyframe_interval_ms) {
+            return Err(format!(
+                "keyframe_interval_ms must be between {} and {}",
+                p2p::MIN_KEYFRAME_MS,
+                p2p::MAX_KEYFRAME_MS
+            )
+            .into());
+        }
+        if !(audio::MIN_FRAME_MS..=audio::MAX_FRAME_MS).contains(&self.audio_frame_ms) {
+            return Err(format!(
+                "audio_frame_ms must be between {} and {}",
+                audio::MIN_FRAME_MS,
+                audio::MAX_FRAME_MS
+            )
+            .into());
+        }
+        if !(self.char_aspect.is_finite() && self.char_aspect > 0.0) {
+            return Err("char_aspect must be a positive number".into());
+        }
+        if self.chat_history == 0 {
+            return Err("chat_history must be at least 1".into());
+        }
+        if self.ascii_ramp.chars().count() < 2 {
+            return Err("ASCII ramp needs at least two characters".into());
+        }
+        for color in self.video_fg.iter().chain(&self.video_bg) {
+            theme::parse_color(color)?;
+        }
+        Ok(())
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    pub fn keyframe_interval(&self) -> Duration {
+        Duration::from_millis(self.keyframe_interval_ms)
+    }
+
+    // The frame rate actually reachable: capture only happens on a tick.
+    pub fn effective_fps(&self) -> u32 {
+        self.fps.min((1000 / self.tick_ms) as u32).max(1)
+    }
+
+    pub fn download_dir(&self) -> PathBuf {
+        self.download_dir
+            .clone()
+            .or_else(dirs::download_dir)
+            .unwrap_or_else(|| ".".into())
+    }
+
+    // Only valid once validate() has passed. Colors are brought down to what
+    // the terminal shows, and without color there's no tint at all.
+    pub fn video_style(&self, color_depth: ColorDepth) -> Style {
+        if color_depth == ColorDepth::Mono {
+            return Style::default();
+        }
+        let color = |name: &Option<String>| {
+            name.as_deref()
+                .and_then(|name| theme::parse_color(name).ok())
+                .map(|color| theme::fit_color(color, color_depth))
+        };
+        let mut style = Style::default();
+        if let Some(fg) = color(&self.video_fg) {
+            style = style.fg(fg);
+        }
+        if let Some(bg) = color(&self.video_bg) {
+            style = style.bg(bg);
+        }
+        style
+    }
+
+    pub fn ascii_options(&self) -> video::AsciiOptions {
+        video::AsciiOptions {
+            width: self.width,
+            height: self.height,
+            ramp: self.ascii_ramp.chars().collect(),
+            dither: self.dither,
+            invert: self.invert,
+            edges: self.edges,
+            char_aspect: self.char_aspect,
+            resize: self.resize,
+            render: self.render,
+        }
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rust-meet").join("config.toml"))
+}
+
+// Sets top-level `key = value` lines in the config file, replacing any the
+// file already has and appending the rest. Comments and every other line
+// are left as they were. Values must already be written as TOML.
+pub fn save_values(path: &Path, values: &[(&str, String)]) -> std::io::Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let updated = set_values(&contents, values);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, updated)
+}
+
+fn set_values(contents: &str, values: &[(&str, String)]) -> String {
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    // Keys after the first table header would belong to that table.
+    let top_level = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    for (key, value) in values {
+        let existing = lines[..top_level].iter().position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        });
+        let line = format!("{} = {}", key, value);
+        match existing {
+            Some(i) => lines[i] = line,
+            None if top_level < lines.len() => lines.insert(top_level, line),
+            None => lines.push(line),
+        }
+    }
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_ticks_cap_the_frame_rate() {
+        let config = Config {
+            tick_ms: 100,
+            ..Config::default()
+        };
+        assert_eq!(config.effective_fps(), 10);
+        let config = Config {
+            tick_ms: MIN_TICK_MS,
+            fps: 15,
+            ..Config::default()
+        };
+        assert_eq!(config.effective_fps(), 15);
+        let config = Config {
+            tick_ms: MAX_TICK_MS + 1,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn saved_values_replace_or_append() {
+        let contents = "# mine\nfps = 5\nfps_note = 1\n";
+        let values = [("fps", "12".to_string()), ("invert", "true".to_string())];
+        let updated = set_values(contents, &values);
+        assert_eq!(updated, "# mine\nfps = 12\nfps_note = 1\ninvert = true\n");
+        let config: Config = toml_edit::de::from_str(&set_values(
+            "",
+            &[("ascii_ramp", "\" .\\\"#\"".to_string())],
+        ))
+        .unwrap();
+        assert_eq!(config.ascii_ramp, " .\"#");
+    }
+}
@@ -0,0 +1,157 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error::Error;
+
+use crate::p2p::{
+    AUDIO_TOPIC, CHAT_TOPIC, CONTROL_TOPIC, FILE_TOPIC, PRESENCE_TOPIC, REACTION_TOPIC, VIDEO_TOPIC,
+};
+
+// Every peer derives the key on its own from the shared passphrase, so the
+// salt has to be fixed rather than random.
+const KDF_SALT: &[u8] = b"rust-meet/payload-key/v1";
+const KDF_ROUNDS: u32 = 100_000;
+const NONCE_LEN: usize = 12;
+
+// Symmetric AEAD applied to gossipsub payloads on top of the noise transport,
+// since gossipsub relays messages through peers we may not trust.
+pub struct PayloadCipher {
+    cipher: ChaCha20Poly1305,
+    encrypt_media: bool,
+}
+
+impl PayloadCipher {
+    pub fn new(password: &str, encrypt_media: bool) -> Self {
+        let key = derive_key(password.as_bytes(), KDF_SALT, KDF_ROUNDS);
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            encrypt_media,
+        }
+    }
+
+    fn applies_to(&self, topic: &str) -> bool {
+        match topic {
+            // Control and presence are sealed so that only peers with the
+            // password can end the call, kick, mute or appear in the room.
+            CHAT_TOPIC | FILE_TOPIC | REACTION_TOPIC | CONTROL_TOPIC | PRESENCE_TOPIC => true,
+            VIDEO_TOPIC | AUDIO_TOPIC => self.encrypt_media,
+            _ => false,
+        }
+    }
+
+    // Output is the random nonce followed by the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "Failed to encrypt payload")?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if sealed.len() < NONCE_LEN {
+            return Err("Encrypted payload is too short".into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Failed to decrypt payload (wrong password?)")?;
+        Ok(plaintext)
+    }
+}
+
+// Encrypts an outgoing payload if encryption is enabled for its topic.
+pub fn seal(
+    cipher: Option<&PayloadCipher>,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match cipher {
+        Some(cipher) if cipher.applies_to(topic) => cipher.encrypt(payload),
+        _ => Ok(payload.to_vec()),
+    }
+}
+
+// Decrypts an incoming payload if encryption is enabled for its topic.
+pub fn open(
+    cipher: Option<&PayloadCipher>,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match cipher {
+        Some(cipher) if cipher.applies_to(topic) => cipher.decrypt(payload),
+        _ => Ok(payload.to_vec()),
+    }
+}
+
+// PBKDF2-HMAC-SHA256 with a single output block, which is exactly a
+// ChaCha20 key.
+fn derive_key(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let prf = <Hmac<Sha256> as Mac>::new_from_slice(password).expect("HMAC accepts any key length");
+
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut key = block;
+
+    for _ in 1..rounds {
+        let mut mac = prf.clone();
+        mac.update(&block);
+        block = mac.finalize().into_bytes().into();
+        for (k, b) in key.iter_mut().zip(block.iter()) {
+            *k ^= b;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn key_derivation_matches_pbkdf2_vectors() {
+        assert_eq!(
+            hex(&derive_key(b"password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            hex(&derive_key(b"password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+    }
+
+    #[test]
+    fn only_the_same_password_opens_a_payload() {
+        let cipher = PayloadCipher::new("hunter2", false);
+        let sealed = seal(Some(&cipher), CONTROL_TOPIC, b"{\"EndCall\":null}").unwrap();
+        assert_ne!(sealed, b"{\"EndCall\":null}");
+        assert_eq!(
+            open(Some(&cipher), CONTROL_TOPIC, &sealed).unwrap(),
+            b"{\"EndCall\":null}"
+        );
+
+        let wrong = PayloadCipher::new("hunter3", false);
+        assert!(open(Some(&wrong), CONTROL_TOPIC, &sealed).is_err());
+        // Nor can a peer without a password slip a plaintext message in.
+        assert!(open(Some(&cipher), PRESENCE_TOPIC, b"{}").is_err());
+
+        // Media is only sealed with --encrypt-media.
+        assert_eq!(
+            seal(Some(&cipher), VIDEO_TOPIC, b"frame").unwrap(),
+            b"frame"
+        );
+    }
+}
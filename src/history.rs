@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::{format_size, short_peer_id};
+
+// Oldest entries are dropped once the file holds this many.
+const MAX_ENTRIES: usize = 500;
+
+// Sent files kept in memory for /resend, bounded both ways.
+const MAX_RECENT_SENDS: usize = 5;
+const MAX_RECENT_SEND_BYTES: usize = 64 * 1024 * 1024;
+
+// Downloads finish on their own tasks, so appends are serialized here to
+// keep them from overwriting each other.
+static APPEND_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// One file sent or received, as stored in the history file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub timestamp: u64, // unix seconds
+    pub direction: Direction,
+    pub file_name: String,
+    pub size: u64,
+    pub peer_id: Option<String>, // sender of a received file; sent files go to everyone
+    pub path: PathBuf,           // where it was sent from or saved to
+}
+
+impl HistoryEntry {
+    pub fn now(
+        direction: Direction,
+        file_name: &str,
+        size: u64,
+        peer_id: Option<&str>,
+        path: &Path,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            direction,
+            file_name: file_name.to_string(),
+            size,
+            peer_id: peer_id.map(str::to_string),
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+pub struct SentFile {
+    pub file_name: String,
+    pub content: Vec<u8>,
+    pub path: PathBuf,
+}
+
+// The last few files we sent, newest first, so one that went out before
+// anyone was listening can be sent again without picking it anew.
+#[derive(Default)]
+pub struct RecentSends {
+    files: VecDeque<SentFile>,
+}
+
+impl RecentSends {
+    // Files too big for the whole budget aren't kept at all.
+    pub fn push(&mut self, file: SentFile) {
+        if file.content.len() > MAX_RECENT_SEND_BYTES {
+            return;
+        }
+        self.files.push_front(file);
+        self.files.truncate(MAX_RECENT_SENDS);
+        while self
+            .files
+            .iter()
+            .map(|file| file.content.len())
+            .sum::<usize>()
+            > MAX_RECENT_SEND_BYTES
+        {
+            self.files.pop_back();
+        }
+    }
+
+    // Numbered from 1, newest first, as listed by /resend.
+    pub fn get(&self, number: usize) -> Option<&SentFile> {
+        self.files.get(number.checked_sub(1)?)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SentFile> {
+        self.files.iter()
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rust-meet").join("history.json"))
+}
+
+// A missing file is just an empty history.
+pub fn load(path: &Path) -> Result<Vec<HistoryEntry>, Box<dyn Error>> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid history file {}: {}", path.display(), e))?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read history file {}: {}", path.display(), e).into()),
+    }
+}
+
+pub fn append(path: &Path, entry: HistoryEntry) -> Result<(), Box<dyn Error>> {
+    let _guard = APPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut entries = load(path)?;
+    entries.push(entry);
+    let excess = entries.len().saturating_sub(MAX_ENTRIES);
+    entries.drain(..excess);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(&entries)?)?;
+    Ok(())
+}
+
+// Appends to the default history file, logging rather than failing since
+// the history is only a convenience.
+pub fn record(entry: HistoryEntry) {
+    let Some(path) = default_path() else {
+        return;
+    };
+    if let Err(e) = append(&path, entry) {
+        log::warn!("Failed to update file history: {}", e);
+    }
+}
+
+pub fn format_entry(entry: &HistoryEntry) -> String {
+    let when = time::OffsetDateTime::from_unix_timestamp(entry.timestamp as i64)
+        .map(|t| {
+            format!(
+                "{}-{:02}-{:02} {:02}:{:02} UTC",
+                t.year(),
+                t.month() as u8,
+                t.day(),
+                t.hour(),
+                t.minute()
+            )
+        })
+        .unwrap_or_else(|_| "unknown time".to_string());
+    let size = format_size(entry.size);
+    match (entry.direction, &entry.peer_id) {
+        (Direction::Sent, _) => format!(
+            "{}  sent {} ({}) from {}",
+            when,
+            entry.file_name,
+            size,
+            entry.path.display()
+        ),
+        (Direction::Received, peer_id) => format!(
+            "{}  received {} ({}) from {}, saved to {}",
+            when,
+            entry.file_name,
+            size,
+            peer_id
+                .as_deref()
+                .map(short_peer_id)
+                .unwrap_or("unknown peer"),
+            entry.path.display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_keeps_only_the_newest_entries() {
+        let path =
+            std::env::temp_dir().join(format!("rust-meet-history-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for i in 0..MAX_ENTRIES + 3 {
+            let name = format!("file-{}", i);
+            let entry = HistoryEntry::now(Direction::Sent, &name, 1, None, Path::new(&name));
+            append(&path, entry).unwrap();
+        }
+        let entries = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].file_name, "file-3");
+        assert_eq!(
+            entries[MAX_ENTRIES - 1].file_name,
+            format!("file-{}", MAX_ENTRIES + 2)
+        );
+    }
+
+    #[test]
+    fn recent_sends_are_bounded_by_count_and_size() {
+        let file = |name: &str, size: usize| SentFile {
+            file_name: name.to_string(),
+            content: vec![0; size],
+            path: PathBuf::from(name),
+        };
+        let mut recent = RecentSends::default();
+        for i in 0..MAX_RECENT_SENDS + 2 {
+            recent.push(file(&format!("file-{}", i), 1));
+        }
+        assert_eq!(recent.iter().count(), MAX_RECENT_SENDS);
+        assert_eq!(
+            recent.get(1).unwrap().file_name,
+            format!("file-{}", MAX_RECENT_SENDS + 1)
+        );
+        assert!(recent.get(0).is_none());
+
+        recent.push(file("huge", MAX_RECENT_SEND_BYTES + 1));
+        assert_ne!(recent.get(1).unwrap().file_name, "huge");
+        recent.push(file("big", MAX_RECENT_SEND_BYTES));
+        assert_eq!(recent.iter().count(), 1);
+    }
+}
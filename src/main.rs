@@ -1,191 +1,846 @@
+mod app;
+mod archive;
 mod audio;
+mod clipboard;
+mod config;
+mod crypto;
+mod history;
+mod opener;
 mod p2p;
+mod qr;
+mod record;
+mod settings;
+mod status;
+mod stun;
+mod theme;
 mod tui;
+mod uplink;
+mod util;
 mod video;
 
 use clap::Parser;
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEventKind,
+};
 use libp2p::{
-    futures::StreamExt,
     gossipsub::{self, IdentTopic as Topic},
+    mdns,
     multiaddr::Protocol,
     swarm::SwarmEvent,
-    Multiaddr,
+    Multiaddr, PeerId, Swarm,
 };
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use tokio::{fs, sync::mpsc, time::Duration};
 
+use app::{App, ChatInput, OutgoingMessage};
+use config::Config;
 use p2p::{
-    AppBehaviourEvent, AudioData, ChatMessage, FileMessage, FrameData, AUDIO_TOPIC, CHAT_TOPIC,
-    FILE_TOPIC, VIDEO_TOPIC,
+    AppBehaviourEvent, AudioData, ChatMessage, FileMessage, FrameData, ReactionMessage,
+    AUDIO_TOPIC, CHAT_TOPIC, FILE_TOPIC, REACTION_TOPIC, VIDEO_TOPIC,
 };
-use tui::{FileDownload, FileDownloadState, Tui};
+use tui::{ClickTarget, FileDownload, FileDownloadState, Tui};
 
 use p2p::AppStatus;
 
+// Dials made while joining before giving up (about 23s in total).
+const MAX_DIAL_ATTEMPTS: u32 = 5;
+// How long `join --peer` waits for mDNS to find the room.
+const PEER_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CHAT_ONLY_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Args,
+    /// Log verbosity (off, error, warn, info, debug, trace). Overrides RUST_LOG.
+    #[arg(long, global = true, value_parser = parse_log_level)]
+    log_level: Option<LevelFilter>,
+    /// File to write the log to.
+    #[arg(long, global = true, default_value = "rust-meet.log")]
+    log_file: PathBuf,
+    /// Draw on the normal screen instead of the alternate one, keeping scrollback (for debugging).
+    #[arg(long, global = true)]
+    no_alt_screen: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
 enum Args {
     /// Create a new room and wait for others to join.
-    Create,
+    Create {
+        /// Listen on a fixed port instead of a random one (useful for port forwarding).
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        port: Option<u16>,
+        /// Ask before letting each new peer into the call.
+        #[arg(long)]
+        moderated: bool,
+        /// Most peers let into the call at once; anyone past that is told the room is full.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_peers: Option<u32>,
+        #[command(flatten)]
+        call: CallArgs,
+    },
     /// Join an existing room using a peer's address.
     Join {
-        /// The address of the peer to connect to.
+        /// Address of the peer to connect to. Repeat or list several to try them all.
+        #[arg(long, num_args = 1.., required_unless_present = "peer")]
+        address: Vec<String>,
+        /// Peer id of a room on the local network, to find with mDNS instead of an address.
+        #[arg(long, conflicts_with = "address")]
+        peer: Option<String>,
+        #[command(flatten)]
+        call: CallArgs,
+    },
+    /// Play your microphone back through your speakers to check your audio setup.
+    AudioTest {
+        /// Apply automatic gain control to the microphone, as in a call.
         #[arg(long)]
-        address: String,
+        agc: bool,
+        /// Suppress the speakers' sound in the microphone, as in a call.
+        #[arg(long)]
+        aec: bool,
+    },
+    /// Print detected devices and settings for bug reports, then exit.
+    Doctor,
+    /// List files sent and received in past calls.
+    History,
+    /// Replay a call recorded with --record.
+    Play {
+        /// asciicast file to play.
+        path: PathBuf,
+        /// Playback speed; +/- halve or double it while playing.
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f32,
     },
 }
 
+/// Options shared by every mode that joins a call.
+#[derive(clap::Args, Debug, Clone)]
+struct CallArgs {
+    /// Shared passphrase used to encrypt chat and file messages end-to-end.
+    #[arg(long)]
+    password: Option<String>,
+    /// Also encrypt video and audio with the passphrase (costs CPU on every frame).
+    #[arg(long, requires = "password")]
+    encrypt_media: bool,
+    /// Largest gossipsub message to send or accept, in bytes. Should match the other peers.
+    #[arg(long, default_value_t = p2p::DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+    /// Seconds a connection may sit idle before it's closed. Call traffic normally keeps it busy.
+    #[arg(long, default_value_t = p2p::DEFAULT_IDLE_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: u64,
+    /// Cap on outgoing traffic in kbit/s. Video frames are dropped to stay under it; audio and chat are not.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_uplink_kbps: Option<u32>,
+    /// Count the bytes sent and received, protocol overhead included, and show the totals and rates.
+    #[arg(long)]
+    metrics: bool,
+    /// STUN server (host[:port], default port 3478) to ask for your public address, shown while waiting for peers.
+    #[arg(long)]
+    stun_server: Option<String>,
+    /// Config file to read defaults from [default: ~/.config/rust-meet/config.toml].
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Name shown next to your chat messages.
+    #[arg(long)]
+    nickname: Option<String>,
+    /// ASCII video size in characters, e.g. 80x40 [default: fit the video pane].
+    #[arg(long, value_parser = parse_resolution)]
+    resolution: Option<(u32, u32)>,
+    /// Video frames captured and sent per second (1-20), at most one per tick.
+    #[arg(long)]
+    fps: Option<u32>,
+    /// Milliseconds between main loop wakeups (10-200); longer saves CPU, shorter cuts key and audio latency [default: 50].
+    #[arg(long)]
+    tick_ms: Option<u64>,
+    /// Milliseconds between full video frames, which peers that missed a change need to catch up [default: 2000].
+    #[arg(long)]
+    keyframe_interval_ms: Option<u64>,
+    /// Transport to listen on when creating a room; not accepted by join.
+    #[arg(long, value_enum)]
+    transport: Option<p2p::Transport>,
+    /// Directory received files are saved to.
+    #[arg(long)]
+    download_dir: Option<PathBuf>,
+    /// Characters used to draw video, from darkest to brightest.
+    #[arg(long)]
+    ascii_ramp: Option<String>,
+    /// Dither video for smoother gradients at the cost of some grain.
+    #[arg(long)]
+    dither: bool,
+    /// Map dark pixels to dense characters, for terminals with a light background.
+    #[arg(long)]
+    invert: bool,
+    /// Draw video as outlines using a Sobel edge filter.
+    #[arg(long)]
+    edges: bool,
+    /// Colors of the interface; mono uses none at all.
+    #[arg(long, value_enum)]
+    theme: Option<theme::ThemeName>,
+    /// Tile layout; speaker gives the large tile to whoever is talking, l locks it [default: grid].
+    #[arg(long, value_enum)]
+    layout: Option<config::LayoutMode>,
+    /// Text color of the video panes: a name like green, #rrggbb, or 0-255 [default: terminal's].
+    #[arg(long)]
+    video_fg: Option<String>,
+    /// Background color of the video panes, in the same forms as --video-fg [default: terminal's].
+    #[arg(long)]
+    video_bg: Option<String>,
+    /// Height of a terminal character cell over its width, to keep video in proportion [default: 2.0].
+    #[arg(long)]
+    char_aspect: Option<f32>,
+    /// Filter for shrinking camera frames; nearest is cheapest, area averages each cell's pixels [default: nearest].
+    #[arg(long, value_enum)]
+    resize: Option<video::ResizeFilter>,
+    /// How video is drawn; halfblock shows twice the rows in color, braille 2x4 dots per cell [default: ascii].
+    #[arg(long, value_enum)]
+    render: Option<video::RenderMode>,
+    /// Even out your microphone volume with automatic gain control.
+    #[arg(long)]
+    agc: bool,
+    /// Suppress echo of the speakers in your microphone, for setups without headphones (uses more CPU).
+    #[arg(long)]
+    aec: bool,
+    /// Milliseconds of audio per message; longer frames mean fewer packets but more delay [default: 20].
+    #[arg(long)]
+    audio_frame_ms: Option<u32>,
+    /// Chat messages kept on screen before the oldest are dropped [default: 1000].
+    #[arg(long)]
+    chat_history: Option<usize>,
+    /// Let senders know when you've seen their chat messages (shown to them as "✓ seen").
+    #[arg(long)]
+    read_receipts: bool,
+    /// Join with the microphone muted.
+    #[arg(long)]
+    mute_audio: bool,
+    /// Join with the camera off.
+    #[arg(long)]
+    mute_video: bool,
+    /// Join with your speakers muted; d toggles them. They start muted anyway when a peer is on this machine.
+    #[arg(long)]
+    mute_output: bool,
+    /// Mute the microphone while the chat box is open, so typing isn't heard.
+    #[arg(long)]
+    mute_while_typing: bool,
+    /// Text chat and files only: no camera, microphone or video panes.
+    #[arg(long)]
+    chat_only: bool,
+    /// Record everything drawn on screen to an asciicast v2 file, for asciinema or GIF converters.
+    #[arg(long)]
+    record: Option<PathBuf>,
+}
+
+impl CallArgs {
+    fn swarm_options(&self) -> p2p::SwarmOptions {
+        p2p::SwarmOptions {
+            max_message_size: self.max_message_size,
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            bandwidth_metrics: self.metrics,
+            ..Default::default()
+        }
+    }
+
+    // Loads the config file and lets any flags given on the command line win.
+    fn load_config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(nickname) = &self.nickname {
+            config.nickname = Some(nickname.clone());
+        }
+        if let Some((width, height)) = self.resolution {
+            config.auto_fit = false;
+            config.width = width;
+            config.height = height;
+        }
+        if let Some(fps) = self.fps {
+            config.fps = fps;
+        }
+        if let Some(tick_ms) = self.tick_ms {
+            config.tick_ms = tick_ms;
+        }
+        if let Some(keyframe_interval_ms) = self.keyframe_interval_ms {
+            config.keyframe_interval_ms = keyframe_interval_ms;
+        }
+        if let Some(transport) = self.transport {
+            config.transport = transport;
+        }
+        if let Some(download_dir) = &self.download_dir {
+            config.download_dir = Some(download_dir.clone());
+        }
+        if let Some(ascii_ramp) = &self.ascii_ramp {
+            config.ascii_ramp = ascii_ramp.clone();
+        }
+        if let Some(chat_history) = self.chat_history {
+            config.chat_history = chat_history;
+        }
+        if let Some(char_aspect) = self.char_aspect {
+            config.char_aspect = char_aspect;
+        }
+        if let Some(audio_frame_ms) = self.audio_frame_ms {
+            config.audio_frame_ms = audio_frame_ms;
+        }
+        if let Some(resize) = self.resize {
+            config.resize = resize;
+        }
+        if let Some(render) = self.render {
+            config.render = render;
+        }
+        if let Some(theme) = self.theme {
+            config.theme = theme;
+        }
+        if let Some(layout) = self.layout {
+            config.layout = layout;
+        }
+        if let Some(video_fg) = &self.video_fg {
+            config.video_fg = Some(video_fg.clone());
+        }
+        if let Some(video_bg) = &self.video_bg {
+            config.video_bg = Some(video_bg.clone());
+        }
+        // These flags can only switch a setting on.
+        config.mute_audio |= self.mute_audio;
+        config.mute_video |= self.mute_video;
+        config.mute_output |= self.mute_output;
+        config.mute_while_typing |= self.mute_while_typing;
+        config.dither |= self.dither;
+        config.invert |= self.invert;
+        config.edges |= self.edges;
+        config.agc |= self.agc;
+        config.aec |= self.aec;
+        config.read_receipts |= self.read_receipts;
+        config.chat_only |= self.chat_only;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// Reads RUST_LOG as a plain level ("debug") or env_logger-style directives
+// ("rust_meet=debug,libp2p=info"), taking the most verbose level mentioned
+// since there's only one filter for the whole log.
+fn env_log_level() -> Option<LevelFilter> {
+    let value = std::env::var("RUST_LOG").ok()?;
+    value
+        .split(',')
+        .filter_map(|directive| {
+            let level = directive.rsplit('=').next()?;
+            level.trim().parse::<LevelFilter>().ok()
+        })
+        .max()
+}
+
+fn parse_log_level(value: &str) -> Result<LevelFilter, String> {
+    value
+        .parse()
+        .map_err(|_| format!("unknown log level '{}'", value))
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got '{}'", value))?;
+    let width = width
+        .parse()
+        .map_err(|_| format!("invalid width '{}'", width))?;
+    let height = height
+        .parse()
+        .map_err(|_| format!("invalid height '{}'", height))?;
+    Ok((width, height))
+}
+
 use log::LevelFilter;
-use simple_logging;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    simple_logging::log_to_file("rust-meet.log", LevelFilter::Info)?;
-    let args = Args::parse();
-    let tui = Arc::new(Mutex::new(Tui::new()?));
-    let mut camera = match video::initialize_camera() {
-        Ok(camera) => Some(camera),
-        Err(_) => None,
+    let cli = Cli::parse();
+    let log_level = cli
+        .log_level
+        .or_else(env_log_level)
+        .unwrap_or(LevelFilter::Info);
+    simple_logging::log_to_file(&cli.log_file, log_level)?;
+    let args = cli.command;
+    tui::install_panic_hook();
+    let call = match &args {
+        // Joining dials whatever the address names, so there's nothing for it to pick.
+        Args::Join { call, .. } if call.transport.is_some() => {
+            return Err(
+                "--transport only applies to create; join uses the transport in the address".into(),
+            )
+        }
+        Args::Create { call, .. } | Args::Join { call, .. } => call.clone(),
+        Args::AudioTest { agc, aec } => {
+            return run_audio_test(*agc, *aec, !cli.no_alt_screen).await
+        }
+        Args::Doctor => return run_doctor(),
+        Args::History => return run_history(),
+        Args::Play { path, speed } => return run_playback(path, *speed, !cli.no_alt_screen).await,
+    };
+    let mut config = call.load_config()?;
+    let mut settings = settings::Settings::default();
+    let mut ascii_options = config.ascii_options();
+    let tui = Arc::new(Mutex::new(Tui::new(!cli.no_alt_screen)?));
+    {
+        let mut tui_guard = tui.lock().unwrap();
+        tui_guard.chat_only = config.chat_only;
+        tui_guard.chat_history = config.chat_history;
+        tui_guard.theme = usable_theme(config.theme).theme();
+        tui_guard.color_depth = theme::terminal_color_depth();
+        if tui_guard.color_depth == theme::ColorDepth::Mono
+            && (config.video_fg.is_some() || config.video_bg.is_some())
+        {
+            log::info!("Terminal doesn't support colors, leaving the video untinted");
+        }
+        tui_guard.video_style = config.video_style(tui_guard.color_depth);
+        tui_guard.layout = config.layout;
+        if let Some(path) = &call.record {
+            tui_guard
+                .start_recording(path)
+                .map_err(|e| format!("Could not record to {}: {}", path.display(), e))?;
+            log::info!("Recording to {}", path.display());
+        }
+    }
+    let mut camera = if config.chat_only {
+        None
+    } else {
+        video::initialize_camera()
+            .map_err(|e| log::warn!("No usable camera, sending a placeholder: {}", e))
+            .ok()
     };
 
-    let (p2p_audio_sender, mut app_audio_receiver) = mpsc::unbounded_channel::<Vec<f32>>();
-    let (app_audio_sender, p2p_audio_receiver) = mpsc::unbounded_channel::<Vec<f32>>();
-
-    let (mut swarm, mut app_status) = match args {
-        Args::Create => {
-            let mut swarm = p2p::create_swarm(true).await?;
-            let listen_addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-            swarm.listen_on(listen_addr)?;
-            (swarm, AppStatus::WaitingForPeers)
+    let mut waiting_room: Option<p2p::WaitingRoom> = None;
+    // Only the host, i.e. whoever created the room, can remove peers.
+    let mut removed_peers: Option<p2p::RemovedPeers> = None;
+    let mut room_limit: Option<p2p::RoomLimit> = None;
+    // Set when the host turned us away, with its limit.
+    let mut room_full: Option<usize> = None;
+    // The peer a joiner dialled: the only one that can turn it away.
+    let mut host: Option<PeerId> = None;
+    // Set while `join --peer` waits for mDNS, with the time to give up at.
+    let mut peer_lookup: Option<(PeerId, Instant)> = None;
+    let mut lookup_failed: Option<PeerId> = None;
+    // Peers announcing another protocol version, by that version.
+    let mut incompatible_peers: HashMap<PeerId, u32> = HashMap::new();
+    let mut incompatible_host: Option<u32> = None;
+    let mut presence = p2p::Presence::default();
+    let mut pings = p2p::Pings::default();
+    let mut capabilities = p2p::PeerCapabilities::default();
+    let mut next_heartbeat = Instant::now();
+    let ((mut swarm, bandwidth_sinks), mut app_status, mut remote_addrs) = match args {
+        Args::Create {
+            port,
+            moderated,
+            max_peers,
+            ..
+        } => {
+            let (mut swarm, sinks) = p2p::create_swarm(p2p::SwarmOptions {
+                validate_messages: moderated,
+                ..call.swarm_options()
+            })
+            .await?;
+            if moderated {
+                waiting_room = Some(p2p::WaitingRoom::default());
+            }
+            removed_peers = Some(p2p::RemovedPeers::default());
+            if let Some(max_peers) = max_peers {
+                room_limit = Some(p2p::RoomLimit::new(max_peers as usize));
+                tui.lock().unwrap().max_peers = Some(max_peers as usize);
+            }
+            let port = port.unwrap_or(0);
+            let (listen_addr, listen_addr_v6) = config.transport.listen_addresses(port);
+            swarm.listen_on(listen_addr).map_err(|e| {
+                format!(
+                    "Failed to listen on {:?} port {} (is it already in use?): {}",
+                    config.transport, port, e
+                )
+            })?;
+            // Dual-stack hosts get a v6 listener too; v6-less hosts just keep v4.
+            if let Err(e) = swarm.listen_on(listen_addr_v6) {
+                log::warn!("Not listening on IPv6: {}", e);
+            }
+            ((swarm, sinks), AppStatus::WaitingForPeers, Vec::new())
         }
-        Args::Join { address } => {
-            let mut swarm = p2p::create_swarm(true).await?;
-            let remote_addr: Multiaddr = address.parse()?;
-            swarm.dial(remote_addr)?;
-            (swarm, AppStatus::Joining)
+        Args::Join {
+            peer: Some(peer), ..
+        } => {
+            let peer_id: PeerId = peer
+                .parse()
+                .map_err(|e| format!("Invalid peer id '{}': {}", peer, e))?;
+            let network = p2p::create_swarm(call.swarm_options()).await?;
+            // Nothing to dial until mDNS turns up an address for the peer.
+            peer_lookup = Some((peer_id, Instant::now() + PEER_LOOKUP_TIMEOUT));
+            (network, AppStatus::Joining, Vec::new())
+        }
+        Args::Join { address, .. } => {
+            let (mut swarm, sinks) = p2p::create_swarm(call.swarm_options()).await?;
+            // Dial every distinct candidate; the first one to connect wins.
+            let mut remote_addrs: Vec<Multiaddr> = Vec::new();
+            for address in &address {
+                let remote_addr: Multiaddr = address
+                    .parse()
+                    .map_err(|e| format!("Invalid address '{}': {}", address, e))?;
+                if !remote_addrs.contains(&remote_addr) {
+                    remote_addrs.push(remote_addr);
+                }
+            }
+            if p2p::dial_all(&mut swarm, &remote_addrs) == 0 {
+                return Err("None of the given addresses could be dialed".into());
+            }
+            ((swarm, sinks), AppStatus::Joining, remote_addrs)
+        }
+        Args::AudioTest { .. } | Args::Doctor | Args::History | Args::Play { .. } => {
+            unreachable!("handled before the call is set up")
         }
     };
 
-    let _audio_streams = audio::setup_audio_streams(p2p_audio_sender, p2p_audio_receiver)?;
+    let mut audio_streams = if config.chat_only {
+        None
+    } else {
+        audio::try_setup_audio_streams(config.agc, config.aec, config.audio_frame_ms)
+    };
+    let cipher = call
+        .password
+        .as_deref()
+        .map(|password| crypto::PayloadCipher::new(password, call.encrypt_media));
 
     let video_topic = Topic::new(VIDEO_TOPIC);
-    let audio_topic = Topic::new(AUDIO_TOPIC);
-    let chat_topic = Topic::new(CHAT_TOPIC);
-    let file_topic = Topic::new(FILE_TOPIC);
     let local_peer_id = *swarm.local_peer_id();
+    let mut bandwidth =
+        bandwidth_sinks.map(|sinks| p2p::BandwidthMeter::new(sinks, Instant::now()));
     let local_peer_id_str = local_peer_id.to_string();
 
-    let mut tick_interval = tokio::time::interval(Duration::from_millis(50));
-    let (key_sender, mut key_receiver) = mpsc::unbounded_channel();
+    let mut tick_interval = tokio::time::interval(config.tick());
+    let mut key_receiver = spawn_event_reader();
+    let mut swarm_events = p2p::EventQueue::default();
     let (download_status_sender, mut download_status_receiver) =
-        mpsc::unbounded_channel::<(usize, FileDownloadState)>();
+        mpsc::unbounded_channel::<(u64, FileDownloadState)>();
+    let (external_sender, mut external_receiver) = mpsc::unbounded_channel();
+    if let Some(server) = call.stun_server.clone() {
+        tokio::spawn(async move {
+            let _ = external_sender.send(
+                stun::external_address(&server)
+                    .await
+                    .map_err(|e| e.to_string()),
+            );
+        });
+    }
     let mut tui_dirty = true;
-    let mut is_audio_muted = false;
-    let mut is_video_muted = false;
+    let mut app = App::new(
+        local_peer_id_str.clone(),
+        config.nickname.clone(),
+        config.chat_only,
+        config.read_receipts,
+        config.mute_audio,
+        config.mute_video,
+        config.mute_output,
+    );
+    app.mute_while_typing = config.mute_while_typing;
+    let mut frame_seq: u64 = 0;
+    let mut oversized_frames: u64 = 0;
+    let mut throttled_frames: u64 = 0;
+    let mut recent_sends = history::RecentSends::default();
+    // Peers caught sending messages under another peer's id.
+    let mut spoofers: HashSet<PeerId> = HashSet::new();
+    let mut malformed = p2p::MalformedMessages::default();
+    let mut uplink = uplink::UplinkBudget::new(call.max_uplink_kbps, Instant::now());
+    let mut frame_encoder = p2p::FrameEncoder::new(config.keyframe_interval());
+    let mut frame_interval = capture_interval(&config);
+    let mut last_capture: Option<Instant> = None;
+    let mut low_power = false;
+    let mut self_frame = video::create_no_camera_frame(&ascii_options)?;
 
-    let mut join_timeout = if app_status == AppStatus::Joining {
-        Some(Box::pin(tokio::time::sleep(Duration::from_secs(10))))
-    } else {
-        None
-    };
+    // The host may not be up yet, so keep re-dialing with a growing delay.
+    let mut dial_attempt: u32 = 1;
+    let mut next_dial = Instant::now() + dial_backoff(dial_attempt);
+    let mut join_failed = false;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
-    thread::spawn(move || {
-        loop {
-            match event::read() {
-                Ok(event) => {
-                    if key_sender.send(event).is_err() {
-                        // rx closed
-                        break;
-                    }
-                }
-                Err(_) => break,
+    loop {
+        // Opening or closing the chat box may mute or unmute the mic. Checked
+        // here since some key handlers finish with `continue`.
+        let typing = tui.lock().unwrap().input_mode;
+        if let Some(message) = app.on_typing(typing, app_status == AppStatus::InCall) {
+            if let Err(e) = send_outgoing(
+                &mut swarm,
+                cipher.as_ref(),
+                &mut uplink,
+                call.max_message_size,
+                message,
+            ) {
+                log::warn!("Failed to send mute state: {}", e);
             }
+            tui_dirty = true;
         }
-    });
-
-    loop {
-        if tui_dirty {
+        // The call view is redrawn from the tick instead, so a burst of
+        // events within one tick costs a single render.
+        if tui_dirty && app_status != AppStatus::InCall {
             let mut tui_guard = tui.lock().unwrap();
             match app_status {
                 AppStatus::WaitingForPeers => {
                     tui_guard.draw_waiting_for_peers(&local_peer_id_str)?;
                 }
                 AppStatus::Joining => {
-                    tui_guard.draw_joining()?;
-                }
-                AppStatus::InCall => {
-                    // InCall is handled by the tick interval
+                    let status = match peer_lookup {
+                        Some((peer_id, _)) => format!(
+                            "Looking for {} on the local network",
+                            util::short_peer_id(&peer_id.to_string())
+                        ),
+                        None => format!("Attempt {} of {}", dial_attempt, MAX_DIAL_ATTEMPTS),
+                    };
+                    tui_guard.draw_joining(&status)?;
                 }
+                AppStatus::InCall => unreachable!(),
             }
             tui_dirty = false;
         }
 
         tokio::select! {
-            _ = async { join_timeout.as_mut().unwrap().await }, if join_timeout.is_some() => {
-                // Timeout occurred while joining
-                break;
-            },
             _ = tick_interval.tick() => {
+                if let Some((peer_id, deadline)) = peer_lookup {
+                    if Instant::now() >= deadline {
+                        lookup_failed = Some(peer_id);
+                        break;
+                    }
+                } else if app_status == AppStatus::Joining && Instant::now() >= next_dial {
+                    if dial_attempt >= MAX_DIAL_ATTEMPTS {
+                        join_failed = true;
+                        break;
+                    }
+                    dial_attempt += 1;
+                    log::info!("Dialing again, attempt {}/{}", dial_attempt, MAX_DIAL_ATTEMPTS);
+                    p2p::dial_all(&mut swarm, &remote_addrs);
+                    next_dial = Instant::now() + dial_backoff(dial_attempt);
+                    tui_dirty = true;
+                }
+                if let Some(bandwidth) = bandwidth.as_mut() {
+                    let stats = bandwidth.update(Instant::now());
+                    let mut tui_guard = tui.lock().unwrap();
+                    if tui_guard.bandwidth != Some(stats) {
+                        tui_guard.bandwidth = Some(stats);
+                        tui_dirty = true;
+                    }
+                }
+                {
+                    let mut tui_guard = tui.lock().unwrap();
+                    if tui_guard.expire_flash()
+                        | tui_guard.expire_reactions()
+                        | tui_guard.call_timer_changed()
+                    {
+                        tui_dirty = true;
+                    }
+                    // The host stays on after removing the last peer or losing it
+                    // to missed heartbeats, but the call itself is over.
+                    if swarm.connected_peers().next().is_none() && tui_guard.stop_call_timer() {
+                        tui_dirty = true;
+                    }
+                }
+                if let Some(limit) = room_limit.as_mut() {
+                    for peer_id in limit.expired(Instant::now()) {
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    }
+                }
+                if app_status == AppStatus::InCall && Instant::now() >= next_heartbeat {
+                    next_heartbeat = Instant::now() + p2p::HEARTBEAT_INTERVAL;
+                    let heartbeat = p2p::HeartbeatMessage {
+                        peer_id: local_peer_id_str.clone(),
+                    };
+                    if let Ok(json) = serde_json::to_string(&heartbeat) {
+                        if let Ok(payload) = crypto::seal(cipher.as_ref(), p2p::PRESENCE_TOPIC, json.as_bytes()) {
+                            let _ = p2p::publish(&mut swarm, p2p::PRESENCE_TOPIC, payload, call.max_message_size);
+                        }
+                    }
+                    let ping = p2p::ControlMessage::Ping {
+                        peer_id: local_peer_id_str.clone(),
+                        nonce: pings.start(Instant::now()),
+                    };
+                    if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &ping) {
+                        log::debug!("Failed to send ping: {}", e);
+                    }
+                    let now = Instant::now();
+                    let links_changed = tui.lock().unwrap().refresh_links(|peer_id| {
+                        let peer_id = peer_id.parse::<PeerId>().ok()?;
+                        Some((!swarm.is_connected(&peer_id), presence.missed_heartbeats(&peer_id, now)))
+                    });
+                    tui_dirty |= links_changed;
+                    for peer_id in presence.expire(Instant::now()) {
+                        log::warn!("{} stopped sending heartbeats, dropping it", peer_id);
+                        let mut tui_guard = tui.lock().unwrap();
+                        tui_guard.push_message(format!(
+                            "Peer {} stopped responding",
+                            util::short_peer_id(&peer_id.to_string())
+                        ));
+                        tui_guard.remove_peer(&peer_id.to_string());
+                        capabilities.remove(&peer_id.to_string());
+                        tui_dirty = true;
+                        // Its connection may take much longer to time out.
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    }
+                }
                 if app_status == AppStatus::InCall {
-                    // Process camera frame
-                    let frame = if !is_video_muted {
-                        if let Some(ref mut cam) = camera {
-                            video::capture_and_process_frame(cam)
-                                .unwrap_or_else(|_| video::create_no_camera_frame().unwrap())
+                    // With every camera in the call off there's nothing to capture or
+                    // show, so release ours until someone turns theirs back on.
+                    let idle = app.is_video_muted && tui.lock().unwrap().all_peers_video_muted();
+                    if idle != low_power {
+                        low_power = idle;
+                        if low_power {
+                            log::info!("All video is off, pausing capture");
+                            if let Some(cam) = camera.as_mut().filter(|cam| cam.is_stream_open()) {
+                                if let Err(e) = cam.stop_stream() {
+                                    log::warn!("Failed to stop the camera: {}", e);
+                                }
+                            }
                         } else {
-                            video::create_no_camera_frame().unwrap()
+                            // Like at startup, the next capture reopens the camera.
+                            log::info!("Video is back on, resuming capture");
                         }
-                    } else {
-                        video::create_no_camera_frame().unwrap()
-                    };
+                    }
+                    let capture_due = last_capture.is_none_or(|t| t.elapsed() >= frame_interval)
+                        && (!low_power || frame_encoder.keyframe_pending());
+                    if capture_due {
+                        last_capture = Some(Instant::now());
+                        tui_dirty = true;
+                        // Process camera frame
+                        self_frame = if !app.is_video_muted {
+                            if let Some(ref mut cam) = camera {
+                                video::capture_and_process_frame(cam, &ascii_options)
+                                    .unwrap_or_else(|_| video::create_no_camera_frame(&ascii_options).unwrap())
+                            } else {
+                                video::create_no_camera_frame(&ascii_options).unwrap()
+                            }
+                        } else {
+                            video::create_video_off_frame(&ascii_options).unwrap()
+                        };
 
-                    // Send frame data along with mute status
-                    let frame_data = FrameData {
-                        peer_id: local_peer_id_str.clone(),
-                        frame: frame.clone(),
-                        is_audio_muted,
-                        is_video_muted,
-                    };
-                    if let Ok(json) = serde_json::to_string(&frame_data) {
-                        if let Err(_e) = swarm
-                            .behaviour_mut()
-                            .gossipsub
-                            .publish(video_topic.clone(), json.as_bytes())
-                        {
+                        // Send frame data along with mute status. Peers draw their own
+                        // placeholder while we're muted, so only new peers get a frame.
+                        let send_frame = !app.is_video_muted || frame_encoder.keyframe_pending();
+                        // The sequence only moves on once a frame is out, so frames
+                        // dropped here don't look like losses on the way.
+                        let frame_data = send_frame.then(|| FrameData {
+                            peer_id: local_peer_id_str.clone(),
+                            seq: frame_seq + 1,
+                            frame: frame_encoder.encode(&self_frame, Instant::now()),
+                            is_audio_muted: app.is_audio_muted,
+                            is_video_muted: app.is_video_muted,
+                        });
+                        if let Some(Ok(encoded)) = frame_data.map(|frame_data| frame_data.encode(capabilities.compression())) {
+                            let payload = crypto::seal(cipher.as_ref(), VIDEO_TOPIC, &encoded)
+                                .ok()
+                                .filter(|payload| {
+                                    let allowed = uplink.try_spend_video(payload.len(), Instant::now());
+                                    if !allowed {
+                                        throttled_frames += 1;
+                                        log::debug!("Over the uplink budget, dropped video frame ({} so far)", throttled_frames);
+                                        frame_encoder.force_keyframe();
+                                    }
+                                    allowed
+                                });
+                            tui.lock().unwrap().video_drop_rate = uplink.video_drop_rate();
+                            if let Some(payload) = payload {
+                                match p2p::publish(&mut swarm, VIDEO_TOPIC, payload, call.max_message_size) {
+                                    Err(e @ p2p::PublishError::TooLarge { .. }) => {
+                                        oversized_frames += 1;
+                                        log::warn!("Dropped video frame: {} ({} so far)", e, oversized_frames);
+                                        if oversized_frames == 1 {
+                                            tui.lock().unwrap().push_message("Video frames are too large to send; try a smaller --resolution".to_string());
+                                        }
+                                        // Receivers can't follow deltas past a gap.
+                                        frame_encoder.force_keyframe();
+                                    }
+                                    // Mostly no peers subscribed yet.
+                                    Err(p2p::PublishError::Gossipsub(_)) => {}
+                                    Ok(()) => frame_seq += 1,
+                                }
+                            }
                         }
                     }
 
-                    // Process and send audio if not muted
-                    if !is_audio_muted {
-                        if let Ok(audio_data) = app_audio_receiver.try_recv() {
+                    // Send every frame captured since the last tick, unless muted;
+                    // frames captured while muted are dropped rather than sent late.
+                    let captured = audio_streams.as_mut().map(audio::AudioStreams::captured_frames);
+                    for audio_data in captured.into_iter().flatten() {
+                        if !app.is_audio_muted {
+                            tui.lock().unwrap().update_self_audio_level(&audio_data);
                             let audio_data_p2p = AudioData {
                                 peer_id: local_peer_id_str.clone(),
                                 data: audio_data,
                             };
                             if let Ok(json) = serde_json::to_string(&audio_data_p2p) {
-                                if let Err(_e) = swarm
-                                    .behaviour_mut()
-                                    .gossipsub
-                                    .publish(audio_topic.clone(), json.as_bytes())
-                                {
+                                if let Ok(payload) = crypto::seal(cipher.as_ref(), AUDIO_TOPIC, json.as_bytes()) {
+                                    uplink.spend(payload.len(), Instant::now());
+                                    let _ = p2p::publish(&mut swarm, AUDIO_TOPIC, payload, call.max_message_size);
                                 }
                             }
                         }
                     }
-                    tui.lock()
-                        .unwrap()
-                        .draw(&frame, is_audio_muted, is_video_muted)?;
+                    let mut tui_guard = tui.lock().unwrap();
+                    if tui_dirty {
+                        tui_guard.draw(&self_frame, app.local_status())?;
+                        tui_dirty = false;
+                    }
+                    if let Some(text) = tui_guard.take_snapshot() {
+                        match save_snapshot(&config.download_dir(), &text) {
+                            Ok(path) => tui_guard.flash(&format!("Saved {}", path.display())),
+                            Err(e) => {
+                                log::warn!("Could not save a snapshot: {}", e);
+                                tui_guard.flash(&format!("Could not save a snapshot: {}", e));
+                            }
+                        }
+                        tui_dirty = true;
+                    }
+                    // Recapture at the new size once the layout or terminal changes.
+                    if config.auto_fit {
+                        if let Some((width, height)) = tui_guard.self_view_size() {
+                            let (width, height) = video::fit_resolution(width, height);
+                            if (width, height) != (ascii_options.width, ascii_options.height) {
+                                log::info!("Fitting video to {}x{}", width, height);
+                                ascii_options.width = width;
+                                ascii_options.height = height;
+                                last_capture = None;
+                            }
+                        }
+                    }
+                    // Meters that are still falling need another frame.
+                    if tui_guard.decay_audio_levels() {
+                        tui_dirty = true;
+                    }
+                    if tui_guard.follow_speaker(Instant::now()) {
+                        tui_dirty = true;
+                    }
                 }
             },
             key_event = key_receiver.recv() => {
+                let mut quit_requested = false;
+                if let Some(Event::Resize(..)) = key_event {
+                    tui_dirty = true;
+                }
+                if let Some(Event::Mouse(mouse)) = key_event {
+                    let mut tui_guard = tui.lock().unwrap();
+                    let clickable = app_status == AppStatus::InCall && tui_guard.admit_prompt.is_none();
+                    if clickable && mouse.kind == MouseEventKind::Down(MouseButton::Left) {
+                        match tui_guard.click_target(mouse.column, mouse.row) {
+                            Some(ClickTarget::Peer(peer_id)) => tui_guard.toggle_focus(&peer_id),
+                            Some(ClickTarget::Input) => tui_guard.input_mode = true,
+                            Some(ClickTarget::Download(id)) => {
+                                tui_guard.select_download_by_id(id);
+                                open_selected_download(&mut tui_guard);
+                            }
+                            None => {}
+                        }
+                        tui_dirty = true;
+                    }
+                }
                 if let Some(Event::Key(key)) = key_event {
                     if key.kind == KeyEventKind::Press {
                         let mut tui_guard = tui.lock().unwrap();
                         if tui_guard.input_mode {
                             match key.code {
+                                _ if is_ctrl_c(&key) => quit_requested = true,
                                 KeyCode::Char(c) => {
                                     tui_guard.input.push(c);
                                     tui_dirty = true;
@@ -194,91 +849,371 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                     tui_guard.input.pop();
                                     tui_dirty = true;
                                 }
+                                KeyCode::Up => {
+                                    tui_guard.history_up();
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Down => {
+                                    tui_guard.history_down();
+                                    tui_dirty = true;
+                                }
                                 KeyCode::Enter => {
                                     let message_text: String = tui_guard.input.drain(..).collect();
-                                    let message = ChatMessage {
-                                        peer_id: local_peer_id_str.clone(),
-                                        message: message_text.clone(),
+                                    tui_guard.reset_history_cursor();
+                                    tui_guard.input_mode = false;
+                                    tui_dirty = true;
+                                    let (message_id, text) = match app.on_submit(&message_text) {
+                                        ChatInput::Message { message_id, text } => (message_id, text),
+                                        ChatInput::Remove { peer, ban } => {
+                                            let result = match removed_peers.as_mut() {
+                                                None => Err("Only the host can kick or ban peers".to_string()),
+                                                Some(removed) => p2p::find_connected_peer(&swarm, &peer).inspect(|&peer_id| {
+                                                    removed.kick(&mut swarm, peer_id, ban);
+                                                    tui_guard.remove_peer(&peer_id.to_string());
+                                                    capabilities.remove(&peer_id.to_string());
+                                                }),
+                                            };
+                                            tui_guard.push_message(match result {
+                                                Ok(peer_id) => format!(
+                                                    "{} {}",
+                                                    if ban { "Banned" } else { "Kicked" },
+                                                    util::short_peer_id(&peer_id.to_string())
+                                                ),
+                                                Err(e) => e,
+                                            });
+                                            continue;
+                                        }
+                                        ChatInput::Ignore { peer, ignore: true } => {
+                                            match p2p::find_connected_peer(&swarm, &peer) {
+                                                Ok(peer_id) => {
+                                                    let peer_id = peer_id.to_string();
+                                                    app.ignore(&peer_id);
+                                                    tui_guard.set_peer_ignored(&peer_id, true);
+                                                    tui_guard.push_message(format!("Ignoring {}", util::short_peer_id(&peer_id)));
+                                                }
+                                                Err(e) => tui_guard.push_message(e),
+                                            }
+                                            continue;
+                                        }
+                                        ChatInput::Ignore { peer, ignore: false } => {
+                                            match app.unignore(&peer) {
+                                                Ok(peer_id) => {
+                                                    tui_guard.set_peer_ignored(&peer_id, false);
+                                                    tui_guard.push_message(format!("No longer ignoring {}", util::short_peer_id(&peer_id)));
+                                                }
+                                                Err(e) => tui_guard.push_message(e),
+                                            }
+                                            continue;
+                                        }
+                                        ChatInput::Resend { number: None } => {
+                                            if recent_sends.iter().next().is_none() {
+                                                tui_guard.push_message("No recently sent files to resend".to_string());
+                                            }
+                                            for (number, file) in recent_sends.iter().enumerate() {
+                                                tui_guard.push_message(format!(
+                                                    "/resend {}: {} ({})",
+                                                    number + 1,
+                                                    file.file_name,
+                                                    util::format_size(file.content.len() as u64)
+                                                ));
+                                            }
+                                            continue;
+                                        }
+                                        ChatInput::Resend { number: Some(number) } => {
+                                            let Some(file) = recent_sends.get(number) else {
+                                                tui_guard.push_message(format!("No sent file number {}, /resend lists them", number));
+                                                continue;
+                                            };
+                                            let upload = tui_guard.add_upload(&file.file_name, file.content.len() as u64);
+                                            let published = publish_file(&mut swarm, cipher.as_ref(), &local_peer_id_str, &file.file_name, &file.content, call.max_message_size, &mut uplink);
+                                            tui_guard.finish_upload(upload, &published);
+                                            match published {
+                                                Ok(()) => {
+                                                    history::record(history::HistoryEntry::now(
+                                                        history::Direction::Sent,
+                                                        &file.file_name,
+                                                        file.content.len() as u64,
+                                                        None,
+                                                        &file.path,
+                                                    ));
+                                                    tui_guard.push_message(format!("You sent a file again: {}", file.file_name));
+                                                }
+                                                Err(e) => {
+                                                    log::warn!("Could not resend {}: {}", file.file_name, e);
+                                                    tui_guard.push_message(format!("Could not resend {}: {}", file.file_name, e));
+                                                }
+                                            }
+                                            continue;
+                                        }
                                     };
-                                    if let Ok(json) = serde_json::to_string(&message) {
-                                        if let Err(_e) = swarm
-                                            .behaviour_mut()
-                                            .gossipsub
-                                            .publish(chat_topic.clone(), json.as_bytes())
-                                        {
+                                    tui_guard.remember_input(&text);
+                                    let message = OutgoingMessage::Chat(app.chat_message(message_id, &text));
+                                    if let Err(e) = send_outgoing(&mut swarm, cipher.as_ref(), &mut uplink, call.max_message_size, message) {
+                                        if let Some(e @ p2p::PublishError::TooLarge { .. }) = e.downcast_ref() {
+                                            // Leave the text in the box to shorten and resend.
+                                            tui_guard.push_message(format!("Message not sent: {}", e));
+                                            tui_guard.input = text;
+                                            tui_guard.input_mode = true;
+                                            continue;
                                         }
                                     }
-                                    tui_guard.messages.push(format!("You: {}", message_text));
-                                    tui_guard.input_mode = false;
-                                    tui_dirty = true;
+                                    tui_guard.push_own_message(format!("You: {}", text), message_id);
                                 }
                                 KeyCode::Esc => {
                                     tui_guard.input.clear();
+                                    tui_guard.reset_history_cursor();
                                     tui_guard.input_mode = false;
                                     tui_dirty = true;
                                 }
                                 _ => {}
                             }
+                        } else if tui_guard.settings_panel.is_some() {
+                            let step = match key.code {
+                                KeyCode::Right | KeyCode::Char('+' | '=') => Some(true),
+                                KeyCode::Left | KeyCode::Char('-') => Some(false),
+                                _ => None,
+                            };
+                            match key.code {
+                                KeyCode::Esc | KeyCode::Char(',') => tui_guard.settings_panel = None,
+                                _ if is_ctrl_c(&key) => quit_requested = true,
+                                KeyCode::Up | KeyCode::Down => settings.select(key.code == KeyCode::Down),
+                                _ => {}
+                            }
+                            match step.map(|forward| settings.adjust(&mut config, forward)) {
+                                Some(settings::Setting::Fps) => frame_interval = capture_interval(&config),
+                                Some(settings::Setting::AsciiRamp | settings::Setting::Invert) => {
+                                    ascii_options.ramp = config.ascii_ramp.chars().collect();
+                                    ascii_options.invert = config.invert;
+                                    last_capture = None;
+                                    frame_encoder.force_keyframe();
+                                }
+                                Some(settings::Setting::Theme) => tui_guard.theme = usable_theme(config.theme).theme(),
+                                Some(settings::Setting::Speakers) => {
+                                    app.on_key(KeyCode::Char('d'), app_status == AppStatus::InCall);
+                                }
+                                None => {}
+                            }
+                            if tui_guard.settings_panel.is_some() {
+                                tui_guard.settings_panel = Some(settings.rows(&config, app.is_output_muted));
+                            }
+                            tui_dirty = true;
                         } else {
                             match key.code {
                                 KeyCode::Char('q') => {
-                                    if app_status == AppStatus::Joining {
-                                        break;
+                                    quit_requested = true;
+                                }
+                                KeyCode::Char(',') => {
+                                    tui_guard.settings_panel = Some(settings.rows(&config, app.is_output_muted));
+                                    tui_dirty = true;
+                                }
+                                _ if is_ctrl_c(&key) => quit_requested = true,
+                                KeyCode::Char('c') if app_status == AppStatus::WaitingForPeers => {
+                                    if let Some(addr) = tui_guard.best_listen_address() {
+                                        match clipboard::copy_text(&addr.to_string()) {
+                                            Ok(()) => tui_guard.flash("Copied!"),
+                                            Err(e) => {
+                                                log::warn!("Could not copy join address: {}", e);
+                                                tui_guard.flash("Clipboard unavailable");
+                                            }
+                                        }
+                                        tui_dirty = true;
                                     }
-                                    if app_status != AppStatus::WaitingForPeers {
-                                        p2p::end_call(&mut swarm)?;
+                                }
+                                KeyCode::Char(answer @ ('y' | 'n')) if tui_guard.admit_prompt.is_some() => {
+                                    let Some(room) = waiting_room.as_mut() else {
+                                        continue;
+                                    };
+                                    if answer == 'y' {
+                                        if let Some(peer_id) = room.admit() {
+                                            log::info!("Admitted {}", peer_id);
+                                            let message = p2p::ControlMessage::Admitted {
+                                                peer_id: peer_id.to_string(),
+                                            };
+                                            if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &message) {
+                                                log::warn!("Failed to send admission: {}", e);
+                                            }
+                                            app_status = AppStatus::InCall;
+                                            tui_guard.start_call_timer();
+                                            last_capture = None;
+                                            frame_encoder.force_keyframe();
+                                        }
+                                    } else if let Some(peer_id) = room.reject() {
+                                        log::info!("Rejected {}", peer_id);
+                                        let _ = swarm.disconnect_peer_id(peer_id);
                                     }
-                                    break;
+                                    tui_guard.admit_prompt =
+                                        room.next_pending().map(|peer| peer.to_string());
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Up | KeyCode::Down if app_status == AppStatus::InCall => {
+                                    tui_guard.select_peer(key.code == KeyCode::Down);
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char(action @ ('k' | 'b')) if app_status == AppStatus::InCall => {
+                                    if let Some(selected) = tui_guard.selected_peer().map(str::to_string) {
+                                        let notice = match (removed_peers.as_mut(), selected.parse()) {
+                                            (None, _) => "Only the host can kick or ban peers".to_string(),
+                                            (Some(_), Err(_)) => format!("Can't remove {}: not a valid peer id", selected),
+                                            (Some(removed), Ok(peer_id)) => {
+                                                removed.kick(&mut swarm, peer_id, action == 'b');
+                                                tui_guard.remove_peer(&selected);
+                                                capabilities.remove(&selected);
+                                                format!(
+                                                    "{} {}",
+                                                    if action == 'b' { "Banned" } else { "Kicked" },
+                                                    util::short_peer_id(&selected)
+                                                )
+                                            }
+                                        };
+                                        tui_guard.push_message(notice);
+                                        tui_dirty = true;
+                                    }
+                                }
+                                KeyCode::Char(key @ ('[' | ']')) => {
+                                    tui_guard.select_download(key == ']');
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char('o') => {
+                                    tui_dirty |= open_selected_download(&mut tui_guard);
                                 }
                                 KeyCode::Char('i') => {
                                     tui_guard.input_mode = true;
                                     tui_dirty = true;
                                 }
-                                KeyCode::Char('m') => {
-                                    is_audio_muted = !is_audio_muted;
+                                KeyCode::Char(c) if app_status == AppStatus::InCall && app::REACTION_KEYS.iter().any(|(key, _)| *key == c) => {
+                                    if let Some(message) = app.on_key(key.code, true) {
+                                        if let OutgoingMessage::Reaction(reaction) = &message {
+                                            tui_guard.add_self_reaction(&reaction.emoji);
+                                        }
+                                        if let Err(e) = send_outgoing(&mut swarm, cipher.as_ref(), &mut uplink, call.max_message_size, message) {
+                                            log::warn!("Failed to send reaction: {}", e);
+                                        }
+                                    }
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char('h') if app_status == AppStatus::InCall => {
+                                    if let Some(message) = app.on_key(key.code, true) {
+                                        if let Err(e) = send_outgoing(&mut swarm, cipher.as_ref(), &mut uplink, call.max_message_size, message) {
+                                            log::warn!("Failed to send hand state: {}", e);
+                                        }
+                                    }
+                                    tui_guard.hand_raised = app.hand_raised;
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char('S') if app_status == AppStatus::InCall => {
+                                    tui_guard.request_snapshot();
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char('d' | 'r') if !config.chat_only => {
+                                    app.on_key(key.code, app_status == AppStatus::InCall);
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Char('s') if !config.chat_only => {
+                                    tui_guard.show_self = !tui_guard.show_self;
+                                    tui_dirty = true;
+                                }
+                                KeyCode::Tab => {
+                                    tui_guard.cycle_focus();
                                     tui_dirty = true;
                                 }
-                                KeyCode::Char('v') => {
-                                    is_video_muted = !is_video_muted;
+                                KeyCode::Char('l') if !config.chat_only => {
+                                    match tui_guard.toggle_focus_lock() {
+                                        Some(true) => tui_guard.flash("Focus locked (l: follow the speaker)"),
+                                        Some(false) => tui_guard.flash("Focus follows the speaker"),
+                                        None => tui_guard.flash("Focus only follows the speaker with --layout speaker"),
+                                    }
                                     tui_dirty = true;
                                 }
-                                KeyCode::Char('f') => {
-                                    if let Some(path) = rfd::FileDialog::new().pick_file() {
-                                        log::info!("Picked file: {:?}", path);
-                                        if let Ok(content) = std::fs::read(&path) {
-                                            let file_name = path
-                                                .file_name()
-                                                .unwrap_or_default()
-                                                .to_string_lossy()
-                                                .to_string();
-                                            log::info!("Sending file: {}", file_name);
-                                            let message = FileMessage {
-                                                peer_id: local_peer_id_str.clone(),
+                                KeyCode::Char('m') | KeyCode::Char('v') if !config.chat_only => {
+                                    let message = app.on_key(key.code, app_status == AppStatus::InCall);
+                                    if key.code == KeyCode::Char('v') {
+                                        // Swap in the camera or placeholder right away, as a
+                                        // keyframe so muting still sends one last frame.
+                                        last_capture = None;
+                                        frame_encoder.force_keyframe();
+                                    }
+                                    if let Some(message) = message {
+                                        if let Err(e) = send_outgoing(&mut swarm, cipher.as_ref(), &mut uplink, call.max_message_size, message) {
+                                            log::warn!("Failed to send mute state: {}", e);
+                                        }
+                                    }
+                                    tui_dirty = true;
+                                }
+                                // F sends a whole folder as a zip archive.
+                                KeyCode::Char(key @ ('f' | 'F')) => {
+                                    let picked = if key == 'f' {
+                                        rfd::FileDialog::new().pick_file()
+                                    } else {
+                                        rfd::FileDialog::new().pick_folder()
+                                    };
+                                    if let Some(path) = picked {
+                                        log::info!("Picked {:?}", path);
+                                        let sent = read_for_sending(&path).and_then(|(file_name, content)| {
+                                            let size = content.len() as u64;
+                                            let upload = tui_guard.add_upload(&file_name, size);
+                                            let published = publish_file(&mut swarm, cipher.as_ref(), &local_peer_id_str, &file_name, &content, call.max_message_size, &mut uplink);
+                                            tui_guard.finish_upload(upload, &published);
+                                            // Kept even if nobody got it, that's when /resend helps.
+                                            recent_sends.push(history::SentFile {
                                                 file_name: file_name.clone(),
                                                 content,
-                                            };
-                                            if let Ok(json) = serde_json::to_string(&message) {
-                                                match swarm
-                                                    .behaviour_mut()
-                                                    .gossipsub
-                                                    .publish(file_topic.clone(), json.as_bytes())
-                                                {
-                                                    Ok(_) => {
-                                                        log::info!("File sent successfully.");
-                                                        tui_guard.messages.push(format!(
-                                                            "You sent a file: {}",
-                                                            file_name
-                                                        ));
-                                                        tui_dirty = true;
-                                                    }
-                                                    Err(e) => {
-                                                        log::error!("Failed to send file: {:?}", e);
-                                                    }
-                                                }
+                                                path: path.clone(),
+                                            });
+                                            published?;
+                                            Ok((file_name, size))
+                                        });
+                                        match sent {
+                                            Ok((file_name, size)) => {
+                                                history::record(history::HistoryEntry::now(
+                                                    history::Direction::Sent,
+                                                    &file_name,
+                                                    size,
+                                                    None,
+                                                    &path,
+                                                ));
+                                                tui_guard.push_message(format!("You sent a file: {}", file_name));
+                                            }
+                                            Err(e) => {
+                                                log::warn!("Could not send {:?}: {}", path, e);
+                                                tui_guard.push_message(format!("Could not send {}: {}", path.display(), e));
                                             }
                                         }
+                                        tui_dirty = true;
                                     }
                                 }
+                                KeyCode::Char('p') => {
+                                    let file_name = format!("pasted-{}.png", file_timestamp());
+                                    let sent = clipboard::paste_image().and_then(|png| {
+                                        let size = png.len() as u64;
+                                        let upload = tui_guard.add_upload(&file_name, size);
+                                        let published = publish_file(&mut swarm, cipher.as_ref(), &local_peer_id_str, &file_name, &png, call.max_message_size, &mut uplink);
+                                        tui_guard.finish_upload(upload, &published);
+                                        recent_sends.push(history::SentFile {
+                                            file_name: file_name.clone(),
+                                            content: png,
+                                            path: PathBuf::from("clipboard"),
+                                        });
+                                        published?;
+                                        Ok(size)
+                                    });
+                                    match sent {
+                                        Ok(size) => {
+                                            history::record(history::HistoryEntry::now(
+                                                history::Direction::Sent,
+                                                &file_name,
+                                                size,
+                                                None,
+                                                Path::new("clipboard"),
+                                            ));
+                                            tui_guard.push_message(format!("You pasted an image: {}", file_name));
+                                        }
+                                        Err(e) => {
+                                            log::warn!("Could not paste an image: {}", e);
+                                            tui_guard.push_message(format!("Could not paste an image: {}", e));
+                                        }
+                                    }
+                                    tui_dirty = true;
+                                }
                                 _ => {}
                             }
                         }
@@ -286,83 +1221,336 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 } else if key_event.is_none() {
                     break;
                 }
+                if quit_requested {
+                    if app_status == AppStatus::InCall {
+                        if let Err(e) = end_call(&mut swarm, cipher.as_ref()).await {
+                            log::warn!("Failed to notify peers of call end: {}", e);
+                        }
+                    }
+                    break;
+                }
             },
-            event = swarm.select_next_some() => {
+            event = swarm_events.next(&mut swarm) => {
                 match event {
-                    SwarmEvent::ConnectionEstablished { .. } => {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                        log::info!(
+                            "Connected to {} via {}",
+                            peer_id,
+                            endpoint.get_remote_address()
+                        );
+                        if removed_peers.is_none() && host.is_none() && endpoint.is_dialer() {
+                            host = Some(peer_id);
+                        }
+                        if removed_peers.as_mut().is_some_and(|removed| removed.on_connected(&mut swarm, peer_id)) {
+                            log::info!("Refusing banned peer {}", peer_id);
+                            continue;
+                        }
+                        if room_limit.as_mut().is_some_and(|limit| {
+                            limit.on_connected(peer_id, swarm.connected_peers(), Instant::now())
+                        }) {
+                            log::info!("Room full, turning away {}", peer_id);
+                            continue;
+                        }
+                        if is_loopback(endpoint.get_remote_address()) && app.on_local_peer() {
+                            log::info!("{} is on this machine, muting the speakers", peer_id);
+                            tui.lock().unwrap().push_message(
+                                "A peer is on this machine, speakers muted to avoid feedback (d: unmute)".to_string(),
+                            );
+                        }
+                        if let Err(e) = p2p::ensure_subscribed(&mut swarm) {
+                            log::warn!("Failed to renew subscriptions: {}", e);
+                        }
+                        if let Some(room) = waiting_room.as_mut() {
+                            if room.on_connected(peer_id) {
+                                log::info!("{} is waiting to be admitted", peer_id);
+                                tui.lock().unwrap().admit_prompt =
+                                    room.next_pending().map(|peer| peer.to_string());
+                                tui_dirty = true;
+                                continue;
+                            }
+                        }
                         app_status = AppStatus::InCall;
-                        join_timeout = None;
+                        tui.lock().unwrap().start_call_timer();
                         tui_dirty = true;
+                        // Send a keyframe on the next tick so the new peer sees
+                        // our video and mute state right away.
+                        last_capture = None;
+                        frame_encoder.force_keyframe();
                     }
                     SwarmEvent::Dialing { .. } => {
                         // Not used in this context
                     }
-                    SwarmEvent::ConnectionClosed { .. } => {
+                    // Checked first so every closed peer stops being tracked.
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if presence.on_disconnected(&peer_id) =>
+                    {
+                        log::info!("Closed connection to unresponsive peer {}", peer_id);
+                        // Nobody left to tell that the call is over.
+                        if swarm.connected_peers().next().is_none() {
+                            break;
+                        }
+                    }
+                    // Several join addresses can reach the same peer; only an
+                    // unreachable peer ends the call, not a redundant connection.
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if waiting_room
+                            .as_mut()
+                            .is_some_and(|room| room.on_disconnected(&peer_id)) =>
+                    {
+                        log::info!("{} left the waiting room", peer_id);
+                        tui.lock().unwrap().admit_prompt = waiting_room
+                            .as_ref()
+                            .and_then(|room| room.next_pending())
+                            .map(|peer| peer.to_string());
+                        tui_dirty = true;
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if removed_peers
+                            .as_mut()
+                            .is_some_and(|removed| removed.on_disconnected(&peer_id)) =>
+                    {
+                        log::info!("Removed {} from the call", peer_id);
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if room_limit
+                            .as_mut()
+                            .is_some_and(|limit| limit.on_disconnected(&peer_id)) =>
+                    {
+                        log::info!("Turned away {}, the room is full", peer_id);
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if incompatible_peers.contains_key(&peer_id) =>
+                    {
+                        let version = incompatible_peers.remove(&peer_id).unwrap();
+                        log::info!("Disconnected incompatible peer {}", peer_id);
+                        // A joiner left with nobody was cut off by the host.
+                        if removed_peers.is_none() && swarm.connected_peers().next().is_none() {
+                            incompatible_host = Some(version);
+                            break;
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { num_established: 0, .. } => {
                         // Attempt to notify other peers, but don't error out if it fails
                         // (e.g. if we are the last peer).
-                        let _ = p2p::end_call(&mut swarm);
+                        let _ = end_call(&mut swarm, cipher.as_ref()).await;
                         break;
                     }
                     SwarmEvent::IncomingConnectionError { .. } => {
                         // Handle error
                     }
                     SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(
-                        gossipsub::Event::Message { message, .. },
+                        gossipsub::Event::Subscribed { peer_id, topic },
+                    )) => {
+                        log::info!("{} subscribed to '{}'", peer_id, topic);
+                        if let Some(limit) = room_limit.as_ref().filter(|limit| limit.is_rejected(&peer_id)) {
+                            if topic == Topic::new(p2p::CONTROL_TOPIC).hash() {
+                                let message = p2p::ControlMessage::RoomFull {
+                                    peer_id: peer_id.to_string(),
+                                    max_peers: limit.max_peers,
+                                    host: local_peer_id_str.clone(),
+                                };
+                                if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &message) {
+                                    log::warn!("Failed to tell {} the room is full: {}", peer_id, e);
+                                }
+                            }
+                            continue;
+                        }
+                        if topic == Topic::new(p2p::CONTROL_TOPIC).hash() {
+                            let message = p2p::ControlMessage::Hello {
+                                peer_id: local_peer_id_str.clone(),
+                                protocol_version: p2p::PROTOCOL_VERSION,
+                            };
+                            if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &message) {
+                                log::warn!("Failed to send protocol version: {}", e);
+                            }
+                            let message = p2p::ControlMessage::Capabilities {
+                                peer_id: local_peer_id_str.clone(),
+                                compression: true,
+                            };
+                            if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &message) {
+                                log::warn!("Failed to send capabilities: {}", e);
+                            }
+                        }
+                        if topic == Topic::new(p2p::CONTROL_TOPIC).hash() && app.hand_raised {
+                            // Late joiners need to hear about a hand that's already up.
+                            let message = p2p::ControlMessage::HandRaised {
+                                peer_id: local_peer_id_str.clone(),
+                                raised: true,
+                            };
+                            if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &message) {
+                                log::warn!("Failed to send hand state: {}", e);
+                            }
+                        }
+                        if topic == video_topic.hash() {
+                            // Publishing right after the connection opens can fail
+                            // before the peer subscribes, so resend once it has.
+                            last_capture = None;
+                            frame_encoder.force_keyframe();
+                        }
+                    }
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(
+                        gossipsub::Event::Unsubscribed { peer_id, topic },
                     )) => {
+                        log::info!("{} unsubscribed from '{}'", peer_id, topic);
+                    }
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(
+                        gossipsub::Event::Message {
+                            propagation_source,
+                            message_id,
+                            message,
+                        },
+                    )) => {
+                        if let Some(room) = &waiting_room {
+                            // Neither show nor forward anything from peers still
+                            // waiting to be admitted.
+                            let allowed = message.source.is_some_and(|source| {
+                                source == local_peer_id || room.is_admitted(&source)
+                            });
+                            let acceptance = if allowed {
+                                gossipsub::MessageAcceptance::Accept
+                            } else {
+                                gossipsub::MessageAcceptance::Ignore
+                            };
+                            if let Err(e) = swarm
+                                .behaviour_mut()
+                                .gossipsub
+                                .report_message_validation_result(&message_id, &propagation_source, acceptance)
+                            {
+                                log::warn!("Failed to report message validation: {}", e);
+                            }
+                            if !allowed {
+                                continue;
+                            }
+                        }
+                        if let (Some(limit), Some(source)) = (&room_limit, &message.source) {
+                            if limit.is_rejected(source) {
+                                continue;
+                            }
+                        }
+                        let source = message.source;
                         let topic = message.topic.as_str();
+                        let data = match crypto::open(cipher.as_ref(), topic, &message.data) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                log::warn!("Dropping message on topic '{}': {}", topic, e);
+                                continue;
+                            }
+                        };
                         if topic == VIDEO_TOPIC {
-                            if let Ok(frame_data) = serde_json::from_slice::<FrameData>(&message.data)
-                            {
-                                if frame_data.peer_id != local_peer_id_str {
-                                    tui.lock().unwrap().update_frame(frame_data);
+                            if let Some(frame_data) = parsed(FrameData::decode(&data), &message, &mut malformed, &tui, &mut tui_dirty) {
+                                if !check_signed(source, &frame_data.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if app.on_frame(&frame_data)
+                                    && tui.lock().unwrap().update_frame(frame_data)
+                                {
                                     tui_dirty = true;
                                 }
                             }
                         } else if topic == AUDIO_TOPIC {
-                            if let Ok(audio_data) =
-                                serde_json::from_slice::<AudioData>(&message.data)
+                            if let Some(audio_data) =
+                                parsed(serde_json::from_slice::<AudioData>(&data), &message, &mut malformed, &tui, &mut tui_dirty)
                             {
-                                if audio_data.peer_id != local_peer_id_str {
-                                    let _ = app_audio_sender.send(audio_data.data);
+                                if !check_signed(source, &audio_data.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if audio_data.peer_id != local_peer_id_str && !app.is_ignored(&audio_data.peer_id) {
+                                    tui.lock()
+                                        .unwrap()
+                                        .update_peer_audio_level(&audio_data.peer_id, &audio_data.data);
+                                    // Levels still show with the speakers muted.
+                                    if let Some(streams) = audio_streams.as_ref().filter(|_| !app.is_output_muted) {
+                                        streams.play(&audio_data.data);
+                                    }
                                 }
                             }
                         } else if topic == CHAT_TOPIC {
-                            if let Ok(chat_message) =
-                                serde_json::from_slice::<ChatMessage>(&message.data)
+                            if let Some(chat_message) =
+                                parsed(serde_json::from_slice::<ChatMessage>(&data), &message, &mut malformed, &tui, &mut tui_dirty)
                             {
-                                if chat_message.peer_id != local_peer_id_str {
-                                    let peer_id_short = &chat_message.peer_id
-                                        [chat_message.peer_id.len() - 6..];
-                                    tui.lock().unwrap().messages.push(format!(
-                                        "{}: {}",
-                                        peer_id_short, chat_message.message
-                                    ));
+                                if !check_signed(source, &chat_message.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if let Some((line, receipt)) = app.on_chat(chat_message) {
+                                    tui.lock().unwrap().push_message(line);
+                                    tui_dirty = true;
+                                    if let Some(receipt) = receipt {
+                                        if let Err(e) = send_outgoing(&mut swarm, cipher.as_ref(), &mut uplink, call.max_message_size, receipt) {
+                                            log::warn!("Failed to send read receipt: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                        } else if topic == REACTION_TOPIC {
+                            if let Some(reaction) = parsed(serde_json::from_slice::<ReactionMessage>(&data), &message, &mut malformed, &tui, &mut tui_dirty) {
+                                if !check_signed(source, &reaction.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if reaction.peer_id != local_peer_id_str && !app.is_ignored(&reaction.peer_id) {
+                                    tui.lock()
+                                        .unwrap()
+                                        .add_peer_reaction(&reaction.peer_id, &reaction.emoji);
                                     tui_dirty = true;
                                 }
                             }
+                        } else if topic == p2p::PRESENCE_TOPIC {
+                            if let Some(heartbeat) = parsed(serde_json::from_slice::<p2p::HeartbeatMessage>(&data), &message, &mut malformed, &tui, &mut tui_dirty) {
+                                if !check_signed(source, &heartbeat.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if let Ok(peer_id) = heartbeat.peer_id.parse::<PeerId>() {
+                                    if peer_id != local_peer_id {
+                                        presence.on_heartbeat(peer_id, Instant::now());
+                                    }
+                                }
+                            }
                         } else if topic == FILE_TOPIC {
                             log::info!("Received file message");
-                            if let Ok(file_message) =
-                                serde_json::from_slice::<FileMessage>(&message.data)
+                            if let Some(mut file_message) =
+                                parsed(serde_json::from_slice::<FileMessage>(&data), &message, &mut malformed, &tui, &mut tui_dirty)
                             {
-                                if file_message.peer_id != local_peer_id_str {
+                                if !check_signed(source, &file_message.peer_id, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                                if app.is_ignored(&file_message.peer_id) {
+                                    log::info!("Dropping file '{}' from ignored peer {}", file_message.file_name, file_message.peer_id);
+                                } else if file_message.peer_id != local_peer_id_str {
                                     log::info!("File message is from another peer. Processing.");
-                                    let download = FileDownload {
-                                        file_name: file_message.file_name.clone(),
-                                        peer_id: file_message.peer_id.clone(),
-                                        state: FileDownloadState::Downloading,
-                                    };
-                                    let download_index = {
-                                        let mut tui_guard = tui.lock().unwrap();
-                                        tui_guard.downloads.push(download);
-                                        tui_guard.downloads.len() - 1
-                                    };
+                                    let download_id = tui
+                                        .lock()
+                                        .unwrap()
+                                        .add_download(&file_message.file_name, &file_message.peer_id);
 
+                                    let Some(file_name) = file_message.safe_file_name().map(str::to_string) else {
+                                        log::warn!("Discarding file '{}' from {}: not a usable file name", file_message.file_name, file_message.peer_id);
+                                        let _ = download_status_sender.send((
+                                            download_id,
+                                            FileDownloadState::Failed("invalid file name".to_string()),
+                                        ));
+                                        tui_dirty = true;
+                                        continue;
+                                    };
+                                    file_message.file_name = file_name;
+                                    if !file_message.verify() {
+                                        log::warn!("Discarding file '{}' from {}: integrity check failed", file_message.file_name, file_message.peer_id);
+                                        let _ = download_status_sender.send((
+                                            download_id,
+                                            FileDownloadState::Failed("integrity check failed".to_string()),
+                                        ));
+                                        tui_dirty = true;
+                                        continue;
+                                    }
                                     let status_sender = download_status_sender.clone();
+                                    let download_dir = config.download_dir();
                                     tokio::spawn(async move {
                                         log::info!("Starting file save for '{}'", &file_message.file_name);
-                                        let downloads_path =
-                                            dirs::download_dir().unwrap_or_else(|| ".".into());
+                                        let downloads_path = download_dir;
                                         if !downloads_path.exists() {
                                             if let Err(e) = fs::create_dir_all(&downloads_path).await {
                                                 log::error!("Failed to create downloads directory: {}", e);
@@ -378,15 +1566,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                         {
                                             Ok(_) => {
                                                 log::info!("File '{}' saved successfully to {:?}", &file_message.file_name, &file_path);
+                                                history::record(history::HistoryEntry::now(
+                                                    history::Direction::Received,
+                                                    &file_message.file_name,
+                                                    file_message.content.len() as u64,
+                                                    Some(&file_message.peer_id),
+                                                    &file_path,
+                                                ));
                                                 FileDownloadState::Completed(
                                                 file_path.to_string_lossy().into_owned(),
                                             )},
                                             Err(e) => {
                                                 log::error!("Failed to save file '{}': {}", &file_message.file_name, e);
-                                                FileDownloadState::Failed
+                                                FileDownloadState::Failed(e.to_string())
                                             },
                                         };
-                                        if status_sender.send((download_index, new_state)).is_err() {
+                                        if status_sender.send((download_id, new_state)).is_err() {
                                             log::error!("Failed to send download status update");
                                         }
                                     });
@@ -395,15 +1590,149 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 }
                             }
                         } else if topic == p2p::CONTROL_TOPIC {
-                            if let Ok(control_msg) =
-                                serde_json::from_slice::<p2p::ControlMessage>(&message.data)
-                            {
-                                if control_msg == p2p::ControlMessage::EndCall {
+                            let control = serde_json::from_slice::<p2p::ControlMessage>(&data);
+                            if let Some(claimed) = control.as_ref().ok().and_then(p2p::ControlMessage::claimed_sender) {
+                                if !check_signed(source, claimed, &mut spoofers, &tui) {
+                                    tui_dirty = true;
+                                    continue;
+                                }
+                            }
+                            match control {
+                                Ok(p2p::ControlMessage::EndCall) => {
+                                    tui.lock().unwrap().stop_call_timer();
                                     break;
                                 }
+                                Ok(p2p::ControlMessage::Hello {
+                                    peer_id,
+                                    protocol_version,
+                                }) => {
+                                    // Peers only reached through the host don't see the
+                                    // newcomer connect, but they do get its Hello.
+                                    if peer_id != local_peer_id_str {
+                                        frame_encoder.force_keyframe();
+                                        capabilities.on_hello(&peer_id);
+                                    }
+                                    if peer_id != local_peer_id_str
+                                        && protocol_version != p2p::PROTOCOL_VERSION
+                                    {
+                                        log::warn!(
+                                            "{} uses protocol version {}, we use {}",
+                                            peer_id,
+                                            protocol_version,
+                                            p2p::PROTOCOL_VERSION
+                                        );
+                                        let mut tui_guard = tui.lock().unwrap();
+                                        tui_guard.push_message(format!(
+                                            "Peer {} uses incompatible version {} (this is version {})",
+                                            util::short_peer_id(&peer_id),
+                                            protocol_version,
+                                            p2p::PROTOCOL_VERSION
+                                        ));
+                                        tui_guard.remove_peer(&peer_id);
+                                        capabilities.remove(&peer_id);
+                                        tui_dirty = true;
+                                        if let Ok(remote) = peer_id.parse::<PeerId>() {
+                                            incompatible_peers.insert(remote, protocol_version);
+                                            // The host drops them; everyone else waits
+                                            // for the host to do so.
+                                            if removed_peers.is_some() {
+                                                let _ = swarm.disconnect_peer_id(remote);
+                                            }
+                                        }
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::HandRaised { peer_id, raised }) => {
+                                    if peer_id != local_peer_id_str {
+                                        tui.lock().unwrap().set_peer_hand(&peer_id, raised);
+                                        tui_dirty = true;
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::Admitted { peer_id }) => {
+                                    if peer_id == local_peer_id_str {
+                                        tui.lock()
+                                            .unwrap()
+                                            .push_message("The host admitted you to the call".to_string());
+                                        tui_dirty = true;
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::RoomFull { peer_id, max_peers, .. }) => {
+                                    if peer_id == local_peer_id_str && source.is_some() && source == host {
+                                        room_full = Some(max_peers);
+                                        break;
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::Seen {
+                                    sender,
+                                    message_id,
+                                    ..
+                                }) => {
+                                    if sender == local_peer_id_str
+                                        && tui.lock().unwrap().mark_seen(message_id)
+                                    {
+                                        tui_dirty = true;
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::Ping { peer_id, nonce }) => {
+                                    if peer_id != local_peer_id_str {
+                                        let pong = p2p::ControlMessage::Pong {
+                                            peer_id: local_peer_id_str.clone(),
+                                            to: peer_id,
+                                            nonce,
+                                        };
+                                        if let Err(e) = send_control(&mut swarm, cipher.as_ref(), &pong) {
+                                            log::debug!("Failed to answer ping: {}", e);
+                                        }
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::Pong { peer_id, to, nonce }) => {
+                                    if to == local_peer_id_str {
+                                        if let Some(rtt) = pings.on_pong(nonce, Instant::now()) {
+                                            tui.lock().unwrap().set_peer_rtt(&peer_id, rtt);
+                                            tui_dirty = true;
+                                        }
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::MuteState {
+                                    peer_id,
+                                    is_audio_muted,
+                                    is_video_muted,
+                                }) => {
+                                    if peer_id != local_peer_id_str {
+                                        tui.lock().unwrap().set_peer_mute(
+                                            &peer_id,
+                                            is_audio_muted,
+                                            is_video_muted,
+                                        );
+                                        tui_dirty = true;
+                                    }
+                                }
+                                Ok(p2p::ControlMessage::Capabilities { peer_id, compression }) => {
+                                    if peer_id != local_peer_id_str {
+                                        log::info!("{} supports compression: {}", peer_id, compression);
+                                        capabilities.on_capabilities(&peer_id, compression);
+                                    }
+                                }
+                                Err(e) => tui_dirty |= note_malformed(&e, &message, &mut malformed, &tui),
                             }
                         }
                     }
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                        let Some((wanted, _)) = peer_lookup else {
+                            continue;
+                        };
+                        remote_addrs = found
+                            .into_iter()
+                            .filter(|(peer_id, _)| *peer_id == wanted)
+                            .map(|(_, addr)| addr.with(Protocol::P2p(wanted)))
+                            .collect();
+                        if !remote_addrs.is_empty() {
+                            log::info!("Found {} at {:?}", wanted, remote_addrs);
+                            peer_lookup = None;
+                            p2p::dial_all(&mut swarm, &remote_addrs);
+                            next_dial = Instant::now() + dial_backoff(dial_attempt);
+                            tui_dirty = true;
+                        }
+                    }
                     SwarmEvent::NewListenAddr { address, .. } => {
                         let listen_addr = address.with(Protocol::P2p(local_peer_id));
                         tui.lock().unwrap().add_listen_address(listen_addr);
@@ -412,16 +1741,524 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     _ => {}
                 }
             },
-            Some((download_index, new_state)) = download_status_receiver.recv() => {
-                log::info!("Received download status update for index {}: {:?}", download_index, new_state);
+            _ = &mut shutdown => {
+                log::info!("Shutting down on signal");
+                if app_status == AppStatus::InCall {
+                    if let Err(e) = end_call(&mut swarm, cipher.as_ref()).await {
+                        log::warn!("Failed to notify peers of call end: {}", e);
+                    }
+                }
+                break;
+            },
+            Some(external) = external_receiver.recv() => {
                 let mut tui_guard = tui.lock().unwrap();
-                if let Some(d) = tui_guard.downloads.get_mut(download_index) {
-                    d.state = new_state;
+                match external {
+                    Ok(addr) => {
+                        log::info!("STUN server sees us as {}", addr);
+                        tui_guard.external_ip = Some(addr.ip());
+                    }
+                    Err(e) => {
+                        log::warn!("Could not find our external address: {}", e);
+                        let notice = format!("Could not find your external address: {}", e);
+                        // In a call it may be a while before anyone looks, so keep it in the chat.
+                        if app_status == AppStatus::InCall {
+                            tui_guard.push_message(notice);
+                        } else {
+                            tui_guard.flash(&notice);
+                        }
+                    }
+                }
+                tui_dirty = true;
+            }
+            Some((download_id, new_state)) = download_status_receiver.recv() => {
+                log::info!("Received download status update for download {}: {:?}", download_id, new_state);
+                if tui.lock().unwrap().update_download(download_id, new_state) {
                     tui_dirty = true;
                 }
             }
         }
     }
 
+    {
+        let mut tui_guard = tui.lock().unwrap();
+        tui_guard.stop_call_timer();
+        if let Err(e) = tui_guard.stop_recording() {
+            log::warn!("Could not finish the recording: {}", e);
+        }
+    }
+    if let Some(path) = call.config.clone().or_else(config::default_path) {
+        match settings.save(&path, &config) {
+            Ok(true) => log::info!("Saved settings to {}", path.display()),
+            Ok(false) => {}
+            Err(e) => log::warn!("Could not save settings to {}: {}", path.display(), e),
+        }
+    }
+    if let Some(version) = incompatible_host {
+        return Err(format!(
+            "The host uses incompatible protocol version {} (this is version {})",
+            version,
+            p2p::PROTOCOL_VERSION
+        )
+        .into());
+    }
+    if let Some(max_peers) = room_full {
+        return Err(format!("The room is full (at most {} peers)", max_peers).into());
+    }
+    if let Some(peer_id) = lookup_failed {
+        return Err(format!(
+            "Peer {} wasn't found on the local network within {}s",
+            peer_id,
+            PEER_LOOKUP_TIMEOUT.as_secs()
+        )
+        .into());
+    }
+    if join_failed {
+        return Err(format!(
+            "Could not connect to the room after {} attempts",
+            MAX_DIAL_ATTEMPTS
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Passes a parsed payload through, or notes that it didn't parse.
+fn parsed<T>(
+    result: Result<T, impl fmt::Display>,
+    message: &gossipsub::Message,
+    malformed: &mut p2p::MalformedMessages,
+    tui: &Mutex<Tui>,
+    tui_dirty: &mut bool,
+) -> Option<T> {
+    result
+        .map_err(|e| *tui_dirty |= note_malformed(&e, message, malformed, tui))
+        .ok()
+}
+
+// Logs a payload that didn't parse and counts it against its sender, who
+// is reported as possibly incompatible after enough of them. Returns true
+// if that report was just made.
+fn note_malformed(
+    error: &impl fmt::Display,
+    message: &gossipsub::Message,
+    malformed: &mut p2p::MalformedMessages,
+    tui: &Mutex<Tui>,
+) -> bool {
+    log::debug!(
+        "Dropping malformed {}-byte message on '{}' from {:?}: {}",
+        message.data.len(),
+        message.topic,
+        message.source,
+        error
+    );
+    let Some(source) = message.source.filter(|source| malformed.record(*source)) else {
+        return false;
+    };
+    log::warn!("{} keeps sending messages we can't read", source);
+    tui.lock().unwrap().push_message(format!(
+        "Peer {} keeps sending messages this version can't read; it may be incompatible",
+        util::short_peer_id(&source.to_string())
+    ));
+    true
+}
+
+// Checks that a message's peer_id is the peer that signed it. Mismatches are
+// dropped, and the first from each signer is pointed out in the chat.
+fn check_signed(
+    source: Option<PeerId>,
+    claimed: &str,
+    spoofers: &mut HashSet<PeerId>,
+    tui: &Mutex<Tui>,
+) -> bool {
+    if p2p::is_signed_by(source.as_ref(), claimed) {
+        return true;
+    }
+    log::warn!(
+        "Dropping a message claiming to be from {} but signed by {:?}",
+        claimed,
+        source
+    );
+    if let Some(source) = source {
+        if spoofers.insert(source) {
+            tui.lock().unwrap().push_message(format!(
+                "Ignoring messages from {} that claim to be from {}",
+                util::short_peer_id(&source.to_string()),
+                util::short_peer_id(claimed)
+            ));
+        }
+    }
+    false
+}
+
+// Sends a file to everyone in the call. There's no chunking, so anything
+// over the gossipsub message limit is refused with an error.
+fn publish_file(
+    swarm: &mut Swarm<p2p::AppBehaviour>,
+    cipher: Option<&crypto::PayloadCipher>,
+    local_peer_id: &str,
+    file_name: &str,
+    content: &[u8],
+    max_message_size: usize,
+    uplink: &mut uplink::UplinkBudget,
+) -> Result<(), Box<dyn Error>> {
+    log::info!("Sending file: {}", file_name);
+    let message = FileMessage::new(local_peer_id, file_name, content);
+    let json = serde_json::to_string(&message)?;
+    let payload = crypto::seal(cipher, FILE_TOPIC, json.as_bytes())?;
+    uplink.spend(payload.len(), Instant::now());
+    p2p::publish(swarm, FILE_TOPIC, payload, max_message_size)?;
+    log::info!("File sent successfully.");
+    Ok(())
+}
+
+// Sends what an App handler asked for, sealed like every other payload.
+fn send_outgoing(
+    swarm: &mut Swarm<p2p::AppBehaviour>,
+    cipher: Option<&crypto::PayloadCipher>,
+    uplink: &mut uplink::UplinkBudget,
+    max_message_size: usize,
+    message: OutgoingMessage,
+) -> Result<(), Box<dyn Error>> {
+    let (topic, json) = match message {
+        OutgoingMessage::Control(message) => return send_control(swarm, cipher, &message),
+        OutgoingMessage::Chat(message) => (CHAT_TOPIC, serde_json::to_string(&message)?),
+        OutgoingMessage::Reaction(message) => (REACTION_TOPIC, serde_json::to_string(&message)?),
+    };
+    let payload = crypto::seal(cipher, topic, json.as_bytes())?;
+    uplink.spend(payload.len(), Instant::now());
+    p2p::publish(swarm, topic, payload, max_message_size)?;
+    Ok(())
+}
+
+// Control messages are sealed too, so peers without the room password can't
+// end the call, mute or kick anyone.
+fn send_control(
+    swarm: &mut Swarm<p2p::AppBehaviour>,
+    cipher: Option<&crypto::PayloadCipher>,
+    message: &p2p::ControlMessage,
+) -> Result<(), Box<dyn Error>> {
+    let json = serde_json::to_string(message)?;
+    p2p::send_control(
+        swarm,
+        crypto::seal(cipher, p2p::CONTROL_TOPIC, json.as_bytes())?,
+    )
+}
+
+async fn end_call(
+    swarm: &mut Swarm<p2p::AppBehaviour>,
+    cipher: Option<&crypto::PayloadCipher>,
+) -> Result<(), Box<dyn Error>> {
+    let json = serde_json::to_string(&p2p::ControlMessage::EndCall)?;
+    p2p::end_call(
+        swarm,
+        crypto::seal(cipher, p2p::CONTROL_TOPIC, json.as_bytes())?,
+    )
+    .await
+}
+
+// Writes the screen, as text, to a timestamped file in the download
+// directory and returns its path.
+fn save_snapshot(download_dir: &Path, text: &str) -> Result<PathBuf, Box<dyn Error>> {
+    std::fs::create_dir_all(download_dir)?;
+    let path = download_dir.join(format!("snapshot-{}.txt", file_timestamp()));
+    std::fs::write(&path, text)?;
+    Ok(path)
+}
+
+// UTC, sortable and safe in a file name, e.g. 20261015-093012.
+fn file_timestamp() -> String {
+    let now = time::OffsetDateTime::now_utc();
+    format!(
+        "{}{:02}{:02}-{:02}{:02}{:02}",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+// Returns the name and contents to send for a picked file, or for a folder
+// an in-memory zip of it.
+fn read_for_sending(path: &Path) -> Result<(String, Vec<u8>), Box<dyn Error>> {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    if path.is_dir() {
+        Ok((format!("{}.zip", name), archive::zip_folder(path)?))
+    } else {
+        Ok((name, std::fs::read(path)?))
+    }
+}
+
+// Chat-only peers have nothing to capture; the placeholder they send new
+// peers doesn't need refreshing often.
+fn capture_interval(config: &Config) -> Duration {
+    if config.chat_only {
+        return CHAT_ONLY_FRAME_INTERVAL;
+    }
+    if config.effective_fps() < config.fps {
+        log::info!(
+            "{} fps is more than a {}ms tick allows, capturing at {} fps",
+            config.fps,
+            config.tick_ms,
+            config.effective_fps()
+        );
+    }
+    Duration::from_secs(1) / config.effective_fps()
+}
+
+fn usable_theme(name: theme::ThemeName) -> theme::ThemeName {
+    if name != theme::ThemeName::Mono && !theme::terminal_has_color() {
+        log::info!("Terminal doesn't support colors, using the mono theme");
+        theme::ThemeName::Mono
+    } else {
+        name
+    }
+}
+
+// 1s, 2s, 4s, then 8s between attempts.
+fn dial_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << (attempt - 1).min(3))
+}
+
+fn run_doctor() -> Result<(), Box<dyn Error>> {
+    let config = Config::load(None)?;
+    println!("rust-meet {}", env!("CARGO_PKG_VERSION"));
+    println!("OS: {} ({})", std::env::consts::OS, std::env::consts::ARCH);
+
+    println!("\nCameras:");
+    match video::list_cameras() {
+        Ok(cameras) if cameras.is_empty() => println!("  none found"),
+        Ok(cameras) => cameras.iter().for_each(|c| println!("  {}", c)),
+        Err(e) => println!("  failed to query cameras: {}", e),
+    }
+    println!(
+        "  ASCII output: {}x{} at {} fps ({}ms tick, keyframe every {}ms)",
+        config.width,
+        config.height,
+        config.effective_fps(),
+        config.tick_ms,
+        config.keyframe_interval_ms
+    );
+
+    println!("\nAudio:");
+    match audio::describe_devices() {
+        Ok(lines) => lines.iter().for_each(|l| println!("  {}", l)),
+        Err(e) => println!("  failed to query audio devices: {}", e),
+    }
+
+    println!("\nNetwork:");
+    println!("  Transport: {}", p2p::TRANSPORT_DESCRIPTION);
+    println!("  Listens on: {:?}", config.transport);
+    println!(
+        "  Max message size: {} bytes",
+        p2p::DEFAULT_MAX_MESSAGE_SIZE
+    );
+
+    println!("\nFiles:");
+    println!("  Download directory: {}", config.download_dir().display());
+    match config::default_path() {
+        Some(path) if path.exists() => println!("  Config file: {}", path.display()),
+        Some(path) => println!("  Config file: {} (not present)", path.display()),
+        None => println!("  Config file: no config directory on this platform"),
+    }
+    Ok(())
+}
+
+fn run_history() -> Result<(), Box<dyn Error>> {
+    let path = history::default_path().ok_or("No config directory on this platform")?;
+    let entries = history::load(&path)?;
+    if entries.is_empty() {
+        println!("No files sent or received yet.");
+    }
+    for entry in &entries {
+        println!("{}", history::format_entry(entry));
+    }
+    Ok(())
+}
+
+// Only finished downloads have anything to open. Returns true if the view
+// needs redrawing to show an error.
+fn open_selected_download(tui: &mut Tui) -> bool {
+    let Some(FileDownload {
+        state: FileDownloadState::Completed(path),
+        ..
+    }) = tui.selected_download()
+    else {
+        return false;
+    };
+    let path = path.clone();
+    log::info!("Opening {}", path);
+    if let Err(e) = opener::open_path(Path::new(&path)) {
+        log::warn!("Failed to open {}: {}", path, e);
+        tui.push_message(format!("Could not open {}: {}", path, e));
+        return true;
+    }
+    false
+}
+
+// Raw mode turns Ctrl-C into a key press instead of SIGINT.
+fn is_ctrl_c(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+// Forwards terminal events from a blocking reader thread into the async loop.
+fn spawn_event_reader() -> mpsc::UnboundedReceiver<Event> {
+    let (key_sender, key_receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if key_sender.send(event).is_err() {
+                // rx closed
+                break;
+            }
+        }
+    });
+    key_receiver
+}
+
+fn is_loopback(address: &Multiaddr) -> bool {
+    address.iter().any(|protocol| match protocol {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+fn parse_speed(s: &str) -> Result<f32, String> {
+    let speed: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (record::MIN_SPEED..=record::MAX_SPEED).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "speed must be between {} and {}",
+            record::MIN_SPEED,
+            record::MAX_SPEED
+        ))
+    }
+}
+
+// Plays a recording back at its own pace: space pauses, +/- change the
+// speed, the arrow keys seek and q quits. The last screen stays up when it
+// ends.
+async fn run_playback(path: &Path, speed: f32, alt_screen: bool) -> Result<(), Box<dyn Error>> {
+    let recording = record::Recording::load(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let mut player = record::Player::new(recording.duration(), speed);
+    let mut tui = Tui::new(alt_screen)?;
+    let mut tick_interval = tokio::time::interval(Duration::from_millis(50));
+    let mut last_tick = Instant::now();
+    let mut key_receiver = spawn_event_reader();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = tick_interval.tick() => {
+                player.advance(last_tick.elapsed());
+                last_tick = Instant::now();
+                let state = if player.finished() {
+                    "ended"
+                } else if player.paused {
+                    "paused"
+                } else {
+                    "playing"
+                };
+                let status = format!(
+                    "{} {} / {} at {}x (space: pause, +/-: speed, ←/→: seek, q: quit)",
+                    state,
+                    tui::format_duration(player.position),
+                    tui::format_duration(recording.duration()),
+                    player.speed
+                );
+                let screen = record::ansi_text(recording.screen_at(player.position));
+                tui.draw_playback(screen, &status)?;
+            },
+            key_event = key_receiver.recv() => {
+                match key_event {
+                    Some(Event::Key(key)) if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char('q') => break,
+                        _ if is_ctrl_c(&key) => break,
+                        KeyCode::Char(' ') => player.paused = !player.paused,
+                        KeyCode::Char('+' | '=') => player.faster(),
+                        KeyCode::Char('-') => player.slower(),
+                        KeyCode::Right => player.seek_forward(),
+                        KeyCode::Left => player.seek_back(),
+                        _ => {}
+                    },
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Resolves on SIGINT or, on Unix, SIGTERM, so the terminal is restored and
+// peers hear the call end instead of the process dying mid-frame.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Can't listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+// Local mic -> speaker loopback with a level meter, no networking involved.
+async fn run_audio_test(agc: bool, aec: bool, alt_screen: bool) -> Result<(), Box<dyn Error>> {
+    let mut tui = Tui::new(alt_screen)?;
+    let mut audio_streams = audio::setup_audio_streams(agc, aec, audio::DEFAULT_FRAME_MS)?;
+
+    let mut meter = audio::LevelMeter::default();
+    let mut tick_interval = tokio::time::interval(Duration::from_millis(50));
+    let mut key_receiver = spawn_event_reader();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = tick_interval.tick() => {
+                for samples in audio_streams.captured_frames() {
+                    meter.update(&samples);
+                    audio_streams.play(&samples);
+                }
+                tui.draw_audio_test(&meter)?;
+                meter.decay();
+            },
+            key_event = key_receiver.recv() => {
+                match key_event {
+                    Some(Event::Key(key))
+                        if key.kind == KeyEventKind::Press
+                            && (key.code == KeyCode::Char('q') || is_ctrl_c(&key)) =>
+                    {
+                        break;
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+    }
+
     Ok(())
 }
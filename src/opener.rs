@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Hands a file to whatever the desktop uses to open it, like double-clicking
+// it in a file manager.
+#[cfg(target_os = "macos")]
+const OPEN_COMMAND: &[&str] = &["open"];
+// Not `cmd /C start`: cmd.exe would run anything after an & in the name.
+#[cfg(target_os = "windows")]
+const OPEN_COMMAND: &[&str] = &["explorer"];
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const OPEN_COMMAND: &[&str] = &["xdg-open"];
+
+// Returns once the opener has started; it is reaped on a background thread
+// since some openers only exit when the application they launched does.
+pub fn open_path(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(OPEN_COMMAND[0])
+        .args(&OPEN_COMMAND[1..])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not run {}: {}", OPEN_COMMAND[0], e))?;
+    std::thread::spawn(move || match child.wait() {
+        // explorer exits with 1 even when it opened the file.
+        Ok(status) if !status.success() && !cfg!(target_os = "windows") => {
+            log::warn!("File opener exited with {}", status)
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to wait for file opener: {}", e),
+    });
+    Ok(())
+}
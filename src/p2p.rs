@@ -1,21 +1,487 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use libp2p::{
+    bandwidth::BandwidthSinks,
+    futures::{FutureExt, Stream, StreamExt},
     gossipsub::{self, IdentTopic as Topic, MessageAuthenticity},
-    identity, mdns, noise,
-    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
-    tcp, yamux, PeerId, Swarm, SwarmBuilder,
+    identity, mdns,
+    multiaddr::Protocol,
+    noise,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder,
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub const VIDEO_TOPIC: &str = "video";
 pub const AUDIO_TOPIC: &str = "audio";
 pub const CHAT_TOPIC: &str = "chat";
 pub const CONTROL_TOPIC: &str = "control";
 pub const FILE_TOPIC: &str = "file";
+pub const REACTION_TOPIC: &str = "reaction";
+pub const PRESENCE_TOPIC: &str = "presence";
+pub const TOPICS: [&str; 7] = [
+    VIDEO_TOPIC,
+    AUDIO_TOPIC,
+    CHAT_TOPIC,
+    CONTROL_TOPIC,
+    FILE_TOPIC,
+    REACTION_TOPIC,
+    PRESENCE_TOPIC,
+];
+
+// Gossipsub has no per-topic size limit, so this has to fit the largest
+// payload we publish (whole files). Peers drop anything above their own
+// limit, so everyone in a room should use the same value.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+// Connections only count as idle once no substream is open, which the
+// media and heartbeat traffic of a call normally prevents, so this mostly
+// decides how long a connection outlives a stalled or finished call.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Mesh sizes for a small call: every peer is usually a direct mesh member,
+// so there's no point in the larger defaults meant for big networks.
+const MESH_N: usize = 4;
+const MESH_N_LOW: usize = 2;
+const MESH_N_HIGH: usize = 8;
+const MESH_OUTBOUND_MIN: usize = 1;
+
+// Bumped with every change to what goes over the wire. Peers only stay
+// connected to peers announcing the same version.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+pub const TRANSPORT_DESCRIPTION: &str = "TCP + Noise + Yamux or QUIC, gossipsub, mDNS";
+
+// Prefix of a compressed FrameData payload. Can't start a JSON document.
+const FRAME_MAGIC: &[u8] = b"\x00RMZ1";
+
+// Time between keyframes, so a peer that missed one, or joined through
+// another peer, gets a whole picture again without waiting long.
+pub const DEFAULT_KEYFRAME_MS: u64 = 2000;
+pub const MIN_KEYFRAME_MS: u64 = 200;
+pub const MAX_KEYFRAME_MS: u64 = 60_000;
+// Deltas touching more than 1/4 of the frame go out as keyframes instead.
+const MAX_DELTA_FRACTION: usize = 4;
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+// A peer missing this many heartbeats in a row is taken to be gone.
+const MISSED_HEARTBEATS: u32 = 3;
+// Unparseable payloads from one peer before it's reported as incompatible.
+const MALFORMED_WARN_AFTER: u32 = 10;
+// Pings still waiting for their pongs. Older ones are given up on.
+const MAX_PENDING_PINGS: usize = 4;
+
+const END_CALL_REPEATS: usize = 2;
+const END_CALL_FLUSH_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ControlMessage {
     EndCall,
+    // Announced to every peer that subscribes. Its shape must never change,
+    // or the peers it is meant to warn about couldn't read it.
+    Hello {
+        peer_id: String,
+        protocol_version: u32,
+    },
+    // Carries the whole state rather than a toggle so repeats are harmless.
+    HandRaised {
+        peer_id: String,
+        raised: bool,
+    },
+    // Sent by a moderated host to the peer it let in.
+    Admitted {
+        peer_id: String,
+    },
+    // Read receipt for a chat message, sent when --read-receipts is on.
+    Seen {
+        peer_id: String,
+        sender: String,
+        message_id: u64,
+    },
+    // Round-trip measurement: everyone answers a ping with a pong addressed
+    // to its sender. Sent along with each heartbeat.
+    Ping {
+        peer_id: String,
+        nonce: u64,
+    },
+    Pong {
+        peer_id: String,
+        to: String,
+        nonce: u64,
+    },
+    // Sent on every mute toggle so tiles update without waiting for a frame.
+    MuteState {
+        peer_id: String,
+        is_audio_muted: bool,
+        is_video_muted: bool,
+    },
+    // Sent by a host with --max-peers to a peer it's about to turn away.
+    // peer_id is that peer; host is the sender. Hosts that predate the host
+    // field send none, and their RoomFull is ignored as unsigned.
+    RoomFull {
+        peer_id: String,
+        max_peers: usize,
+        #[serde(default)]
+        host: String,
+    },
+    // What a peer can decode, sent along with Hello. Peers that predate it
+    // never send one and are taken to support nothing optional.
+    Capabilities {
+        peer_id: String,
+        compression: bool,
+    },
+}
+
+impl ControlMessage {
+    // The peer a message says it's from. Admitted names the peer being let
+    // in rather than the host sending it, and EndCall names no one.
+    pub fn claimed_sender(&self) -> Option<&str> {
+        match self {
+            ControlMessage::EndCall | ControlMessage::Admitted { .. } => None,
+            ControlMessage::RoomFull { host, .. } => Some(host),
+            ControlMessage::Hello { peer_id, .. }
+            | ControlMessage::HandRaised { peer_id, .. }
+            | ControlMessage::Seen { peer_id, .. }
+            | ControlMessage::Ping { peer_id, .. }
+            | ControlMessage::Pong { peer_id, .. }
+            | ControlMessage::MuteState { peer_id, .. }
+            | ControlMessage::Capabilities { peer_id, .. } => Some(peer_id),
+        }
+    }
+}
+
+// Whether the peer id a message names as its sender is the one that signed
+// it. Strict validation checks the signature on every gossipsub message, so
+// the source can be trusted where a peer_id field in the payload can't.
+pub fn is_signed_by(source: Option<&PeerId>, claimed: &str) -> bool {
+    source.is_some_and(|source| source.to_string() == claimed)
+}
+
+// Host-side state for --moderated rooms. Peers that connect wait here until
+// the host admits or rejects them, one at a time in arrival order.
+#[derive(Default)]
+pub struct WaitingRoom {
+    pending: VecDeque<PeerId>,
+    admitted: HashSet<PeerId>,
+    rejected: HashSet<PeerId>,
+}
+
+impl WaitingRoom {
+    // Returns true if the peer now has to wait for the host.
+    pub fn on_connected(&mut self, peer_id: PeerId) -> bool {
+        if self.admitted.contains(&peer_id) {
+            return false;
+        }
+        if !self.pending.contains(&peer_id) {
+            self.pending.push_back(peer_id);
+        }
+        true
+    }
+
+    // Returns true if the peer was still waiting or had been turned away,
+    // i.e. its leaving doesn't affect the call.
+    pub fn on_disconnected(&mut self, peer_id: &PeerId) -> bool {
+        let was_pending = self.pending.contains(peer_id);
+        self.pending.retain(|pending| pending != peer_id);
+        was_pending || self.rejected.remove(peer_id)
+    }
+
+    pub fn next_pending(&self) -> Option<&PeerId> {
+        self.pending.front()
+    }
+
+    pub fn admit(&mut self) -> Option<PeerId> {
+        let peer_id = self.pending.pop_front()?;
+        self.admitted.insert(peer_id);
+        Some(peer_id)
+    }
+
+    pub fn reject(&mut self) -> Option<PeerId> {
+        let peer_id = self.pending.pop_front()?;
+        self.rejected.insert(peer_id);
+        Some(peer_id)
+    }
+
+    pub fn is_admitted(&self, peer_id: &PeerId) -> bool {
+        self.admitted.contains(peer_id)
+    }
+}
+
+// Host-side record of peers it removed from the call. Banned peers are
+// disconnected again whenever they reconnect, until the app exits.
+#[derive(Default)]
+pub struct RemovedPeers {
+    kicked: HashSet<PeerId>,
+    banned: HashSet<PeerId>,
+}
+
+impl RemovedPeers {
+    pub fn kick(&mut self, swarm: &mut Swarm<AppBehaviour>, peer_id: PeerId, ban: bool) {
+        if ban {
+            self.banned.insert(peer_id);
+        }
+        if swarm.disconnect_peer_id(peer_id).is_ok() {
+            self.kicked.insert(peer_id);
+        }
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned.contains(peer_id)
+    }
+
+    // Disconnects a banned peer that has come back. Returns true if it was
+    // turned away.
+    pub fn on_connected(&mut self, swarm: &mut Swarm<AppBehaviour>, peer_id: PeerId) -> bool {
+        let banned = self.is_banned(&peer_id);
+        if banned {
+            self.kick(swarm, peer_id, true);
+        }
+        banned
+    }
+
+    // Returns true if we closed this peer's connection on purpose, so its
+    // leaving doesn't end the call.
+    pub fn on_disconnected(&mut self, peer_id: &PeerId) -> bool {
+        self.kicked.remove(peer_id)
+    }
+}
+
+// How long a peer turned away by --max-peers stays connected, so the
+// RoomFull message has a chance to reach it first.
+const ROOM_FULL_GRACE: Duration = Duration::from_secs(2);
+
+// Host-side cap on the number of peers in the call. Peers over the cap are
+// told so once they subscribe, then disconnected when their grace runs out.
+pub struct RoomLimit {
+    pub max_peers: usize,
+    rejected: HashMap<PeerId, Instant>, // with when to disconnect them
+    closed: HashSet<PeerId>,
+}
+
+impl RoomLimit {
+    pub fn new(max_peers: usize) -> Self {
+        Self {
+            max_peers,
+            rejected: HashMap::new(),
+            closed: HashSet::new(),
+        }
+    }
+
+    // Called with every connected peer, the new one included. Returns true
+    // if the new peer is over the cap.
+    pub fn on_connected<'a>(
+        &mut self,
+        peer_id: PeerId,
+        connected: impl Iterator<Item = &'a PeerId>,
+        now: Instant,
+    ) -> bool {
+        let in_call = connected
+            .filter(|peer| **peer != peer_id && !self.rejected.contains_key(peer))
+            .count();
+        if in_call < self.max_peers {
+            return false;
+        }
+        self.rejected.insert(peer_id, now + ROOM_FULL_GRACE);
+        true
+    }
+
+    pub fn is_rejected(&self, peer_id: &PeerId) -> bool {
+        self.rejected.contains_key(peer_id)
+    }
+
+    // Rejected peers whose grace is up; they're expected to be disconnected.
+    pub fn expired(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .rejected
+            .iter()
+            .filter(|(_, deadline)| now >= **deadline)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in &expired {
+            self.rejected.remove(peer_id);
+            self.closed.insert(*peer_id);
+        }
+        expired
+    }
+
+    // Returns true if this was a peer we turned away, so its leaving doesn't
+    // end the call.
+    pub fn on_disconnected(&mut self, peer_id: &PeerId) -> bool {
+        self.rejected.remove(peer_id).is_some() | self.closed.remove(peer_id)
+    }
+}
+
+// Counts the payloads from each peer that didn't parse. A few can be a
+// glitch; this many mean the peer is likely on an incompatible version.
+#[derive(Default)]
+pub struct MalformedMessages {
+    counts: HashMap<PeerId, u32>,
+}
+
+impl MalformedMessages {
+    // Returns true once, when the peer reaches MALFORMED_WARN_AFTER.
+    pub fn record(&mut self, source: PeerId) -> bool {
+        let count = self.counts.entry(source).or_default();
+        *count += 1;
+        *count == MALFORMED_WARN_AFTER
+    }
+}
+
+// Tracks when each peer was last heard on the presence topic, so peers
+// that vanish without closing their connection (killed, network lost)
+// don't linger in the call. Peers are only tracked after their first
+// heartbeat.
+#[derive(Default)]
+pub struct Presence {
+    last_heartbeat: HashMap<PeerId, Instant>,
+    timed_out: HashSet<PeerId>,
+}
+
+impl Presence {
+    pub fn on_heartbeat(&mut self, peer_id: PeerId, now: Instant) {
+        self.last_heartbeat.insert(peer_id, now);
+        self.timed_out.remove(&peer_id);
+    }
+
+    // Removes and returns the peers that have gone quiet for too long.
+    pub fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let timeout = HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        let expired: Vec<PeerId> = self
+            .last_heartbeat
+            .iter()
+            .filter(|(_, last)| now.saturating_duration_since(**last) > timeout)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in &expired {
+            self.last_heartbeat.remove(peer_id);
+            self.timed_out.insert(*peer_id);
+        }
+        expired
+    }
+
+    // Heartbeats the peer is overdue on, allowing one interval of slack for
+    // delivery. Zero for peers that haven't sent one yet.
+    pub fn missed_heartbeats(&self, peer_id: &PeerId, now: Instant) -> u32 {
+        self.last_heartbeat.get(peer_id).map_or(0, |last| {
+            let overdue = now
+                .saturating_duration_since(*last)
+                .saturating_sub(HEARTBEAT_INTERVAL);
+            (overdue.as_millis() / HEARTBEAT_INTERVAL.as_millis()) as u32
+        })
+    }
+
+    // Returns true if the peer had already timed out, i.e. it was dropped
+    // from the call before its connection finally closed.
+    pub fn on_disconnected(&mut self, peer_id: &PeerId) -> bool {
+        self.last_heartbeat.remove(peer_id);
+        self.timed_out.remove(peer_id)
+    }
+}
+
+// What each peer in the call said it can decode. Gossipsub sends one copy
+// of a message to everyone, so we encode for whatever all of them support.
+#[derive(Default)]
+pub struct PeerCapabilities {
+    compression: HashMap<String, bool>,
+}
+
+impl PeerCapabilities {
+    // Every peer says hello; only newer ones follow up with Capabilities,
+    // which may also arrive first.
+    pub fn on_hello(&mut self, peer_id: &str) {
+        self.compression.entry(peer_id.to_string()).or_insert(false);
+    }
+
+    pub fn on_capabilities(&mut self, peer_id: &str, compression: bool) {
+        self.compression.insert(peer_id.to_string(), compression);
+    }
+
+    pub fn remove(&mut self, peer_id: &str) {
+        self.compression.remove(peer_id);
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compression.values().all(|supported| *supported)
+    }
+}
+
+// Our pings that haven't been answered by everyone yet.
+#[derive(Default)]
+pub struct Pings {
+    pending: VecDeque<(u64, Instant)>,
+    next_nonce: u64,
+}
+
+impl Pings {
+    // Returns the nonce to send.
+    pub fn start(&mut self, now: Instant) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pending.push_back((nonce, now));
+        if self.pending.len() > MAX_PENDING_PINGS {
+            self.pending.pop_front();
+        }
+        nonce
+    }
+
+    // Round-trip time for a pong, if it answers a ping we're still waiting on.
+    // Each peer answers the same ping, so it stays pending for the others.
+    pub fn on_pong(&self, nonce: u64, now: Instant) -> Option<Duration> {
+        self.pending
+            .iter()
+            .find(|(sent, _)| *sent == nonce)
+            .map(|(_, sent_at)| now.saturating_duration_since(*sent_at))
+    }
+}
+
+// Looks up a connected peer by its full id or a suffix of it, such as the
+// short ids shown in the participant list.
+pub fn find_connected_peer(swarm: &Swarm<AppBehaviour>, query: &str) -> Result<PeerId, String> {
+    let matches: Vec<PeerId> = swarm
+        .connected_peers()
+        .filter(|peer_id| !query.is_empty() && peer_id.to_string().ends_with(query))
+        .copied()
+        .collect();
+    match matches.as_slice() {
+        [peer_id] => Ok(*peer_id),
+        [] => Err(format!("No connected peer matches '{}'", query)),
+        _ => Err(format!("'{}' matches more than one peer", query)),
+    }
+}
+
+// Which transport a created room listens on. The swarm can dial either.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Quic,
+}
+
+impl Transport {
+    pub fn listen_addresses(&self, port: u16) -> (Multiaddr, Multiaddr) {
+        let (v4, v6) = match self {
+            Transport::Tcp => (
+                format!("/ip4/0.0.0.0/tcp/{}", port),
+                format!("/ip6/::/tcp/{}", port),
+            ),
+            Transport::Quic => (
+                format!("/ip4/0.0.0.0/udp/{}/quic-v1", port),
+                format!("/ip6/::/udp/{}/quic-v1", port),
+            ),
+        };
+        (
+            v4.parse().expect("valid multiaddr"),
+            v6.parse().expect("valid multiaddr"),
+        )
+    }
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -28,11 +494,128 @@ pub enum AppStatus {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FrameData {
     pub peer_id: String,
-    pub frame: String, // ASCII frame
+    // Increases by one per published frame so receivers can drop frames that
+    // gossipsub delivered late or twice. Audio has the same reordering issue
+    // but a stale chunk only costs a few ms of sound, so it isn't tracked.
+    #[serde(default)]
+    pub seq: u64,
+    pub frame: FrameUpdate,
     pub is_audio_muted: bool,
     pub is_video_muted: bool,
 }
 
+impl FrameData {
+    // Deflated JSON behind a magic prefix. Mostly-blank ASCII frames shrink
+    // several times over, and the prefix tells them apart from the plain
+    // JSON that older peers send. Without `compress` it's that plain JSON,
+    // for rooms with a peer that can't inflate.
+    pub fn encode(&self, compress: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        if !compress {
+            return Ok(serde_json::to_vec(self)?);
+        }
+        let mut encoder = DeflateEncoder::new(FRAME_MAGIC.to_vec(), Compression::fast());
+        serde_json::to_writer(&mut encoder, self)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        match payload.strip_prefix(FRAME_MAGIC) {
+            Some(compressed) => Ok(serde_json::from_reader(DeflateDecoder::new(compressed))?),
+            None => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+}
+
+// Either a whole ASCII frame or the characters that changed since the
+// sender's previous frame. Untagged so a keyframe is a plain string, which
+// is also what peers without delta support send.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FrameUpdate {
+    Key(String),
+    Delta(Vec<(u16, char)>),
+}
+
+impl FrameUpdate {
+    // Rebuilds the full frame. A delta needs the frame it was taken against.
+    pub fn apply(self, previous: Option<&str>) -> Option<String> {
+        match self {
+            FrameUpdate::Key(frame) => Some(frame),
+            FrameUpdate::Delta(changes) => {
+                let mut chars: Vec<char> = previous?.chars().collect();
+                for (index, c) in changes {
+                    *chars.get_mut(index as usize)? = c;
+                }
+                Some(chars.into_iter().collect())
+            }
+        }
+    }
+}
+
+// Sender side of FrameUpdate. Sends a delta when only a small part of the
+// frame changed, and a keyframe otherwise or once the keyframe interval is
+// up, so receivers that missed something catch up.
+pub struct FrameEncoder {
+    last_frame: Option<Vec<char>>,
+    last_key: Option<Instant>,
+    keyframe_interval: Duration,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(DEFAULT_KEYFRAME_MS))
+    }
+}
+
+impl FrameEncoder {
+    pub fn new(keyframe_interval: Duration) -> Self {
+        Self {
+            last_frame: None,
+            last_key: None,
+            keyframe_interval,
+        }
+    }
+
+    pub fn encode(&mut self, frame: &str, now: Instant) -> FrameUpdate {
+        let chars: Vec<char> = frame.chars().collect();
+        let key_due = self
+            .last_key
+            .is_none_or(|last_key| now.duration_since(last_key) >= self.keyframe_interval);
+        let delta = match &self.last_frame {
+            Some(last)
+                if last.len() == chars.len() && chars.len() <= u16::MAX as usize && !key_due =>
+            {
+                let changes: Vec<(u16, char)> = last
+                    .iter()
+                    .zip(&chars)
+                    .enumerate()
+                    .filter(|(_, (old, new))| old != new)
+                    .map(|(i, (_, &new))| (i as u16, new))
+                    .collect();
+                (changes.len() <= chars.len() / MAX_DELTA_FRACTION).then_some(changes)
+            }
+            _ => None,
+        };
+        self.last_frame = Some(chars);
+        match delta {
+            Some(changes) => FrameUpdate::Delta(changes),
+            None => {
+                self.last_key = Some(now);
+                FrameUpdate::Key(frame.to_string())
+            }
+        }
+    }
+
+    pub fn keyframe_pending(&self) -> bool {
+        self.last_frame.is_none()
+    }
+
+    // Makes the next frame a keyframe, e.g. because a peer just joined.
+    pub fn force_keyframe(&mut self) {
+        self.last_frame = None;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AudioData {
     pub peer_id: String,
@@ -42,7 +625,24 @@ pub struct AudioData {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
     pub peer_id: String,
+    #[serde(default)]
+    pub nickname: Option<String>,
     pub message: String,
+    // Counts up per sender; 0 from peers that predate read receipts.
+    #[serde(default)]
+    pub message_id: u64,
+}
+
+// Published on the presence topic every HEARTBEAT_INTERVAL while in a call.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeartbeatMessage {
+    pub peer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionMessage {
+    pub peer_id: String,
+    pub emoji: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,6 +650,39 @@ pub struct FileMessage {
     pub peer_id: String,
     pub file_name: String,
     pub content: Vec<u8>,
+    // Hex SHA-256 of the content. Peers that predate it leave it out, and
+    // their files are taken as they are.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+impl FileMessage {
+    pub fn new(peer_id: &str, file_name: &str, content: &[u8]) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            file_name: file_name.to_string(),
+            content: content.to_vec(),
+            sha256: Some(sha256_hex(content)),
+        }
+    }
+
+    // False if the content doesn't match the hash it was sent with.
+    pub fn verify(&self) -> bool {
+        self.sha256
+            .as_ref()
+            .is_none_or(|sha256| sha256.eq_ignore_ascii_case(&sha256_hex(&self.content)))
+    }
+
+    // The name to save under. Only the last component is kept, so "../" or an
+    // absolute path can't place the file outside the download directory.
+    pub fn safe_file_name(&self) -> Option<&str> {
+        let name = Path::new(&self.file_name).file_name()?.to_str()?;
+        (!matches!(name, "" | "." | "..")).then_some(name)
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
 }
 
 // The network behaviour combines multiple protocols.
@@ -61,9 +694,10 @@ pub struct AppBehaviour {
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum AppBehaviourEvent {
     Gossipsub(gossipsub::Event),
-    Mdns(()),
+    Mdns(mdns::Event),
 }
 
 impl From<gossipsub::Event> for AppBehaviourEvent {
@@ -73,75 +707,848 @@ impl From<gossipsub::Event> for AppBehaviourEvent {
 }
 
 impl From<mdns::Event> for AppBehaviourEvent {
-    fn from(_: mdns::Event) -> Self {
-        AppBehaviourEvent::Mdns(())
+    fn from(event: mdns::Event) -> Self {
+        AppBehaviourEvent::Mdns(event)
     }
 }
 
-pub async fn create_swarm(use_mdns: bool) -> Result<Swarm<AppBehaviour>, Box<dyn Error>> {
+pub struct SwarmOptions {
+    pub use_mdns: bool,
+    pub max_message_size: usize,
+    pub idle_timeout: Duration,
+    // Count the bytes going over every connection, protocol overhead included.
+    pub bandwidth_metrics: bool,
+    // Hold every message until the app reports whether to forward it.
+    pub validate_messages: bool,
+}
+
+impl Default for SwarmOptions {
+    fn default() -> Self {
+        Self {
+            use_mdns: true,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            bandwidth_metrics: false,
+            validate_messages: false,
+        }
+    }
+}
+
+// The byte counters are only returned when asked for in the options.
+pub async fn create_swarm(
+    options: SwarmOptions,
+) -> Result<(Swarm<AppBehaviour>, Option<Arc<BandwidthSinks>>), Box<dyn Error>> {
     // Create a random PeerId
     let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
+    let behaviour = app_behaviour(&local_key, &options)?;
 
-    // Create a Gossipsub topic
-    let video_topic = Topic::new(VIDEO_TOPIC);
-    let audio_topic = Topic::new(AUDIO_TOPIC);
-    let chat_topic = Topic::new(CHAT_TOPIC);
-    let control_topic = Topic::new(CONTROL_TOPIC);
-    let file_topic = Topic::new(FILE_TOPIC);
-
-    // Create a Swarm to manage peers and events
-    let swarm = {
-        let gossipsub_config = gossipsub::ConfigBuilder::default()
-            .max_transmit_size(10 * 1024 * 1024) // 10MB
-            .build()
-            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::Other, msg))?;
-        let mut gossipsub: gossipsub::Behaviour = gossipsub::Behaviour::new(
-            MessageAuthenticity::Signed(local_key.clone()),
-            gossipsub_config,
-        )
-        .map_err(|msg| std::io::Error::new(std::io::ErrorKind::Other, msg))?;
-        gossipsub.subscribe(&video_topic)?;
-        gossipsub.subscribe(&audio_topic)?;
-        gossipsub.subscribe(&chat_topic)?;
-        gossipsub.subscribe(&control_topic)?;
-        gossipsub.subscribe(&file_topic)?;
-
-        let mdns = if use_mdns {
-            Some(mdns::tokio::Behaviour::new(
-                mdns::Config::default(),
-                local_peer_id,
-            )?)
-            .into()
-        } else {
-            None.into()
-        };
+    // Counting bytes costs next to nothing, so every swarm does it and the
+    // counters are simply dropped when nobody asked for them.
+    let (builder, sinks) = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_bandwidth_logging();
+    let swarm = builder
+        .with_behaviour(|_key| behaviour)?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(options.idle_timeout))
+        .build();
+    Ok((swarm, options.bandwidth_metrics.then_some(sinks)))
+}
 
-        let behaviour = AppBehaviour { gossipsub, mdns };
-
-        SwarmBuilder::with_existing_identity(local_key)
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_behaviour(|_key| behaviour)?
-            .with_swarm_config(|c| {
-                c.with_idle_connection_timeout(std::time::Duration::from_secs(60))
-            })
-            .build()
+// Gossipsub subscribed to all our topics, plus mDNS if it's wanted.
+fn app_behaviour(
+    local_key: &identity::Keypair,
+    options: &SwarmOptions,
+) -> Result<AppBehaviour, Box<dyn Error>> {
+    let mut gossipsub_config = gossipsub::ConfigBuilder::default();
+    gossipsub_config
+        .max_transmit_size(options.max_message_size)
+        .mesh_n(MESH_N)
+        .mesh_n_low(MESH_N_LOW)
+        .mesh_n_high(MESH_N_HIGH)
+        .mesh_outbound_min(MESH_OUTBOUND_MIN)
+        .validation_mode(gossipsub::ValidationMode::Strict);
+    if options.validate_messages {
+        gossipsub_config.validate_messages();
+    }
+    let gossipsub_config = gossipsub_config.build().map_err(std::io::Error::other)?;
+    let mut gossipsub: gossipsub::Behaviour = gossipsub::Behaviour::new(
+        MessageAuthenticity::Signed(local_key.clone()),
+        gossipsub_config,
+    )
+    .map_err(std::io::Error::other)?;
+    for topic in TOPICS {
+        gossipsub.subscribe(&Topic::new(topic))?;
+    }
+
+    let mdns = if options.use_mdns {
+        Some(mdns::tokio::Behaviour::new(
+            mdns::Config::default(),
+            PeerId::from(local_key.public()),
+        )?)
+        .into()
+    } else {
+        None.into()
     };
 
-    Ok(swarm)
+    Ok(AppBehaviour { gossipsub, mdns })
 }
 
-pub fn end_call(swarm: &mut Swarm<AppBehaviour>) -> Result<(), Box<dyn Error>> {
-    let control_topic = Topic::new(CONTROL_TOPIC);
-    let message = serde_json::to_string(&ControlMessage::EndCall)?;
+// Totals and per-second rates read off the transport's byte counters.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthStats {
+    pub total_inbound: u64,
+    pub total_outbound: u64,
+    pub inbound_per_sec: u64,
+    pub outbound_per_sec: u64,
+}
+
+pub struct BandwidthMeter {
+    sinks: Arc<BandwidthSinks>,
+    last_sample: (u64, u64, Instant),
+    stats: BandwidthStats,
+}
+
+impl BandwidthMeter {
+    pub fn new(sinks: Arc<BandwidthSinks>, now: Instant) -> Self {
+        Self {
+            last_sample: (sinks.total_inbound(), sinks.total_outbound(), now),
+            sinks,
+            stats: BandwidthStats::default(),
+        }
+    }
+
+    // Rates are averaged over at least a second, so a call on every tick
+    // doesn't make them jump around.
+    pub fn update(&mut self, now: Instant) -> BandwidthStats {
+        self.sample(self.sinks.total_inbound(), self.sinks.total_outbound(), now)
+    }
+
+    fn sample(&mut self, inbound: u64, outbound: u64, now: Instant) -> BandwidthStats {
+        let (last_inbound, last_outbound, last_at) = self.last_sample;
+        let elapsed = now.saturating_duration_since(last_at);
+        if elapsed >= Duration::from_secs(1) {
+            let per_sec = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.stats.inbound_per_sec = per_sec(inbound.saturating_sub(last_inbound));
+            self.stats.outbound_per_sec = per_sec(outbound.saturating_sub(last_outbound));
+            self.last_sample = (inbound, outbound, now);
+        }
+        self.stats.total_inbound = inbound;
+        self.stats.total_outbound = outbound;
+        self.stats
+    }
+}
+
+// Subscribes again to any of our topics that went missing, e.g. after the
+// mesh emptied out. Returns how many had to be renewed.
+pub fn ensure_subscribed(swarm: &mut Swarm<AppBehaviour>) -> Result<usize, Box<dyn Error>> {
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+    let mut renewed = 0;
+    for name in TOPICS {
+        let topic = Topic::new(name);
+        if !gossipsub.topics().any(|hash| *hash == topic.hash()) {
+            log::warn!("Not subscribed to '{}', subscribing again", name);
+            gossipsub.subscribe(&topic)?;
+            renewed += 1;
+        }
+    }
+    Ok(renewed)
+}
+
+// Returns how many of the addresses could be dialed at all.
+pub fn dial_all(swarm: &mut Swarm<AppBehaviour>, addrs: &[Multiaddr]) -> usize {
+    addrs
+        .iter()
+        .filter(|addr| match swarm.dial((*addr).clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Failed to dial {}: {}", addr, e);
+                false
+            }
+        })
+        .count()
+}
+
+// Gossipsub wraps every payload in an envelope (topic, sender, sequence
+// number, signature) that counts against max_transmit_size too.
+const PUBLISH_ENVELOPE_ALLOWANCE: usize = 256;
+
+#[derive(Debug)]
+pub enum PublishError {
+    TooLarge { size: usize, limit: usize },
+    Gossipsub(gossipsub::PublishError),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::TooLarge { size, limit } => {
+                write!(f, "too large to send ({} bytes, limit {})", size, limit)
+            }
+            PublishError::Gossipsub(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PublishError {}
+
+// Gossipsub rejects oversized messages only once they're handed over, and
+// callers tend to ignore publish errors, so the size is checked here first
+// where it can be reported on its own.
+pub fn publish(
+    swarm: &mut Swarm<AppBehaviour>,
+    topic: &str,
+    payload: Vec<u8>,
+    max_message_size: usize,
+) -> Result<(), PublishError> {
+    let limit = max_message_size.saturating_sub(PUBLISH_ENVELOPE_ALLOWANCE);
+    if payload.len() > limit {
+        return Err(PublishError::TooLarge {
+            size: payload.len(),
+            limit,
+        });
+    }
     swarm
         .behaviour_mut()
         .gossipsub
-        .publish(control_topic, message.as_bytes())?;
+        .publish(Topic::new(topic), payload)
+        .map(|_| ())
+        .map_err(PublishError::Gossipsub)
+}
+
+pub fn send_control(
+    swarm: &mut Swarm<AppBehaviour>,
+    payload: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(Topic::new(CONTROL_TOPIC), payload)?;
+    Ok(())
+}
+
+// Gossipsub only queues a publish; if the process exits right away the
+// message may never leave. Send EndCall a couple of times and keep the swarm
+// running briefly in between so it actually reaches the other peers.
+// `payload` is the EndCall message as it goes on the wire.
+pub async fn end_call(
+    swarm: &mut Swarm<AppBehaviour>,
+    payload: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let control_topic = Topic::new(CONTROL_TOPIC);
+    for _ in 0..END_CALL_REPEATS {
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(control_topic.clone(), payload.clone())?;
+        drive_swarm_for(swarm, END_CALL_FLUSH_INTERVAL).await;
+    }
     Ok(())
 }
+
+async fn drive_swarm_for(swarm: &mut Swarm<AppBehaviour>, duration: Duration) {
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = swarm.select_next_some() => {}
+        }
+    }
+}
+
+pub type AppSwarmEvent = <Swarm<AppBehaviour> as Stream>::Item;
+
+// Most ready events taken from the swarm at once. Bounds how long a flood
+// of video can hold back the events behind it.
+const MAX_EVENT_BATCH: usize = 64;
+
+// Video and file chunks are large and plentiful, so every other event that
+// is already waiting - control, chat, presence, reactions, audio, connection
+// changes - is handled before them. Gossipsub itself shares one mesh and one
+// send queue per peer between all topics, so this only orders our own
+// handling; it can't make chat overtake video on the wire.
+//
+// Ordering guarantees: events of the same kind (bulk or not) are handled in
+// the order the swarm produced them, so a peer's video deltas and file chunks
+// stay in sequence. A non-bulk event may be handled before a bulk one that
+// arrived earlier, never the other way round.
+#[derive(Default)]
+pub struct EventQueue {
+    events: VecDeque<AppSwarmEvent>,
+}
+
+impl EventQueue {
+    pub async fn next(&mut self, swarm: &mut Swarm<AppBehaviour>) -> AppSwarmEvent {
+        if self.events.is_empty() {
+            let event = swarm.select_next_some().await;
+            self.events.push_back(event);
+        }
+        while self.events.len() < MAX_EVENT_BATCH {
+            match swarm.next().now_or_never() {
+                Some(Some(event)) => self.events.push_back(event),
+                _ => break,
+            }
+        }
+        take_prioritized(&mut self.events, is_bulk).expect("queue was filled above")
+    }
+}
+
+fn is_bulk(event: &AppSwarmEvent) -> bool {
+    matches!(
+        event,
+        SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. }))
+            if message.topic.as_str() == VIDEO_TOPIC || message.topic.as_str() == FILE_TOPIC
+    )
+}
+
+// The oldest event that isn't bulk, or the oldest bulk one if that's all
+// there is.
+fn take_prioritized<T>(queue: &mut VecDeque<T>, is_bulk: impl Fn(&T) -> bool) -> Option<T> {
+    let index = queue.iter().position(|event| !is_bulk(event)).unwrap_or(0);
+    queue.remove(index)
+}
+
+// Picks the address most likely to be reachable by someone else: a LAN/public
+// IPv4 address first, then a non-link-local IPv6 one, loopback last.
+pub fn best_listen_address(addresses: &[Multiaddr]) -> Option<&Multiaddr> {
+    addresses
+        .iter()
+        .min_by_key(|addr| match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) if !ip.is_loopback() => 0,
+            // fe80::/10 is link-local.
+            Some(Protocol::Ip6(ip)) if !ip.is_loopback() && ip.segments()[0] & 0xffc0 != 0xfe80 => {
+                1
+            }
+            _ => 2,
+        })
+}
+
+// Our listen addresses as seen from outside, given the public IP a STUN
+// server reported. Ports are kept as they are, so these only work where the
+// NAT forwards them unchanged.
+pub fn external_addresses(listen_addresses: &[Multiaddr], ip: IpAddr) -> Vec<Multiaddr> {
+    let mut external: Vec<Multiaddr> = listen_addresses
+        .iter()
+        .filter_map(|addr| {
+            let mut parts = addr.iter();
+            let replaced = match (parts.next()?, ip) {
+                (Protocol::Ip4(_), IpAddr::V4(ip)) => Protocol::Ip4(ip),
+                (Protocol::Ip6(_), IpAddr::V6(ip)) => Protocol::Ip6(ip),
+                _ => return None,
+            };
+            Some(std::iter::once(replaced).chain(parts).collect())
+        })
+        .collect();
+    external.sort();
+    external.dedup();
+    external
+}
+
+// Whether peers on the internet can dial us directly, judged by whether
+// the address a STUN server saw is one of our own. Behind a NAT it isn't.
+// A firewall can still get in the way either way.
+pub fn is_publicly_reachable(listen_addresses: &[Multiaddr], ip: IpAddr) -> bool {
+    listen_addresses
+        .iter()
+        .any(|addr| match addr.iter().next() {
+            Some(Protocol::Ip4(own)) => IpAddr::V4(own) == ip,
+            Some(Protocol::Ip6(own)) => IpAddr::V6(own) == ip,
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A face-sized blob of shading on a blank background, roughly what the
+    // camera produces for someone sitting in front of a plain wall.
+    fn representative_frame() -> String {
+        let ramp = crate::video::ASCII_CHARS;
+        let mut frame = String::new();
+        for y in 0..40i32 {
+            for x in 0..80i32 {
+                let (dx, dy) = ((x - 40) as f32 / 2.0, (y - 20) as f32);
+                let distance = (dx * dx + dy * dy).sqrt();
+                let shade = (1.0 - distance / 16.0).max(0.0);
+                frame.push(ramp[(shade * (ramp.len() - 1) as f32) as usize]);
+            }
+            frame.push('\n');
+        }
+        frame
+    }
+
+    fn frame_data(frame: String) -> FrameData {
+        FrameData {
+            peer_id: PeerId::random().to_string(),
+            seq: 42,
+            frame: FrameUpdate::Key(frame),
+            is_audio_muted: false,
+            is_video_muted: true,
+        }
+    }
+
+    #[test]
+    fn frame_compression_round_trips() {
+        let original = frame_data(representative_frame());
+        let decoded = FrameData::decode(&original.encode(true).unwrap()).unwrap();
+        assert_eq!(decoded.peer_id, original.peer_id);
+        assert_eq!(decoded.seq, original.seq);
+        assert_eq!(decoded.frame, original.frame);
+        assert!(decoded.is_video_muted);
+    }
+
+    #[test]
+    fn small_changes_are_sent_as_deltas() {
+        let first = representative_frame();
+        let mut second: Vec<char> = first.chars().collect();
+        second[100] = '@';
+        second[200] = '@';
+        let second: String = second.into_iter().collect();
+
+        let mut encoder = FrameEncoder::default();
+        let key = encoder.encode(&first, Instant::now());
+        assert_eq!(key, FrameUpdate::Key(first.clone()));
+        let delta = encoder.encode(&second, Instant::now());
+        assert_eq!(delta, FrameUpdate::Delta(vec![(100, '@'), (200, '@')]));
+
+        let rebuilt = key.apply(None).unwrap();
+        assert_eq!(delta.clone().apply(Some(&rebuilt)).unwrap(), second);
+        assert_eq!(delta.apply(None), None);
+    }
+
+    #[test]
+    fn keyframes_are_sent_periodically_and_on_request() {
+        let frame = representative_frame();
+        let mut encoder = FrameEncoder::new(Duration::from_secs(2));
+        let start = Instant::now();
+        // 20 fps for a little over 2s.
+        let kinds: Vec<bool> = (0..=41)
+            .map(|i| {
+                let now = start + Duration::from_millis(50 * i);
+                matches!(encoder.encode(&frame, now), FrameUpdate::Key(_))
+            })
+            .collect();
+        assert!(kinds[0] && kinds[40]);
+        assert_eq!(kinds.iter().filter(|&&key| key).count(), 2);
+
+        encoder.force_keyframe();
+        let now = start + Duration::from_millis(2100);
+        assert!(matches!(encoder.encode(&frame, now), FrameUpdate::Key(_)));
+    }
+
+    #[test]
+    fn frame_compression_ratio() {
+        let frame_data = frame_data(representative_frame());
+        let plain = serde_json::to_vec(&frame_data).unwrap().len();
+        let compressed = frame_data.encode(true).unwrap().len();
+        let ratio = plain as f32 / compressed as f32;
+        assert!(ratio >= 4.0, "only compressed {:.1}x", ratio);
+    }
+
+    #[test]
+    fn uncompressed_frames_are_still_accepted() {
+        let original = frame_data(representative_frame());
+        let plain = serde_json::to_vec(&original).unwrap();
+        assert_eq!(FrameData::decode(&plain).unwrap().frame, original.frame);
+
+        // Peers without delta support send the frame as a plain string.
+        let legacy =
+            br#"{"peer_id":"p","frame":"ab\n","is_audio_muted":false,"is_video_muted":false}"#;
+        let decoded = FrameData::decode(legacy).unwrap();
+        assert_eq!(decoded.seq, 0);
+        assert_eq!(decoded.frame, FrameUpdate::Key("ab\n".to_string()));
+    }
+
+    #[test]
+    fn only_the_signing_peer_may_claim_a_message() {
+        let signer = PeerId::random();
+        let other = PeerId::random();
+        assert!(is_signed_by(Some(&signer), &signer.to_string()));
+        assert!(!is_signed_by(Some(&signer), &other.to_string()));
+        assert!(!is_signed_by(None, &signer.to_string()));
+        let ping = ControlMessage::Ping {
+            peer_id: other.to_string(),
+            nonce: 1,
+        };
+        assert_eq!(ping.claimed_sender(), Some(other.to_string().as_str()));
+        let admitted = ControlMessage::Admitted {
+            peer_id: other.to_string(),
+        };
+        assert_eq!(admitted.claimed_sender(), None);
+        let room_full = ControlMessage::RoomFull {
+            peer_id: other.to_string(),
+            max_peers: 2,
+            host: signer.to_string(),
+        };
+        assert_eq!(
+            room_full.claimed_sender(),
+            Some(signer.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn files_are_checked_against_their_hash() {
+        let mut file = FileMessage::new("peer", "notes.txt", b"hello");
+        assert_eq!(
+            file.sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        assert!(file.verify());
+        file.content.truncate(3);
+        assert!(!file.verify());
+        // Older peers send no hash at all.
+        file.sha256 = None;
+        assert!(file.verify());
+    }
+
+    #[test]
+    fn received_file_names_stay_in_the_download_dir() {
+        let name = |file_name: &str| {
+            FileMessage::new("peer", file_name, b"")
+                .safe_file_name()
+                .map(str::to_string)
+        };
+        assert_eq!(name("notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(name("../../.bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(name(""), None);
+        assert_eq!(name("."), None);
+        assert_eq!(name(".."), None);
+        assert_eq!(name("a/.."), None);
+    }
+
+    #[test]
+    fn external_addresses_swap_in_the_public_ip() {
+        let listen: Vec<Multiaddr> = [
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/192.168.1.5/tcp/4001",
+            "/ip4/192.168.1.5/udp/4001/quic-v1",
+            "/ip6/::1/tcp/4001",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let external: Vec<String> = external_addresses(&listen, "203.0.113.7".parse().unwrap())
+            .iter()
+            .map(Multiaddr::to_string)
+            .collect();
+        assert_eq!(
+            external,
+            [
+                "/ip4/203.0.113.7/tcp/4001",
+                "/ip4/203.0.113.7/udp/4001/quic-v1"
+            ]
+        );
+        assert!(!is_publicly_reachable(
+            &listen,
+            "203.0.113.7".parse().unwrap()
+        ));
+        assert!(is_publicly_reachable(
+            &listen,
+            "192.168.1.5".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn compression_needs_every_peer() {
+        let mut capabilities = PeerCapabilities::default();
+        assert!(capabilities.compression());
+        capabilities.on_hello("new");
+        capabilities.on_capabilities("new", true);
+        capabilities.on_hello("new");
+        assert!(capabilities.compression());
+        capabilities.on_hello("old");
+        assert!(!capabilities.compression());
+        capabilities.remove("old");
+        assert!(capabilities.compression());
+    }
+
+    #[test]
+    fn malformed_senders_are_reported_once() {
+        let mut malformed = MalformedMessages::default();
+        let peer = PeerId::random();
+        let reports = (0..MALFORMED_WARN_AFTER * 2)
+            .filter(|_| malformed.record(peer))
+            .count();
+        assert_eq!(reports, 1);
+        assert!(!malformed.record(PeerId::random()));
+    }
+
+    #[test]
+    fn quiet_peers_expire_once() {
+        let mut presence = Presence::default();
+        let (alive, dead) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        presence.on_heartbeat(alive, start);
+        presence.on_heartbeat(dead, start);
+
+        let later = start + HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        assert_eq!(
+            presence.missed_heartbeats(&alive, start + HEARTBEAT_INTERVAL),
+            0
+        );
+        assert_eq!(
+            presence.missed_heartbeats(&alive, later),
+            MISSED_HEARTBEATS - 1
+        );
+        presence.on_heartbeat(alive, later);
+        assert!(presence.expire(later).is_empty());
+
+        let much_later = later + HEARTBEAT_INTERVAL;
+        assert_eq!(presence.expire(much_later), vec![dead]);
+        assert!(presence.expire(much_later).is_empty());
+        assert!(presence.on_disconnected(&dead));
+        assert!(!presence.on_disconnected(&alive));
+    }
+
+    #[test]
+    fn pongs_only_answer_recent_pings() {
+        let mut pings = Pings::default();
+        let start = Instant::now();
+        let first = pings.start(start);
+        for _ in 0..MAX_PENDING_PINGS {
+            pings.start(start);
+        }
+        let last = pings.start(start + Duration::from_secs(1));
+        let later = start + Duration::from_millis(1040);
+        assert_eq!(pings.on_pong(first, later), None);
+        assert_eq!(pings.on_pong(last, later), Some(Duration::from_millis(40)));
+        // A second peer answering the same ping still counts.
+        assert_eq!(pings.on_pong(last, later), Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn hello_keeps_its_wire_format() {
+        let hello = ControlMessage::Hello {
+            peer_id: "peer".to_string(),
+            protocol_version: 7,
+        };
+        assert_eq!(
+            serde_json::to_string(&hello).unwrap(),
+            r#"{"Hello":{"peer_id":"peer","protocol_version":7}}"#
+        );
+    }
+
+    // A host listening on localhost and a guest connected to it, with the
+    // address the guest dialed.
+    async fn connected_pair() -> (Swarm<AppBehaviour>, Swarm<AppBehaviour>, Multiaddr) {
+        let options = || SwarmOptions {
+            use_mdns: false,
+            ..Default::default()
+        };
+        let (mut host, _) = create_swarm(options()).await.unwrap();
+        let (mut guest, _) = create_swarm(options()).await.unwrap();
+        host.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = host.select_next_some().await {
+                break address;
+            }
+        };
+        guest.dial(address.clone()).unwrap();
+        let guest_id = *guest.local_peer_id();
+        host_sees_guest(&mut host, &mut guest, guest_id, true).await;
+        (host, guest, address)
+    }
+
+    // Runs both swarms until the host has connected to the guest, or lost
+    // its last connection to it.
+    async fn host_sees_guest(
+        host: &mut Swarm<AppBehaviour>,
+        guest: &mut Swarm<AppBehaviour>,
+        guest_id: PeerId,
+        connected: bool,
+    ) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = host.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. }
+                            if connected && peer_id == guest_id => break,
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                            if !connected && peer_id == guest_id => break,
+                        _ => {}
+                    },
+                    _ = guest.select_next_some() => {}
+                }
+            }
+        })
+        .await
+        .expect("the guest never connected or disconnected");
+    }
+
+    // Two real swarms on localhost: one listens, the other dials, and a chat
+    // message published by the host has to arrive intact at the guest.
+    #[tokio::test]
+    async fn chat_reaches_a_peer_over_localhost() {
+        let (mut host, mut guest, _) = connected_pair().await;
+
+        let sent = ChatMessage {
+            peer_id: host.local_peer_id().to_string(),
+            nickname: Some("Ann".to_string()),
+            message: "hello".to_string(),
+            message_id: 1,
+        };
+        let chat_hash = Topic::new(CHAT_TOPIC).hash();
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = host.select_next_some() => {
+                        // Publishing only works once the guest has joined the topic.
+                        if let SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(
+                            gossipsub::Event::Subscribed { topic, .. },
+                        )) = event
+                        {
+                            if topic == chat_hash {
+                                let json = serde_json::to_string(&sent).unwrap();
+                                let payload =
+                                    crate::crypto::seal(None, CHAT_TOPIC, json.as_bytes()).unwrap();
+                                publish(&mut host, CHAT_TOPIC, payload, DEFAULT_MAX_MESSAGE_SIZE)
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    event = guest.select_next_some() => {
+                        if let SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(
+                            gossipsub::Event::Message { message, .. },
+                        )) = event
+                        {
+                            assert_eq!(message.topic, chat_hash);
+                            assert_eq!(message.source, Some(*host.local_peer_id()));
+                            let data =
+                                crate::crypto::open(None, CHAT_TOPIC, &message.data).unwrap();
+                            break serde_json::from_slice::<ChatMessage>(&data).unwrap();
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .expect("the chat message never arrived");
+        assert_eq!(received.message, "hello");
+        assert_eq!(received.nickname.as_deref(), Some("Ann"));
+        assert_eq!(received.peer_id, sent.peer_id);
+        assert_eq!(received.message_id, 1);
+    }
+
+    #[test]
+    fn the_waiting_room_admits_in_arrival_order() {
+        let mut room = WaitingRoom::default();
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let third = PeerId::random();
+        assert!(room.on_connected(first));
+        assert!(room.on_connected(second));
+        // A second connection from a waiting peer doesn't queue it again.
+        assert!(room.on_connected(first));
+        assert_eq!(room.next_pending(), Some(&first));
+
+        assert_eq!(room.admit(), Some(first));
+        assert!(room.is_admitted(&first));
+        assert!(!room.on_connected(first));
+        assert_eq!(room.next_pending(), Some(&second));
+
+        assert_eq!(room.reject(), Some(second));
+        assert!(!room.is_admitted(&second));
+        assert_eq!(room.next_pending(), None);
+        assert_eq!(room.admit(), None);
+        assert_eq!(room.reject(), None);
+
+        // Leaving while waiting or after being turned away doesn't touch the
+        // call, unlike an admitted peer leaving.
+        assert!(room.on_connected(third));
+        assert!(room.on_disconnected(&third));
+        assert_eq!(room.next_pending(), None);
+        assert!(room.on_disconnected(&second));
+        assert!(!room.on_disconnected(&second));
+        assert!(!room.on_disconnected(&first));
+    }
+
+    #[tokio::test]
+    async fn kicked_peers_may_rejoin_but_banned_ones_are_turned_away() {
+        let (mut host, mut guest, address) = connected_pair().await;
+        let guest_id = *guest.local_peer_id();
+        let mut removed = RemovedPeers::default();
+
+        removed.kick(&mut host, guest_id, false);
+        host_sees_guest(&mut host, &mut guest, guest_id, false).await;
+        assert!(removed.on_disconnected(&guest_id));
+        guest.dial(address.clone()).unwrap();
+        host_sees_guest(&mut host, &mut guest, guest_id, true).await;
+        assert!(!removed.on_connected(&mut host, guest_id));
+        assert!(host.is_connected(&guest_id));
+        // Only the disconnect we caused is ours; if the kicked peer leaves
+        // again, that counts as a normal departure.
+        assert!(!removed.on_disconnected(&guest_id));
+
+        removed.kick(&mut host, guest_id, true);
+        host_sees_guest(&mut host, &mut guest, guest_id, false).await;
+        assert!(removed.on_disconnected(&guest_id));
+        guest.dial(address).unwrap();
+        host_sees_guest(&mut host, &mut guest, guest_id, true).await;
+        assert!(removed.on_connected(&mut host, guest_id));
+        host_sees_guest(&mut host, &mut guest, guest_id, false).await;
+        assert!(!host.is_connected(&guest_id));
+        assert!(removed.on_disconnected(&guest_id));
+    }
+
+    #[tokio::test]
+    async fn bandwidth_rates_are_averaged_over_a_second() {
+        let options = SwarmOptions {
+            use_mdns: false,
+            bandwidth_metrics: true,
+            ..Default::default()
+        };
+        let (_swarm, sinks) = create_swarm(options).await.unwrap();
+        let start = Instant::now();
+        let mut meter = BandwidthMeter::new(sinks.unwrap(), start);
+
+        // Totals follow right away, rates only once a second has passed.
+        let stats = meter.sample(1000, 500, start + Duration::from_millis(500));
+        assert_eq!((stats.total_inbound, stats.total_outbound), (1000, 500));
+        assert_eq!((stats.inbound_per_sec, stats.outbound_per_sec), (0, 0));
+
+        let stats = meter.sample(3000, 1500, start + Duration::from_secs(2));
+        assert_eq!((stats.inbound_per_sec, stats.outbound_per_sec), (1500, 750));
+
+        let stats = meter.sample(3500, 1500, start + Duration::from_millis(2500));
+        assert_eq!(stats.total_inbound, 3500);
+        assert_eq!((stats.inbound_per_sec, stats.outbound_per_sec), (1500, 750));
+        let stats = meter.sample(4500, 1500, start + Duration::from_secs(4));
+        assert_eq!((stats.inbound_per_sec, stats.outbound_per_sec), (750, 0));
+    }
+
+    #[test]
+    fn peers_over_the_limit_are_turned_away() {
+        let mut limit = RoomLimit::new(1);
+        let start = Instant::now();
+        let first = PeerId::random();
+        let second = PeerId::random();
+        assert!(!limit.on_connected(first, [first].iter(), start));
+        assert!(limit.on_connected(second, [first, second].iter(), start));
+        assert!(limit.is_rejected(&second));
+        assert!(limit.expired(start).is_empty());
+        assert_eq!(limit.expired(start + ROOM_FULL_GRACE), vec![second]);
+        assert!(limit.on_disconnected(&second));
+        assert!(!limit.on_disconnected(&first));
+        // Once the first peer leaves there's room again.
+        let third = PeerId::random();
+        assert!(!limit.on_connected(third, [third].iter(), start));
+    }
+
+    #[test]
+    fn bulk_events_wait_but_keep_their_order() {
+        // Odd numbers stand in for video.
+        let mut queue: VecDeque<u32> = [1, 3, 2, 5, 4].into_iter().collect();
+        let odd = |n: &u32| n % 2 == 1;
+        let order: Vec<u32> = std::iter::from_fn(|| take_prioritized(&mut queue, odd)).collect();
+        assert_eq!(order, [2, 4, 1, 3, 5]);
+    }
+}
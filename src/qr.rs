@@ -0,0 +1,615 @@
+// Minimal QR code encoder for sharing join addresses: byte mode, error
+// correction level L, versions 1-10 (up to 271 bytes). Follows the layout
+// in ISO/IEC 18004.
+
+const MAX_VERSION: usize = 10;
+// Indexed by version, level L only.
+const ECC_CODEWORDS_PER_BLOCK: [usize; MAX_VERSION + 1] =
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18];
+const NUM_ERROR_CORRECTION_BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4];
+// Format bits for level L.
+const ECC_FORMAT_BITS: u32 = 1;
+// The spec's minimum; scanners often fail to find codes with less.
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl QrCode {
+    // Returns None if the data doesn't fit in the largest supported version.
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let version = (1..=MAX_VERSION).find(|&v| data.len() <= data_capacity(v))?;
+        let codewords = add_ecc_and_interleave(version, &encode_data(version, data));
+
+        let size = version * 4 + 17;
+        let mut qr = QrCode {
+            size,
+            modules: vec![false; size * size],
+            is_function: vec![false; size * size],
+        };
+        qr.draw_function_patterns(version);
+        qr.draw_codewords(&codewords);
+
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                qr.apply_mask(mask);
+                qr.draw_format_bits(mask);
+                let penalty = qr.penalty_score();
+                qr.apply_mask(mask);
+                penalty
+            })
+            .unwrap_or(0);
+        qr.apply_mask(mask);
+        qr.draw_format_bits(mask);
+        Some(qr)
+    }
+
+    // Renders two module rows per line. Light modules (including the quiet
+    // zone) are drawn as block glyphs, so this must be shown with a light
+    // foreground on a dark background.
+    pub fn to_half_blocks(&self) -> Vec<String> {
+        let total = self.size + QUIET_ZONE * 2;
+        let is_light = |x: usize, y: usize| {
+            if x < QUIET_ZONE
+                || y < QUIET_ZONE
+                || x >= total - QUIET_ZONE
+                || y >= total - QUIET_ZONE
+            {
+                return true;
+            }
+            !self.module(x - QUIET_ZONE, y - QUIET_ZONE)
+        };
+
+        (0..total)
+            .step_by(2)
+            .map(|y| {
+                (0..total)
+                    .map(|x| {
+                        let top = is_light(x, y);
+                        let bottom = y + 1 >= total || is_light(x, y + 1);
+                        match (top, bottom) {
+                            (true, true) => '█',
+                            (true, false) => '▀',
+                            (false, true) => '▄',
+                            (false, false) => ' ',
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn rendered_width(&self) -> usize {
+        self.size + QUIET_ZONE * 2
+    }
+
+    pub fn rendered_height(&self) -> usize {
+        self.rendered_width().div_ceil(2)
+    }
+
+    fn module(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function_module(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function_module(6, i, i % 2 == 0);
+            self.set_function_module(i, 6, i % 2 == 0);
+        }
+
+        self.draw_finder_pattern(3, 3);
+        self.draw_finder_pattern(size - 4, 3);
+        self.draw_finder_pattern(3, size - 4);
+
+        let positions = alignment_pattern_positions(version, size);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                let overlaps_finder = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if !overlaps_finder {
+                    self.draw_alignment_pattern(x, y);
+                }
+            }
+        }
+
+        // Reserve the format areas; the real bits are drawn after masking.
+        self.draw_format_bits(0);
+        self.draw_version_bits(version);
+    }
+
+    fn draw_finder_pattern(&mut self, cx: usize, cy: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let x = cx as i32 + dx;
+                let y = cy as i32 + dy;
+                if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+                    continue;
+                }
+                let dist = dx.abs().max(dy.abs());
+                self.set_function_module(x as usize, y as usize, dist != 2 && dist != 4);
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, cx: usize, cy: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let dist = dx.abs().max(dy.abs());
+                self.set_function_module(
+                    (cx as i32 + dx) as usize,
+                    (cy as i32 + dy) as usize,
+                    dist != 1,
+                );
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        let size = self.size;
+
+        for i in 0..=5 {
+            self.set_function_module(8, i, bit(i));
+        }
+        self.set_function_module(8, 7, bit(6));
+        self.set_function_module(8, 8, bit(7));
+        self.set_function_module(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function_module(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function_module(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function_module(8, size - 15 + i, bit(i));
+        }
+        self.set_function_module(8, size - 8, true);
+    }
+
+    fn draw_version_bits(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let mut rem = version as u32;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+        }
+        let bits = ((version as u32) << 12) | rem;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function_module(a, b, dark);
+            self.set_function_module(b, a, dark);
+        }
+    }
+
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let total_bits = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.is_function[y * size + x] && i < total_bits {
+                        self.modules[y * size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    // Applying the same mask twice undoes it.
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if invert && !self.is_function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    fn penalty_score(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+
+        // Runs of five or more same-colored modules in a row or column.
+        for transpose in [false, true] {
+            for a in 0..size {
+                let mut run_color = false;
+                let mut run_len = 0;
+                for b in 0..size {
+                    let dark = if transpose {
+                        self.module(a, b)
+                    } else {
+                        self.module(b, a)
+                    };
+                    if b > 0 && dark == run_color {
+                        run_len += 1;
+                        if run_len == 5 {
+                            penalty += 3;
+                        } else if run_len > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run_color = dark;
+                        run_len = 1;
+                    }
+                }
+            }
+        }
+
+        penalty += self.finder_like_penalty();
+
+        // 2x2 blocks of the same color.
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.module(x, y);
+                if color == self.module(x + 1, y)
+                    && color == self.module(x, y + 1)
+                    && color == self.module(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Imbalance between dark and light modules.
+        let dark = self.modules.iter().filter(|&&m| m).count();
+        let total = size * size;
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+
+    // Patterns like the inside of a finder: dark, light, dark, light, dark
+    // in the ratio 1:1:3:1:1, with four light modules' worth on either
+    // side. Outside the symbol counts as light.
+    fn finder_like_penalty(&self) -> usize {
+        let mut penalty = 0;
+        for transpose in [false, true] {
+            for a in 0..self.size {
+                let runs = self.runs(a, transpose);
+                for j in (1..runs.len().saturating_sub(5)).filter(|&j| runs[j].0) {
+                    let n = runs[j].1;
+                    let core = [1, 1, 3, 1, 1]
+                        .iter()
+                        .zip(&runs[j..j + 5])
+                        .all(|(ratio, &(_, len))| len == ratio * n);
+                    if core {
+                        let (before, after) = (runs[j - 1].1, runs[j + 5].1);
+                        if before >= n * 4 && after >= n {
+                            penalty += 40;
+                        }
+                        if after >= n * 4 && before >= n {
+                            penalty += 40;
+                        }
+                    }
+                }
+            }
+        }
+        penalty
+    }
+
+    // Color and length of each run of modules along a row (or a column if
+    // transposed), starting and ending with the light area around the symbol.
+    fn runs(&self, a: usize, transpose: bool) -> Vec<(bool, usize)> {
+        let mut runs = vec![(false, self.size)];
+        for b in 0..self.size {
+            let dark = if transpose {
+                self.module(a, b)
+            } else {
+                self.module(b, a)
+            };
+            match runs.last_mut() {
+                Some((color, len)) if *color == dark => *len += 1,
+                _ => runs.push((dark, 1)),
+            }
+        }
+        match runs.last_mut() {
+            Some((false, len)) => *len += self.size,
+            _ => runs.push((false, self.size)),
+        }
+        runs
+    }
+}
+
+// Error correction level and mask, BCH-protected and XORed with the fixed
+// pattern from the spec.
+fn format_bits(mask: u32) -> u32 {
+    let data = (ECC_FORMAT_BITS << 3) | mask;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    ((data << 10) | rem) ^ 0x5412
+}
+
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        result -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_data_modules(version) / 8
+        - ECC_CODEWORDS_PER_BLOCK[version] * NUM_ERROR_CORRECTION_BLOCKS[version]
+}
+
+fn char_count_bits(version: usize) -> usize {
+    if version <= 9 {
+        8
+    } else {
+        16
+    }
+}
+
+fn data_capacity(version: usize) -> usize {
+    // 4 bits of mode indicator plus the character count field.
+    (data_codewords(version) * 8 - 4 - char_count_bits(version)) / 8
+}
+
+fn alignment_pattern_positions(version: usize, size: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let num_align = version / 7 + 2;
+    let step = (version * 8 + num_align * 3 + 5) / (num_align * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..num_align - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+fn encode_data(version: usize, data: &[u8]) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::new();
+    let push_bits = |value: usize, len: usize, bits: &mut Vec<bool>| {
+        for i in (0..len).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+    push_bits(0b0100, 4, &mut bits);
+    push_bits(data.len(), char_count_bits(version), &mut bits);
+    for &byte in data {
+        push_bits(byte as usize, 8, &mut bits);
+    }
+
+    let capacity_bits = data_codewords(version) * 8;
+    let terminator = (capacity_bits - bits.len()).min(4);
+    push_bits(0, terminator, &mut bits);
+    let pad = (8 - bits.len() % 8) % 8;
+    push_bits(0, pad, &mut bits);
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |acc, &b| (acc << 1) | b as u8))
+        .collect();
+    for pad_byte in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= data_codewords(version) {
+            break;
+        }
+        codewords.push(pad_byte);
+    }
+    codewords
+}
+
+fn add_ecc_and_interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let num_blocks = NUM_ERROR_CORRECTION_BLOCKS[version];
+    let block_ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+
+    let divisor = reed_solomon_divisor(block_ecc_len);
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(num_blocks);
+    let mut k = 0;
+    for i in 0..num_blocks {
+        let data_len = short_block_len - block_ecc_len + usize::from(i >= num_short_blocks);
+        let mut block = data[k..k + data_len].to_vec();
+        k += data_len;
+        let ecc = reed_solomon_remainder(&block, &divisor);
+        if i < num_short_blocks {
+            block.push(0);
+        }
+        block.extend(ecc);
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            // Skip the padding byte added to short blocks.
+            if i != short_block_len - block_ecc_len || j >= num_short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, &coef) in result.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(coef, factor);
+        }
+    }
+    result
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bits_match_the_spec_table() {
+        let level_l = [
+            0x77c4, 0x72f3, 0x7daa, 0x789d, 0x662f, 0x6318, 0x6c41, 0x6976,
+        ];
+        for (mask, expected) in level_l.into_iter().enumerate() {
+            assert_eq!(format_bits(mask as u32), expected, "mask {}", mask);
+        }
+
+        // Both copies in a finished symbol carry the bits for one mask.
+        let qr = QrCode::encode(b"/ip4/192.0.2.1/tcp/4001").unwrap();
+        let size = qr.size;
+        let read = |positions: &[(usize, usize)]| {
+            positions
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (i, &(x, y))| bits | (qr.module(x, y) as u32) << i)
+        };
+        let first: Vec<(usize, usize)> = (0..=5)
+            .map(|i| (8, i))
+            .chain([(8, 7), (8, 8), (7, 8)])
+            .chain((9..15).map(|i| (14 - i, 8)))
+            .collect();
+        let second: Vec<(usize, usize)> = (0..8)
+            .map(|i| (size - 1 - i, 8))
+            .chain((8..15).map(|i| (8, size - 15 + i)))
+            .collect();
+        assert_eq!(read(&first), read(&second));
+        assert!(level_l.contains(&read(&first)));
+    }
+
+    #[test]
+    fn codewords_match_reference_encodings() {
+        // "hello" in byte mode: 0100, a count of 5, the bytes, a terminator,
+        // then the alternating pad bytes.
+        let data = encode_data(1, b"hello");
+        assert_eq!(
+            data,
+            [
+                0x40, 0x56, 0x86, 0x56, 0xc6, 0xc6, 0xf0, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec,
+                0x11, 0xec, 0x11, 0xec, 0x11
+            ]
+        );
+
+        // The error correction worked examples for "HELLO WORLD" and
+        // "01234567" at version 1-M.
+        let divisor = reed_solomon_divisor(10);
+        let hello_world = [
+            0x20, 0x5b, 0x0b, 0x78, 0xd1, 0x72, 0xdc, 0x4d, 0x43, 0x40, 0xec, 0x11, 0xec, 0x11,
+            0xec, 0x11,
+        ];
+        assert_eq!(
+            reed_solomon_remainder(&hello_world, &divisor),
+            [0xc4, 0x23, 0x27, 0x77, 0xeb, 0xd7, 0xe7, 0xe2, 0x5d, 0x17]
+        );
+        let digits = [
+            0x10, 0x20, 0x0c, 0x56, 0x61, 0x80, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11,
+            0xec, 0x11,
+        ];
+        assert_eq!(
+            reed_solomon_remainder(&digits, &divisor),
+            [0xa5, 0x24, 0xd4, 0xc1, 0xed, 0x36, 0xc7, 0x87, 0x2c, 0x55]
+        );
+    }
+
+    #[test]
+    fn finder_like_patterns_are_penalized() {
+        let mut finder_like = QrCode {
+            size: 21,
+            modules: vec![false; 21 * 21],
+            is_function: vec![false; 21 * 21],
+        };
+        assert_eq!(finder_like.finder_like_penalty(), 0);
+        for (x, dark) in [true, false, true, true, true, false, true]
+            .into_iter()
+            .enumerate()
+        {
+            finder_like.modules[10 * 21 + 7 + x] = dark;
+        }
+        let runs = finder_like.runs(10, false);
+        assert_eq!(
+            runs,
+            [
+                (false, 28),
+                (true, 1),
+                (false, 1),
+                (true, 3),
+                (false, 1),
+                (true, 1),
+                (false, 28)
+            ]
+        );
+        // Light on both sides counts twice.
+        assert_eq!(finder_like.finder_like_penalty(), 80);
+    }
+
+    #[test]
+    fn quiet_zone_is_four_modules() {
+        let qr = QrCode::encode(b"hello").unwrap();
+        assert_eq!(qr.rendered_width(), 21 + 8);
+        assert!(qr.to_half_blocks()[0].chars().all(|c| c == '█'));
+        assert!(qr.to_half_blocks()[1].chars().all(|c| c == '█'));
+    }
+}
@@ -0,0 +1,413 @@
+use crossterm::style::{
+    Attribute, Color as CColor, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor,
+};
+use ratatui::{
+    buffer::Buffer,
+    style::{Color, Modifier, Style},
+    text::{Line, Span, Text},
+};
+use serde_json::json;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Playback speeds reachable with the speed keys, halving or doubling.
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 16.0;
+const SEEK_STEP: Duration = Duration::from_secs(5);
+
+// Modifiers carried over into the recording, with the attribute that turns
+// each one on.
+const ATTRIBUTES: [(Modifier, Attribute); 5] = [
+    (Modifier::BOLD, Attribute::Bold),
+    (Modifier::DIM, Attribute::Dim),
+    (Modifier::ITALIC, Attribute::Italic),
+    (Modifier::UNDERLINED, Attribute::Underlined),
+    (Modifier::REVERSED, Attribute::Reverse),
+];
+
+// Writes what's drawn on screen to an asciicast v2 file: a JSON header line,
+// then one [seconds, "o", output] line per screen that differs from the last.
+// Each screen is written out whole, so any event can be seeked to.
+pub struct Recorder<W: Write> {
+    out: W,
+    start: Instant,
+    size: (u16, u16),
+    last: String,
+}
+
+impl Recorder<BufWriter<File>> {
+    pub fn create(path: &Path, width: u16, height: u16) -> io::Result<Self> {
+        Recorder::new(BufWriter::new(File::create(path)?), width, height)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut out: W, width: u16, height: u16) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+        });
+        writeln!(out, "{}", header)?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+            size: (width, height),
+            last: String::new(),
+        })
+    }
+
+    pub fn frame(&mut self, buffer: &Buffer) -> io::Result<()> {
+        let size = (buffer.area.width, buffer.area.height);
+        if size != self.size {
+            self.size = size;
+            self.last.clear();
+            self.event("r", &format!("{}x{}", size.0, size.1))?;
+        }
+        let screen = buffer_ansi(buffer);
+        if screen == self.last {
+            return Ok(());
+        }
+        // Clear first so a screen follows cleanly after a resize or a seek.
+        let output = format!("\x1b[?25l\x1b[H\x1b[2J{}", screen);
+        self.event("o", &output)?;
+        self.last = screen;
+        Ok(())
+    }
+
+    // asciicast needs no footer, so finishing is just a flush.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let seconds = self.start.elapsed().as_secs_f64();
+        writeln!(self.out, "{}", json!([seconds, code, data]))
+    }
+}
+
+// The screen as terminal output, rows separated by CRLF, with colors and
+// modifiers set only where they change.
+fn buffer_ansi(buffer: &Buffer) -> String {
+    let mut output = String::new();
+    for y in buffer.area.top()..buffer.area.bottom() {
+        if y > buffer.area.top() {
+            output.push_str("\r\n");
+        }
+        // Every row starts from the default style.
+        let mut style = None;
+        output.push_str(&format!("{}{}", SetAttribute(Attribute::Reset), ResetColor));
+        let mut x = buffer.area.left();
+        while x < buffer.area.right() {
+            let cell = buffer.get(x, y);
+            let cell_style = (cell.fg, cell.bg, cell.modifier);
+            if style != Some(cell_style) {
+                output.push_str(&format!(
+                    "{}{}{}",
+                    SetAttribute(Attribute::Reset),
+                    SetForegroundColor(CColor::from(cell.fg)),
+                    SetBackgroundColor(CColor::from(cell.bg))
+                ));
+                for (modifier, attribute) in ATTRIBUTES {
+                    if cell.modifier.contains(modifier) {
+                        output.push_str(&SetAttribute(attribute).to_string());
+                    }
+                }
+                style = Some(cell_style);
+            }
+            let symbol = cell.symbol();
+            output.push_str(symbol);
+            // A wide character covers the cells after it too.
+            x += (Line::from(symbol).width() as u16).max(1);
+        }
+    }
+    output.push_str(&format!("{}{}", SetAttribute(Attribute::Reset), ResetColor));
+    output
+}
+
+// A recording made with --record, loaded for playback. Each output event
+// there is a whole screen, so screens are kept as they are and not replayed
+// through a terminal emulator; recordings from other tools that draw
+// incrementally won't look right.
+pub struct Recording {
+    pub screens: Vec<(Duration, String)>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: serde_json::Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err("The recording is empty".into()),
+        };
+        if header["version"] != 2 {
+            return Err("Only asciicast version 2 recordings can be played".into());
+        }
+        let mut screens = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (seconds, code, data): (f64, String, String) = serde_json::from_str(&line)?;
+            // Resizes and input are only of use to a terminal emulator.
+            if code == "o" {
+                screens.push((Duration::from_secs_f64(seconds.max(0.0)), data));
+            }
+        }
+        if screens.is_empty() {
+            return Err("The recording has no screens".into());
+        }
+        Ok(Self { screens })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.screens
+            .last()
+            .map(|(time, _)| *time)
+            .unwrap_or_default()
+    }
+
+    // The screen on display at a point in the recording.
+    pub fn screen_at(&self, position: Duration) -> &str {
+        let index = self
+            .screens
+            .partition_point(|(time, _)| *time <= position)
+            .saturating_sub(1);
+        &self.screens[index].1
+    }
+}
+
+// Where playback is in a recording and how fast it moves.
+pub struct Player {
+    pub position: Duration,
+    pub speed: f32,
+    pub paused: bool,
+    duration: Duration,
+}
+
+impl Player {
+    pub fn new(duration: Duration, speed: f32) -> Self {
+        Self {
+            position: Duration::ZERO,
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            paused: false,
+            duration,
+        }
+    }
+
+    pub fn advance(&mut self, elapsed: Duration) {
+        if !self.paused {
+            self.position = (self.position + elapsed.mul_f32(self.speed)).min(self.duration);
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position >= self.duration
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(MAX_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(MIN_SPEED);
+    }
+
+    pub fn seek_forward(&mut self) {
+        self.position = (self.position + SEEK_STEP).min(self.duration);
+    }
+
+    pub fn seek_back(&mut self) {
+        self.position = self.position.saturating_sub(SEEK_STEP);
+    }
+}
+
+// Turns recorded output back into styled lines. Only SGR sequences are
+// understood, which is all buffer_ansi writes besides cursor and clearing
+// sequences; those are skipped.
+pub fn ansi_text(output: &str) -> Text<'static> {
+    let mut lines = Vec::new();
+    for row in output.split("\r\n") {
+        let mut spans = Vec::new();
+        let mut style = Style::default();
+        let mut plain = String::new();
+        let mut chars = row.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\x1b' {
+                plain.push(c);
+                continue;
+            }
+            if chars.next_if_eq(&'[').is_none() {
+                continue;
+            }
+            let mut parameters = String::new();
+            let mut command = None;
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    command = Some(c);
+                    break;
+                }
+                parameters.push(c);
+            }
+            if command == Some('m') {
+                if !plain.is_empty() {
+                    spans.push(Span::styled(std::mem::take(&mut plain), style));
+                }
+                style = apply_sgr(style, &parameters);
+            }
+        }
+        if !plain.is_empty() {
+            spans.push(Span::styled(plain, style));
+        }
+        lines.push(Line::from(spans));
+    }
+    Text::from(lines)
+}
+
+fn apply_sgr(mut style: Style, parameters: &str) -> Style {
+    let mut codes = parameters
+        .split(';')
+        .map(|code| code.parse::<u8>().unwrap_or(0));
+    while let Some(code) = codes.next() {
+        style = match code {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            3 => style.add_modifier(Modifier::ITALIC),
+            4 => style.add_modifier(Modifier::UNDERLINED),
+            7 => style.add_modifier(Modifier::REVERSED),
+            30..=37 => style.fg(Color::Indexed(code - 30)),
+            90..=97 => style.fg(Color::Indexed(code - 90 + 8)),
+            40..=47 => style.bg(Color::Indexed(code - 40)),
+            100..=107 => style.bg(Color::Indexed(code - 100 + 8)),
+            39 => style.fg(Color::Reset),
+            49 => style.bg(Color::Reset),
+            38 | 48 => {
+                let color = match codes.next() {
+                    Some(5) => codes.next().map(Color::Indexed),
+                    Some(2) => match (codes.next(), codes.next(), codes.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(Color::Rgb(r, g, b)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match (code, color) {
+                    (38, Some(color)) => style.fg(color),
+                    (48, Some(color)) => style.bg(color),
+                    _ => style,
+                }
+            }
+            _ => style,
+        };
+    }
+    style
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::layout::Rect;
+
+    fn lines(out: &[u8]) -> Vec<serde_json::Value> {
+        std::str::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_screens_are_recorded_once() {
+        let mut out = Vec::new();
+        let mut recorder = Recorder::new(&mut out, 4, 2).unwrap();
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 4, 2));
+        buffer.set_string(0, 0, "hi", Style::default().fg(Color::Red));
+        recorder.frame(&buffer).unwrap();
+        recorder.frame(&buffer).unwrap();
+        buffer.set_string(0, 1, "yo", Style::default());
+        recorder.frame(&buffer).unwrap();
+        recorder.finish().unwrap();
+
+        let lines = lines(&out);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 4);
+        assert_eq!(lines[1][1], "o");
+        let screen = lines[2][2].as_str().unwrap();
+        assert!(screen.contains("hi") && screen.contains("\r\n") && screen.contains("yo"));
+        assert!(lines[2][0].as_f64().unwrap() >= lines[1][0].as_f64().unwrap());
+    }
+
+    #[test]
+    fn recorded_screens_read_back_with_their_styles() {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 3, 2));
+        buffer.set_string(0, 0, "ab", Style::default().fg(Color::Indexed(196)));
+        buffer.set_string(
+            0,
+            1,
+            "c",
+            Style::default()
+                .bg(Color::Rgb(1, 2, 3))
+                .add_modifier(Modifier::BOLD),
+        );
+        let text = ansi_text(&buffer_ansi(&buffer));
+        assert_eq!(text.lines.len(), 2);
+        let first = &text.lines[0].spans;
+        assert_eq!(first[0].content, "ab");
+        assert_eq!(first[0].style.fg, Some(Color::Indexed(196)));
+        let second = &text.lines[1].spans[0];
+        assert_eq!(second.content, "c");
+        assert_eq!(second.style.bg, Some(Color::Rgb(1, 2, 3)));
+        assert!(second.style.add_modifier.contains(Modifier::BOLD));
+    }
+
+    #[test]
+    fn playback_follows_the_speed_and_stops_at_the_end() {
+        let recording = Recording {
+            screens: vec![
+                (Duration::ZERO, "a".to_string()),
+                (Duration::from_secs(2), "b".to_string()),
+                (Duration::from_secs(4), "c".to_string()),
+            ],
+        };
+        let mut player = Player::new(recording.duration(), 1.0);
+        player.advance(Duration::from_millis(1500));
+        assert_eq!(recording.screen_at(player.position), "a");
+        player.faster();
+        player.advance(Duration::from_millis(500));
+        assert_eq!(recording.screen_at(player.position), "b");
+        player.paused = true;
+        player.advance(Duration::from_secs(10));
+        assert!(!player.finished());
+        player.paused = false;
+        player.advance(Duration::from_secs(10));
+        assert!(player.finished());
+        assert_eq!(recording.screen_at(player.position), "c");
+        player.seek_back();
+        assert_eq!(player.position, Duration::ZERO);
+    }
+
+    #[test]
+    fn resizes_are_recorded_before_the_screen() {
+        let mut out = Vec::new();
+        let mut recorder = Recorder::new(&mut out, 4, 2).unwrap();
+        recorder
+            .frame(&Buffer::empty(Rect::new(0, 0, 6, 3)))
+            .unwrap();
+        recorder.finish().unwrap();
+
+        let lines = lines(&out);
+        assert_eq!(lines[1][1], "r");
+        assert_eq!(lines[1][2], "6x3");
+        assert_eq!(lines[2][1], "o");
+    }
+}
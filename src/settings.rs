@@ -0,0 +1,215 @@
+use clap::ValueEnum;
+use std::io;
+use std::path::Path;
+
+use crate::config::{self, Config, MAX_FPS};
+use crate::theme::ThemeName;
+
+// Ramps the panel cycles through, darkest character first. The first is
+// the default.
+const RAMPS: &[&str] = &[
+    " .:-=+*#%@",
+    " .'`^\",:;Il!i><~+_-?][}{1)(|\\/tjfrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$",
+    " ░▒▓█",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Setting {
+    Fps,
+    AsciiRamp,
+    Invert,
+    Theme,
+    Speakers,
+}
+
+const SETTINGS: [Setting; 5] = [
+    Setting::Fps,
+    Setting::AsciiRamp,
+    Setting::Invert,
+    Setting::Theme,
+    Setting::Speakers,
+];
+
+impl Setting {
+    fn label(self) -> &'static str {
+        match self {
+            Setting::Fps => "Frame rate",
+            Setting::AsciiRamp => "ASCII ramp",
+            Setting::Invert => "Invert video",
+            Setting::Theme => "Theme",
+            Setting::Speakers => "Speakers",
+        }
+    }
+
+    // Where it's saved in the config file. The speakers are a per-call
+    // switch like the d key, so they aren't saved.
+    fn key(self) -> Option<&'static str> {
+        match self {
+            Setting::Fps => Some("fps"),
+            Setting::AsciiRamp => Some("ascii_ramp"),
+            Setting::Invert => Some("invert"),
+            Setting::Theme => Some("theme"),
+            Setting::Speakers => None,
+        }
+    }
+}
+
+// The overlay opened with ',': Up and Down pick a row, Left and Right (or
+// - and +) change it. Changes apply right away and are written to the
+// config file when the call ends.
+#[derive(Default)]
+pub struct Settings {
+    selected: usize,
+    changed: Vec<Setting>,
+}
+
+impl Settings {
+    pub fn select(&mut self, forward: bool) {
+        self.selected = if forward {
+            (self.selected + 1) % SETTINGS.len()
+        } else {
+            (self.selected + SETTINGS.len() - 1) % SETTINGS.len()
+        };
+    }
+
+    // Steps the selected setting in `config` and returns which one it was.
+    // The speakers live outside the config, so the caller flips those.
+    pub fn adjust(&mut self, config: &mut Config, forward: bool) -> Setting {
+        let setting = SETTINGS[self.selected];
+        match setting {
+            Setting::Fps => {
+                config.fps = if forward {
+                    (config.fps + 1).min(MAX_FPS)
+                } else {
+                    config.fps.saturating_sub(1).max(1)
+                };
+            }
+            Setting::AsciiRamp => {
+                // A ramp from the command line starts the cycle over.
+                let current = RAMPS.iter().position(|ramp| *ramp == config.ascii_ramp);
+                config.ascii_ramp = RAMPS[step(current, RAMPS.len(), forward)].to_string();
+            }
+            Setting::Invert => config.invert = !config.invert,
+            Setting::Theme => {
+                let themes = ThemeName::value_variants();
+                let current = themes.iter().position(|theme| *theme == config.theme);
+                config.theme = themes[step(current, themes.len(), forward)];
+            }
+            Setting::Speakers => {}
+        }
+        if !self.changed.contains(&setting) {
+            self.changed.push(setting);
+        }
+        setting
+    }
+
+    // "Label: value" per setting, and whether it's the selected one.
+    pub fn rows(&self, config: &Config, speakers_muted: bool) -> Vec<(String, bool)> {
+        SETTINGS
+            .iter()
+            .enumerate()
+            .map(|(i, setting)| {
+                let value = match setting {
+                    Setting::Fps => format!("{} fps", config.fps),
+                    Setting::AsciiRamp => format!("\"{}\"", config.ascii_ramp),
+                    Setting::Invert => on_off(config.invert).to_string(),
+                    Setting::Theme => theme_name(config.theme),
+                    Setting::Speakers => on_off(!speakers_muted).to_string(),
+                };
+                (
+                    format!("{}: {}", setting.label(), value),
+                    i == self.selected,
+                )
+            })
+            .collect()
+    }
+
+    // Writes whatever was changed to the config file, if anything was.
+    pub fn save(&self, path: &Path, config: &Config) -> io::Result<bool> {
+        let values: Vec<(&str, String)> = self
+            .changed
+            .iter()
+            .filter_map(|setting| {
+                let value = match setting {
+                    Setting::Fps => config.fps.to_string(),
+                    Setting::AsciiRamp => toml_string(&config.ascii_ramp),
+                    Setting::Invert => config.invert.to_string(),
+                    Setting::Theme => toml_string(&theme_name(config.theme)),
+                    Setting::Speakers => return None,
+                };
+                Some((setting.key()?, value))
+            })
+            .collect();
+        if values.is_empty() {
+            return Ok(false);
+        }
+        config::save_values(path, &values)?;
+        Ok(true)
+    }
+}
+
+// The next index around a list, from the start if there's no current one.
+fn step(current: Option<usize>, len: usize, forward: bool) -> usize {
+    match current {
+        None => 0,
+        Some(i) if forward => (i + 1) % len,
+        Some(i) => (i + len - 1) % len,
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+// As written in the config file and on the command line.
+fn theme_name(theme: ThemeName) -> String {
+    theme
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+// JSON string escapes are all valid in a TOML basic string.
+fn toml_string(text: &str) -> String {
+    serde_json::to_string(text).expect("strings always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_step_and_wrap() {
+        let mut settings = Settings::default();
+        let mut config = Config {
+            fps: MAX_FPS,
+            ..Config::default()
+        };
+        assert_eq!(settings.adjust(&mut config, true), Setting::Fps);
+        assert_eq!(config.fps, MAX_FPS);
+        settings.adjust(&mut config, false);
+        assert_eq!(config.fps, MAX_FPS - 1);
+
+        settings.select(true);
+        settings.adjust(&mut config, false);
+        assert_eq!(config.ascii_ramp, RAMPS[RAMPS.len() - 1]);
+
+        settings.select(true);
+        settings.select(true);
+        settings.adjust(&mut config, true);
+        assert_eq!(config.theme, ThemeName::Mono);
+        assert_eq!(
+            settings.rows(&config, false)[3],
+            ("Theme: mono".to_string(), true)
+        );
+
+        settings.select(false);
+        settings.select(false);
+        settings.select(false);
+        settings.select(false);
+        assert_eq!(settings.adjust(&mut config, true), Setting::Speakers);
+    }
+}
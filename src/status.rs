@@ -0,0 +1,52 @@
+// Our own switches, each shown as its own badge on the self view. Only the
+// mic and camera are ever sent to peers; the rest only change what we hear
+// and see.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalStatus {
+    pub mic_muted: bool,
+    pub speakers_muted: bool,
+    pub camera_off: bool,
+    pub remote_video_hidden: bool,
+}
+
+impl LocalStatus {
+    pub fn badges(&self) -> String {
+        status_badges(&[
+            (self.mic_muted, "mic off"),
+            (self.speakers_muted, "speakers off"),
+            (self.camera_off, "cam off"),
+            (self.remote_video_hidden, "remote video hidden"),
+        ])
+    }
+}
+
+// "[mic off] [cam off] " for whichever flags are set, or nothing.
+pub fn status_badges(flags: &[(bool, &str)]) -> String {
+    flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, badge)| format!("[{}] ", badge))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_local_switch_has_its_own_badge() {
+        assert_eq!(LocalStatus::default().badges(), "");
+        let status = LocalStatus {
+            mic_muted: true,
+            remote_video_hidden: true,
+            ..LocalStatus::default()
+        };
+        assert_eq!(status.badges(), "[mic off] [remote video hidden] ");
+        let status = LocalStatus {
+            speakers_muted: true,
+            camera_off: true,
+            ..LocalStatus::default()
+        };
+        assert_eq!(status.badges(), "[speakers off] [cam off] ");
+    }
+}
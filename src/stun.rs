@@ -0,0 +1,209 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// Just enough of STUN (RFC 5389) to ask a server which address our packets
+// come from, i.e. the public side of any NAT in between.
+const DEFAULT_PORT: u16 = 3478;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+// Requests go over UDP, so a lost one is asked again rather than waited on.
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+type TransactionId = [u8; 12];
+
+// Asks `server` (see server_address) for our address as it sees it.
+pub async fn external_address(server: &str) -> io::Result<SocketAddr> {
+    let server = server_address(server);
+    let server_addr = tokio::net::lookup_host(&server)
+        .await?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no address", server),
+            )
+        })?;
+    let local: SocketAddr = if server_addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server_addr).await?;
+
+    let transaction_id = transaction_id();
+    let request = binding_request(&transaction_id);
+    let mut buf = [0u8; 512];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request).await?;
+        if let Ok(received) = tokio::time::timeout(ATTEMPT_TIMEOUT, socket.recv(&mut buf)).await {
+            if let Some(addr) = parse_binding_response(&buf[..received?], &transaction_id) {
+                return Ok(addr);
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no answer from STUN server {}", server),
+    ))
+}
+
+// "host", "host:port", an IP address or "[ip]:port", with the default port
+// added where there's none. A bare IPv6 address is full of colons, so those
+// are tried as addresses before looking for a port.
+fn server_address(server: &str) -> String {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return addr.to_string();
+    }
+    let unbracketed = server.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return SocketAddr::new(ip, DEFAULT_PORT).to_string();
+    }
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+        _ => format!("{}:{}", server, DEFAULT_PORT),
+    }
+}
+
+// Only needs to be unpredictable enough not to match a stray packet.
+fn transaction_id() -> TransactionId {
+    let random = || RandomState::new().build_hasher().finish().to_be_bytes();
+    let mut id = [0u8; 12];
+    id[..8].copy_from_slice(&random());
+    id[8..].copy_from_slice(&random()[..4]);
+    id
+}
+
+fn binding_request(transaction_id: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut request = [0u8; HEADER_LEN];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // Bytes 2..4 are the attribute length, and there are none.
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction_id);
+    request
+}
+
+// The mapped address in a success response to our request. Servers that
+// predate RFC 5389 only send the plain MAPPED-ADDRESS.
+fn parse_binding_response(response: &[u8], transaction_id: &TransactionId) -> Option<SocketAddr> {
+    let header = response.get(..HEADER_LEN)?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_SUCCESS
+        || header[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || header[8..] != transaction_id[..]
+    {
+        return None;
+    }
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut attributes = response.get(HEADER_LEN..HEADER_LEN + length)?;
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = decode_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of four bytes.
+        attributes = attributes
+            .get((4 + len).next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    mapped
+}
+
+// With a transaction id the address is XOR-MAPPED: port and address are
+// XORed with the magic cookie, followed by the id for IPv6.
+fn decode_address(value: &[u8], xor_with: Option<&TransactionId>) -> Option<SocketAddr> {
+    let mut mask = [0u8; 16];
+    if let Some(transaction_id) = xor_with {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    let unmask = |bytes: &[u8]| -> Vec<u8> { bytes.iter().zip(mask).map(|(b, m)| b ^ m).collect() };
+    let port = unmask(value.get(2..4)?);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = match *value.get(1)? {
+        FAMILY_IPV4 => {
+            let octets: [u8; 4] = unmask(value.get(4..8)?).try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        FAMILY_IPV6 => {
+            let octets: [u8; 16] = unmask(value.get(4..20)?).try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A success response carrying one attribute.
+    fn response(transaction_id: &TransactionId, kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(transaction_id);
+        response.extend_from_slice(&kind.to_be_bytes());
+        response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        response.extend_from_slice(value);
+        response
+    }
+
+    #[test]
+    fn mapped_addresses_are_decoded() {
+        let transaction_id = [7u8; 12];
+        let request = binding_request(&transaction_id);
+        assert_eq!(request[..2], BINDING_REQUEST.to_be_bytes());
+        assert_eq!(request[8..], transaction_id);
+
+        // 192.0.2.1:32853, XORed with the cookie as RFC 5769 does.
+        let xored = [0x00, FAMILY_IPV4, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let expected: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let reply = response(&transaction_id, XOR_MAPPED_ADDRESS, &xored);
+        assert_eq!(
+            parse_binding_response(&reply, &transaction_id),
+            Some(expected)
+        );
+
+        let plain = [0x00, FAMILY_IPV4, 0x80, 0x55, 192, 0, 2, 1];
+        let reply = response(&transaction_id, MAPPED_ADDRESS, &plain);
+        assert_eq!(
+            parse_binding_response(&reply, &transaction_id),
+            Some(expected)
+        );
+
+        // Someone else's answer.
+        assert_eq!(parse_binding_response(&reply, &[8u8; 12]), None);
+    }
+
+    #[test]
+    fn server_addresses_get_the_default_port() {
+        assert_eq!(server_address("stun.example.org"), "stun.example.org:3478");
+        assert_eq!(
+            server_address("stun.example.org:19302"),
+            "stun.example.org:19302"
+        );
+        assert_eq!(server_address("192.0.2.1"), "192.0.2.1:3478");
+        assert_eq!(server_address("192.0.2.1:19302"), "192.0.2.1:19302");
+        assert_eq!(server_address("2001:db8::1"), "[2001:db8::1]:3478");
+        assert_eq!(server_address("[2001:db8::1]"), "[2001:db8::1]:3478");
+        assert_eq!(server_address("[2001:db8::1]:19302"), "[2001:db8::1]:19302");
+    }
+}
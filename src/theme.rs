@@ -0,0 +1,265 @@
+use crate::video;
+use image::Rgb;
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+
+#[derive(Deserialize, clap::ValueEnum, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    #[default]
+    Default,
+    // No colors at all, for monochrome terminals and colorblind users.
+    Mono,
+    HighContrast,
+}
+
+// Every style the TUI draws with. A new theme is one more constructor here.
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub heading: Style,   // app title and status screen text
+    pub border: Style,    // status screen and prompt borders
+    pub text: Style,      // plain labels
+    pub highlight: Style, // our peer id, flash messages
+    pub hint: Style,      // footers and other secondary text
+    pub selected: Style,  // selected list rows
+    pub speaking: Style,  // border of a tile whose peer is talking
+    pub meter: Style,     // audio level gauges
+    pub rtt_good: Style,
+    pub rtt_fair: Style,
+    pub rtt_poor: Style,
+}
+
+impl ThemeName {
+    pub fn theme(self) -> Theme {
+        match self {
+            ThemeName::Default => Theme {
+                heading: Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+                border: Style::default().fg(Color::Yellow),
+                text: Style::default().fg(Color::White),
+                highlight: Style::default()
+                    .fg(Color::Green)
+                    .add_modifier(Modifier::BOLD),
+                hint: Style::default().fg(Color::Gray),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+                speaking: Style::default().fg(Color::Green),
+                meter: Style::default().fg(Color::Green),
+                rtt_good: Style::default().fg(Color::Green),
+                rtt_fair: Style::default().fg(Color::Yellow),
+                rtt_poor: Style::default().fg(Color::Red),
+            },
+            // Emphasis through weight and reversal only; ping times are
+            // still shown as numbers.
+            ThemeName::Mono => Theme {
+                heading: Style::default().add_modifier(Modifier::BOLD),
+                border: Style::default(),
+                text: Style::default(),
+                highlight: Style::default().add_modifier(Modifier::BOLD),
+                hint: Style::default().add_modifier(Modifier::DIM),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+                speaking: Style::default().add_modifier(Modifier::BOLD),
+                meter: Style::default(),
+                rtt_good: Style::default(),
+                rtt_fair: Style::default(),
+                rtt_poor: Style::default().add_modifier(Modifier::BOLD),
+            },
+            // Bright colors and bold everywhere, nothing dimmed.
+            ThemeName::HighContrast => Theme {
+                heading: Style::default()
+                    .fg(Color::LightYellow)
+                    .add_modifier(Modifier::BOLD),
+                border: Style::default()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+                text: Style::default()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+                highlight: Style::default()
+                    .fg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+                hint: Style::default().fg(Color::White),
+                selected: Style::default()
+                    .fg(Color::Black)
+                    .bg(Color::LightYellow)
+                    .add_modifier(Modifier::BOLD),
+                speaking: Style::default()
+                    .fg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+                meter: Style::default().fg(Color::LightGreen),
+                rtt_good: Style::default()
+                    .fg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+                rtt_fair: Style::default()
+                    .fg(Color::LightYellow)
+                    .add_modifier(Modifier::BOLD),
+                rtt_poor: Style::default()
+                    .fg(Color::LightRed)
+                    .add_modifier(Modifier::BOLD),
+            },
+        }
+    }
+}
+
+// A color given by name ("light-blue"), hex ("#1e1e2e") or 256-color index.
+pub fn parse_color(name: &str) -> Result<Color, String> {
+    name.parse::<Color>().map_err(|_| {
+        format!(
+            "Unknown color '{}': use a name like red or light-blue, #rrggbb, or 0-255",
+            name
+        )
+    })
+}
+
+// How many colors the terminal can show, fewest first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Mono,
+    // The 16 basic colors, which every theme sticks to.
+    Basic,
+    // The xterm-256 palette, for video drawn in color.
+    #[default]
+    Palette256,
+    // Any #rrggbb color.
+    TrueColor,
+}
+
+// Going by the NO_COLOR convention, TERM and COLORTERM. Terminals that only
+// name themselves "xterm" often have the full palette too, but nothing says
+// so, so only the 16 basic colors are assumed.
+pub fn terminal_color_depth() -> ColorDepth {
+    color_depth(
+        std::env::var("TERM").ok().as_deref(),
+        std::env::var_os("COLORTERM").is_some_and(|value| !value.is_empty()),
+        std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()),
+    )
+}
+
+// Whether the terminal can show colors at all.
+pub fn terminal_has_color() -> bool {
+    terminal_color_depth() != ColorDepth::Mono
+}
+
+fn color_depth(term: Option<&str>, colorterm: bool, no_color: bool) -> ColorDepth {
+    match term {
+        _ if no_color => ColorDepth::Mono,
+        Some("") | Some("dumb") => ColorDepth::Mono,
+        // Windows consoles don't set TERM but have colors all the same.
+        None if cfg!(windows) => ColorDepth::Palette256,
+        None => ColorDepth::Mono,
+        // COLORTERM is set by terminals with 24-bit color.
+        _ if colorterm => ColorDepth::TrueColor,
+        Some(term) if term.ends_with("-direct") => ColorDepth::TrueColor,
+        Some(term) if term.contains("256color") => ColorDepth::Palette256,
+        Some(_) => ColorDepth::Basic,
+    }
+}
+
+// The nearest color the terminal can show. Named colors are among the 16
+// basic ones, so they always fit.
+pub fn fit_color(color: Color, color_depth: ColorDepth) -> Color {
+    match (color, color_depth) {
+        (Color::Rgb(r, g, b), ColorDepth::Basic) => basic_color(video::xterm_index(Rgb([r, g, b]))),
+        (Color::Rgb(r, g, b), ColorDepth::Mono | ColorDepth::Palette256) => {
+            Color::Indexed(video::xterm_index(Rgb([r, g, b])))
+        }
+        (Color::Indexed(index), ColorDepth::Mono | ColorDepth::Basic) => basic_color(index),
+        _ => color,
+    }
+}
+
+// One of the 16 basic colors by name. Indexed colors are sent as 256-color
+// codes even below 16, which terminals with only the basic colors ignore.
+pub fn basic_color(index: u8) -> Color {
+    const NAMED: [Color; 16] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::Gray,
+        Color::DarkGray,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+        Color::White,
+    ];
+    NAMED[video::basic_color(index) as usize]
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        ThemeName::default().theme()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_parse_by_name_hex_and_index() {
+        assert_eq!(parse_color("light-blue"), Ok(Color::LightBlue));
+        assert_eq!(parse_color("#1e1e2e"), Ok(Color::Rgb(0x1e, 0x1e, 0x2e)));
+        assert_eq!(parse_color("208"), Ok(Color::Indexed(208)));
+        assert!(parse_color("sparkly").is_err());
+    }
+
+    #[test]
+    fn dumb_terminals_and_no_color_mean_no_colors() {
+        assert_eq!(
+            color_depth(Some("xterm-256color"), false, true),
+            ColorDepth::Mono
+        );
+        assert_eq!(color_depth(Some("dumb"), true, false), ColorDepth::Mono);
+        let windows = if cfg!(windows) {
+            ColorDepth::Palette256
+        } else {
+            ColorDepth::Mono
+        };
+        assert_eq!(color_depth(None, false, false), windows);
+    }
+
+    #[test]
+    fn the_256_color_palette_needs_the_terminal_to_say_so() {
+        assert_eq!(color_depth(Some("linux"), false, false), ColorDepth::Basic);
+        assert_eq!(color_depth(Some("xterm"), false, false), ColorDepth::Basic);
+        assert_eq!(
+            color_depth(Some("xterm-256color"), false, false),
+            ColorDepth::Palette256
+        );
+        assert_eq!(
+            color_depth(Some("xterm"), true, false),
+            ColorDepth::TrueColor
+        );
+        assert_eq!(
+            color_depth(Some("xterm-direct"), false, false),
+            ColorDepth::TrueColor
+        );
+    }
+
+    #[test]
+    fn colors_are_brought_down_to_what_the_terminal_shows() {
+        let orange = Color::Rgb(0xff, 0x87, 0x00);
+        assert_eq!(fit_color(orange, ColorDepth::TrueColor), orange);
+        assert_eq!(
+            fit_color(orange, ColorDepth::Palette256),
+            Color::Indexed(208)
+        );
+        assert_eq!(fit_color(orange, ColorDepth::Basic), Color::Yellow);
+        assert_eq!(
+            fit_color(Color::Indexed(208), ColorDepth::Palette256),
+            Color::Indexed(208)
+        );
+        assert_eq!(
+            fit_color(Color::Indexed(21), ColorDepth::Basic),
+            Color::Blue
+        );
+        assert_eq!(fit_color(Color::Cyan, ColorDepth::Basic), Color::Cyan);
+    }
+}
@@ -1,248 +1,1325 @@
-use crate::p2p::FrameData;
+use crate::audio::{self, LevelMeter};
+use crate::config::LayoutMode;
+use crate::p2p::{self, FrameData, FrameUpdate};
+use crate::qr::QrCode;
+use crate::record::Recorder;
+use crate::status::{status_badges, LocalStatus};
+use crate::theme::{self, ColorDepth, Theme};
+use crate::util::{format_size, short_peer_id};
+use crate::video;
 use crossterm::{
+    cursor,
     event::{DisableMouseCapture, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use libp2p::Multiaddr;
+use libp2p::{multiaddr::Protocol, Multiaddr};
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{
+        block::{Position, Title},
+        Block, Borders, Clear, Gauge, List, ListItem, Paragraph, Wrap,
+    },
 };
 use std::{
-    collections::HashMap,
-    io::{self, Stdout},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Stdout},
+    net::IpAddr,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
-#[derive(Clone, Debug)]
+// Whether the alternate screen was entered, so restoring the terminal only
+// leaves it if so. Global because the panic hook has no Tui to ask.
+static ALT_SCREEN: AtomicBool = AtomicBool::new(false);
+
+const FLASH_DURATION: Duration = Duration::from_secs(2);
+// RMS above which a peer counts as talking, and how long the highlight
+// lingers after they go quiet.
+const SPEAKING_THRESHOLD: f32 = 0.02;
+const SPEAKING_HOLD: Duration = Duration::from_millis(500);
+const REACTION_DURATION: Duration = Duration::from_secs(2);
+// How long someone has to be the loudest talker before the speaker layout
+// moves the focus to them, so two people talking over each other don't
+// make it flap.
+const SPEAKER_SWITCH_AFTER: Duration = Duration::from_millis(1500);
+// Round trips up to these are shown green and yellow, anything slower red.
+const GOOD_RTT: Duration = Duration::from_millis(100);
+const FAIR_RTT: Duration = Duration::from_millis(300);
+// Share of a peer's video frames that never arrived before its link counts
+// as fair or poor, and how many frames the share is measured over.
+const FAIR_LOSS: f32 = 0.02;
+const POOR_LOSS: f32 = 0.1;
+const LOSS_WINDOW: u32 = 200;
+// Sent messages kept for recall with Up and Down.
+const MAX_INPUT_HISTORY: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum FileDownloadState {
     Downloading,
-    Completed(String), // path
-    Failed,
+    Uploading(u64, u64), // bytes published so far, of the total
+    Completed(String),   // path
+    Failed(String),      // reason
 }
 
+// A file on its way in, or with `sent`, one of ours on its way out.
 #[derive(Clone, Debug)]
 pub struct FileDownload {
+    pub id: u64, // stays the same wherever the download ends up in the list
     pub file_name: String,
     pub peer_id: String,
+    pub sent: bool,
     pub state: FileDownloadState,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkQuality {
+    Good,
+    Fair,
+    Poor,
+    Reconnecting, // heartbeats stopped arriving
+}
+
+impl LinkQuality {
+    fn label(self) -> &'static str {
+        match self {
+            LinkQuality::Good => "good",
+            LinkQuality::Fair => "fair",
+            LinkQuality::Poor => "poor",
+            LinkQuality::Reconnecting => "reconnecting",
+        }
+    }
+
+    fn style(self, theme: &Theme) -> Style {
+        match self {
+            LinkQuality::Good => theme.rtt_good,
+            LinkQuality::Fair => theme.rtt_fair,
+            LinkQuality::Poor | LinkQuality::Reconnecting => theme.rtt_poor,
+        }
+    }
+}
+
+// What we know about the connection to one peer, boiled down to a
+// LinkQuality for display.
+#[derive(Clone, Debug, Default)]
+struct PeerLink {
+    rtt: Option<Duration>, // latest ping round trip
+    relayed: bool,         // only reached through other peers' gossip
+    missed_heartbeats: u32,
+    frames_received: u32,
+    frames_lost: u32,
+}
+
+impl PeerLink {
+    fn loss(&self) -> f32 {
+        let total = self.frames_received + self.frames_lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.frames_lost as f32 / total as f32
+        }
+    }
+
+    fn on_frames(&mut self, received: u32, lost: u32) {
+        self.frames_received += received;
+        self.frames_lost += lost;
+        // Halve the counts now and then so old losses fade out.
+        if self.frames_received + self.frames_lost > LOSS_WINDOW {
+            self.frames_received /= 2;
+            self.frames_lost /= 2;
+        }
+    }
+
+    fn quality(&self) -> LinkQuality {
+        let loss = self.loss();
+        let rtt = self.rtt.unwrap_or_default();
+        if self.missed_heartbeats > 0 {
+            LinkQuality::Reconnecting
+        } else if rtt > FAIR_RTT || loss >= POOR_LOSS {
+            LinkQuality::Poor
+        } else if rtt > GOOD_RTT || loss >= FAIR_LOSS || self.relayed {
+            LinkQuality::Fair
+        } else {
+            LinkQuality::Good
+        }
+    }
+
+    // "fair 140ms, relayed, 3% loss"
+    fn summary(&self) -> String {
+        let mut parts = vec![self.quality().label().to_string()];
+        if let Some(rtt) = self.rtt {
+            parts[0] = format!("{} {}ms", parts[0], rtt.as_millis());
+        }
+        if self.relayed {
+            parts.push("relayed".to_string());
+        }
+        if self.loss() >= FAIR_LOSS {
+            parts.push(format!("{:.0}% loss", self.loss() * 100.0));
+        }
+        parts.join(", ")
+    }
+}
+
+// Picks who the speaker layout focuses on.
+#[derive(Default)]
+struct SpeakerFollow {
+    candidate: Option<(String, Instant)>, // loudest talker and since when
+}
+
+impl SpeakerFollow {
+    // The peer to focus instead of `focused`, if it's time to switch. With
+    // nobody focused yet the first talker is taken right away.
+    fn next(
+        &mut self,
+        loudest: Option<&str>,
+        focused: Option<&str>,
+        now: Instant,
+    ) -> Option<String> {
+        let Some(loudest) = loudest.filter(|loudest| Some(*loudest) != focused) else {
+            self.candidate = None;
+            return None;
+        };
+        let since = match &self.candidate {
+            Some((candidate, since)) if candidate == loudest => *since,
+            _ => {
+                self.candidate = Some((loudest.to_string(), now));
+                now
+            }
+        };
+        if focused.is_none() || now.saturating_duration_since(since) >= SPEAKER_SWITCH_AFTER {
+            self.candidate = None;
+            Some(loudest.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+// Something a mouse click can act on in the call view.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClickTarget {
+    Peer(String),
+    Input,
+    Download(u64), // by id
+}
+
+struct ChatLine {
+    text: String,
+    own_id: Option<u64>, // set on our own messages, for read receipts
+    seen: bool,
+}
+
 type Terminal = ratatui::Terminal<CrosstermBackend<Stdout>>;
 
 pub struct Tui {
     terminal: Terminal,
     remote_frames: HashMap<String, (String, bool, bool)>,
+    pending_deltas: HashMap<String, Vec<Vec<(u16, char)>>>,
+    last_frame_seq: HashMap<String, u64>,
+    awaiting_keyframe: HashSet<String>, // a delta failed to apply, so deltas wait for a keyframe
     listen_addresses: Vec<Multiaddr>,
-    pub messages: Vec<String>,
-    pub downloads: Vec<FileDownload>,
+    pub external_ip: Option<IpAddr>, // from --stun-server
+    flash_message: Option<(String, Instant)>,
+    self_audio_level: LevelMeter,
+    peer_audio_levels: HashMap<String, LevelMeter>,
+    peer_last_spoke: HashMap<String, Instant>,
+    focused_peer: Option<String>,
+    focus_locked: bool, // the speaker layout leaves the focus to the user
+    speaker_follow: SpeakerFollow,
+    self_reaction: Option<(String, Instant)>,
+    peer_reactions: HashMap<String, (String, Instant)>,
+    raised_hands: HashSet<String>,
+    peer_links: HashMap<String, PeerLink>,
+    ignored_peers: HashSet<String>, // kept across reconnects
+    call_start: Option<Instant>,
+    shown_call_secs: u64,
+    pub hand_raised: bool,
+    pub chat_only: bool, // no video panes, the chat column takes the whole screen
+    pub show_self: bool, // draw our own tile; we keep sending video either way
+    pub video_drop_rate: f32, // share of our frames held back by --max-uplink-kbps
+    pub bandwidth: Option<p2p::BandwidthStats>, // with --metrics
+    pub theme: Theme,
+    pub layout: LayoutMode,
+    pub video_style: Style,           // --video-fg and --video-bg
+    pub color_depth: ColorDepth,      // what halfblock video can be drawn in
+    pub admit_prompt: Option<String>, // peer waiting for the host's y/n
+    pub settings_panel: Option<Vec<(String, bool)>>, // rows of the ',' overlay
+    pub max_peers: Option<usize>,     // the host's --max-peers
+    selected_peer: Option<String>,
+    self_view_size: Option<(u16, u16)>, // room for our frame at the last draw
+    selected_download: Option<u64>,     // by id, so it follows the download around
+    click_areas: Vec<(Rect, ClickTarget)>, // from the last draw
+    snapshot: Option<Option<String>>,   // requested, then taken by the next call draw
+    recorder: Option<Recorder<BufWriter<File>>>, // with --record
+    messages: VecDeque<ChatLine>,
+    pub chat_history: usize, // most messages kept
+    downloads: Vec<FileDownload>,
+    next_download_id: u64,
     pub input: String,
+    input_history: VecDeque<String>, // newest first
+    history_index: Option<usize>,    // into input_history while browsing it
+    draft: String,                   // what was typed before browsing began
     pub input_mode: bool,
+    restore_on_drop: bool,
 }
 
 impl Tui {
-    pub fn new() -> io::Result<Self> {
+    // Without the alternate screen the UI is drawn over the normal one, which
+    // keeps the scrollback around for debugging.
+    pub fn new(alt_screen: bool) -> io::Result<Self> {
         let mut stdout = io::stdout();
         enable_raw_mode()?;
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        if alt_screen {
+            execute!(stdout, EnterAlternateScreen)?;
+            ALT_SCREEN.store(true, Ordering::SeqCst);
+        }
+        execute!(stdout, EnableMouseCapture)?;
         let backend = CrosstermBackend::new(stdout);
         let terminal = ratatui::Terminal::new(backend)?;
-        Ok(Self {
+        Ok(Self::with_terminal(terminal, true))
+    }
+
+    // Drawn into a fixed area and leaving the terminal alone, for tests.
+    #[cfg(test)]
+    fn headless() -> Self {
+        let options = ratatui::TerminalOptions {
+            viewport: Viewport::Fixed(Rect::new(0, 0, 120, 40)),
+        };
+        let terminal =
+            ratatui::Terminal::with_options(CrosstermBackend::new(io::stdout()), options)
+                .expect("a fixed viewport doesn't query the terminal");
+        Self::with_terminal(terminal, false)
+    }
+
+    fn with_terminal(terminal: Terminal, restore_on_drop: bool) -> Self {
+        Self {
             terminal,
             remote_frames: HashMap::new(),
+            pending_deltas: HashMap::new(),
+            last_frame_seq: HashMap::new(),
+            awaiting_keyframe: HashSet::new(),
             listen_addresses: Vec::new(),
-            messages: Vec::new(),
+            external_ip: None,
+            flash_message: None,
+            self_audio_level: LevelMeter::default(),
+            peer_audio_levels: HashMap::new(),
+            peer_last_spoke: HashMap::new(),
+            focused_peer: None,
+            focus_locked: false,
+            speaker_follow: SpeakerFollow::default(),
+            self_reaction: None,
+            peer_reactions: HashMap::new(),
+            raised_hands: HashSet::new(),
+            peer_links: HashMap::new(),
+            ignored_peers: HashSet::new(),
+            call_start: None,
+            shown_call_secs: 0,
+            hand_raised: false,
+            chat_only: false,
+            show_self: true,
+            video_drop_rate: 0.0,
+            bandwidth: None,
+            theme: Theme::default(),
+            layout: LayoutMode::default(),
+            video_style: Style::default(),
+            color_depth: ColorDepth::default(),
+            admit_prompt: None,
+            settings_panel: None,
+            max_peers: None,
+            selected_peer: None,
+            self_view_size: None,
+            selected_download: None,
+            click_areas: Vec::new(),
+            snapshot: None,
+            recorder: None,
+            messages: VecDeque::new(),
+            chat_history: usize::MAX,
             downloads: Vec::new(),
+            next_download_id: 0,
             input: String::new(),
+            input_history: VecDeque::new(),
+            history_index: None,
+            draft: String::new(),
             input_mode: false,
-        })
+            restore_on_drop,
+        }
     }
 
     pub fn add_listen_address(&mut self, addr: Multiaddr) {
         self.listen_addresses.push(addr);
     }
 
-    pub fn update_frame(&mut self, frame_data: FrameData) {
-        self.remote_frames.insert(
-            frame_data.peer_id,
-            (
-                frame_data.frame,
-                frame_data.is_audio_muted,
-                frame_data.is_video_muted,
-            ),
-        );
+    pub fn best_listen_address(&self) -> Option<Multiaddr> {
+        p2p::best_listen_address(&self.listen_addresses).cloned()
     }
 
-    pub fn draw(
-        &mut self,
-        self_frame: &str,
-        is_audio_muted: bool,
-        is_video_muted: bool,
-    ) -> io::Result<()> {
+    // Shows a short-lived confirmation in the status screens' footer, or
+    // in the input pane's title during a call.
+    pub fn flash(&mut self, message: &str) {
+        self.flash_message = Some((message.to_string(), Instant::now()));
+    }
+
+    // Returns true when a flash message just timed out and needs a redraw.
+    pub fn expire_flash(&mut self) -> bool {
+        match &self.flash_message {
+            Some((_, shown_at)) if shown_at.elapsed() >= FLASH_DURATION => {
+                self.flash_message = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn add_self_reaction(&mut self, emoji: &str) {
+        self.self_reaction = Some((emoji.to_string(), Instant::now()));
+    }
+
+    pub fn add_peer_reaction(&mut self, peer_id: &str, emoji: &str) {
+        self.peer_reactions
+            .insert(peer_id.to_string(), (emoji.to_string(), Instant::now()));
+    }
+
+    // Starts the call timer unless it's already running, so later
+    // connections don't reset it.
+    pub fn start_call_timer(&mut self) {
+        self.call_start.get_or_insert_with(Instant::now);
+    }
+
+    // Returns true if it was running.
+    pub fn stop_call_timer(&mut self) -> bool {
+        self.call_start.take().is_some()
+    }
+
+    // Returns true once per second while the call timer runs, when the
+    // displayed time changes.
+    pub fn call_timer_changed(&mut self) -> bool {
+        let Some(call_start) = self.call_start else {
+            return false;
+        };
+        let secs = call_start.elapsed().as_secs();
+        let changed = secs != self.shown_call_secs;
+        self.shown_call_secs = secs;
+        changed
+    }
+
+    // Moves the participant list selection through the peers in display order.
+    pub fn select_peer(&mut self, forward: bool) {
+        let mut peers: Vec<&String> = self.remote_frames.keys().collect();
+        peers.sort();
+        if peers.is_empty() {
+            self.selected_peer = None;
+            return;
+        }
+        let current = self
+            .selected_peer
+            .as_ref()
+            .and_then(|selected| peers.iter().position(|peer| *peer == selected));
+        let next = match (current, forward) {
+            (None, _) => 0,
+            (Some(i), true) => (i + 1) % peers.len(),
+            (Some(i), false) => (i + peers.len() - 1) % peers.len(),
+        };
+        self.selected_peer = Some(peers[next].clone());
+    }
+
+    pub fn selected_peer(&self) -> Option<&str> {
+        self.selected_peer.as_deref()
+    }
+
+    // Adds a line to the chat pane, dropping the oldest past chat_history.
+    pub fn push_message(&mut self, message: String) {
+        self.push_line(ChatLine {
+            text: message,
+            own_id: None,
+            seen: false,
+        });
+    }
+
+    // Our own chat message, which can later be marked as seen.
+    pub fn push_own_message(&mut self, message: String, message_id: u64) {
+        self.push_line(ChatLine {
+            text: message,
+            own_id: Some(message_id),
+            seen: false,
+        });
+    }
+
+    // Keeps a sent message for Up and Down. Blank messages aren't kept, nor
+    // is a repeat of the last one; commands never get here.
+    pub fn remember_input(&mut self, text: &str) {
+        if text.trim().is_empty() || self.input_history.front().is_some_and(|last| last == text) {
+            return;
+        }
+        self.input_history.push_front(text.to_string());
+        self.input_history.truncate(MAX_INPUT_HISTORY);
+    }
+
+    // Steps back to an older message, putting aside what was being typed.
+    pub fn history_up(&mut self) {
+        let index = self.history_index.map_or(0, |index| index + 1);
+        let Some(text) = self.input_history.get(index) else {
+            return;
+        };
+        if self.history_index.is_none() {
+            self.draft = std::mem::take(&mut self.input);
+        }
+        self.input = text.clone();
+        self.history_index = Some(index);
+    }
+
+    // Steps forward again, back to what was being typed past the newest.
+    pub fn history_down(&mut self) {
+        match self.history_index {
+            None => {}
+            Some(0) => {
+                self.input = std::mem::take(&mut self.draft);
+                self.history_index = None;
+            }
+            Some(index) => {
+                self.input = self.input_history[index - 1].clone();
+                self.history_index = Some(index - 1);
+            }
+        }
+    }
+
+    pub fn reset_history_cursor(&mut self) {
+        self.history_index = None;
+        self.draft.clear();
+    }
+
+    fn push_line(&mut self, line: ChatLine) {
+        self.messages.push_back(line);
+        while self.messages.len() > self.chat_history {
+            self.messages.pop_front();
+        }
+    }
+
+    // Returns true if this changed what's shown.
+    pub fn mark_seen(&mut self, message_id: u64) -> bool {
+        match self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|line| line.own_id == Some(message_id))
+        {
+            Some(line) if !line.seen => {
+                line.seen = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Returns the id that status updates for this download refer to.
+    pub fn add_download(&mut self, file_name: &str, peer_id: &str) -> u64 {
+        let id = self.next_download_id;
+        self.next_download_id += 1;
+        self.downloads.push(FileDownload {
+            id,
+            file_name: file_name.to_string(),
+            peer_id: peer_id.to_string(),
+            sent: false,
+            state: FileDownloadState::Downloading,
+        });
+        id
+    }
+
+    pub fn add_upload(&mut self, file_name: &str, size: u64) -> u64 {
+        let id = self.next_download_id;
+        self.next_download_id += 1;
+        self.downloads.push(FileDownload {
+            id,
+            file_name: file_name.to_string(),
+            peer_id: String::new(),
+            sent: true,
+            state: FileDownloadState::Uploading(0, size),
+        });
+        id
+    }
+
+    // Marks an upload sent in full, or failed with the publish error.
+    pub fn finish_upload<E: fmt::Display>(&mut self, id: u64, result: &Result<(), E>) {
+        let Some(FileDownload {
+            state: FileDownloadState::Uploading(_, size),
+            ..
+        }) = self.downloads.iter().find(|download| download.id == id)
+        else {
+            return;
+        };
+        let state = match result {
+            Ok(()) => FileDownloadState::Uploading(*size, *size),
+            Err(e) => FileDownloadState::Failed(e.to_string()),
+        };
+        self.update_download(id, state);
+    }
+
+    // Returns false if there's no such download (any more).
+    pub fn update_download(&mut self, id: u64, state: FileDownloadState) -> bool {
+        match self.downloads.iter_mut().find(|download| download.id == id) {
+            Some(download) => {
+                download.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Moves the downloads pane selection, wrapping around at either end.
+    pub fn select_download(&mut self, forward: bool) {
+        let count = self.downloads.len();
+        if count == 0 {
+            return;
+        }
+        let current = self
+            .selected_download
+            .and_then(|id| self.downloads.iter().position(|download| download.id == id));
+        let next = match (current, forward) {
+            (None, true) => 0,
+            (None, false) => count - 1,
+            (Some(i), true) => (i + 1) % count,
+            (Some(i), false) => (i + count - 1) % count,
+        };
+        self.selected_download = Some(self.downloads[next].id);
+    }
+
+    pub fn select_download_by_id(&mut self, id: u64) {
+        if self.downloads.iter().any(|download| download.id == id) {
+            self.selected_download = Some(id);
+        }
+    }
+
+    pub fn selected_download(&self) -> Option<&FileDownload> {
+        let id = self.selected_download?;
+        self.downloads.iter().find(|download| download.id == id)
+    }
+
+    // Forgets everything shown for a peer that was removed from the call.
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.remote_frames.remove(peer_id);
+        self.pending_deltas.remove(peer_id);
+        self.last_frame_seq.remove(peer_id);
+        self.awaiting_keyframe.remove(peer_id);
+        self.peer_audio_levels.remove(peer_id);
+        self.peer_last_spoke.remove(peer_id);
+        self.peer_reactions.remove(peer_id);
+        self.raised_hands.remove(peer_id);
+        self.peer_links.remove(peer_id);
+        if self.selected_peer.as_deref() == Some(peer_id) {
+            self.selected_peer = None;
+        }
+        if self.focused_peer.as_deref() == Some(peer_id) {
+            self.focused_peer = None;
+        }
+    }
+
+    pub fn set_peer_rtt(&mut self, peer_id: &str, rtt: Duration) {
+        self.peer_links.entry(peer_id.to_string()).or_default().rtt = Some(rtt);
+    }
+
+    // Asks `probe` whether each peer in the call is relayed and how many
+    // heartbeats it has missed. Returns true if any link's badge changed.
+    pub fn refresh_links(&mut self, mut probe: impl FnMut(&str) -> Option<(bool, u32)>) -> bool {
+        let mut changed = false;
+        for peer_id in self.remote_frames.keys() {
+            let Some((relayed, missed_heartbeats)) = probe(peer_id) else {
+                continue;
+            };
+            let link = self.peer_links.entry(peer_id.clone()).or_default();
+            let before = link.summary();
+            link.relayed = relayed;
+            link.missed_heartbeats = missed_heartbeats;
+            changed |= link.summary() != before;
+        }
+        changed
+    }
+
+    // An ignored peer keeps their last frame, but stops looking like they're
+    // talking.
+    pub fn set_peer_ignored(&mut self, peer_id: &str, ignored: bool) {
+        if ignored {
+            self.ignored_peers.insert(peer_id.to_string());
+            self.peer_audio_levels.remove(peer_id);
+            self.peer_last_spoke.remove(peer_id);
+            self.peer_reactions.remove(peer_id);
+        } else {
+            self.ignored_peers.remove(peer_id);
+        }
+    }
+
+    // True with no peers too, as there's no video to show either way.
+    pub fn all_peers_video_muted(&self) -> bool {
+        self.remote_frames
+            .values()
+            .all(|(_, _, is_video_muted)| *is_video_muted)
+    }
+
+    pub fn set_peer_hand(&mut self, peer_id: &str, raised: bool) {
+        if raised {
+            self.raised_hands.insert(peer_id.to_string());
+        } else {
+            self.raised_hands.remove(peer_id);
+        }
+    }
+
+    // Only peers we already have a tile for; the next frame carries the
+    // state for anyone else.
+    pub fn set_peer_mute(&mut self, peer_id: &str, is_audio_muted: bool, is_video_muted: bool) {
+        if let Some((_, audio_muted, video_muted)) = self.remote_frames.get_mut(peer_id) {
+            *audio_muted = is_audio_muted;
+            *video_muted = is_video_muted;
+        }
+    }
+
+    // Returns true when a reaction just timed out and needs a redraw.
+    pub fn expire_reactions(&mut self) -> bool {
+        let live = |(_, shown_at): &(String, Instant)| shown_at.elapsed() < REACTION_DURATION;
+        let before = self.peer_reactions.len() + self.self_reaction.is_some() as usize;
+        self.peer_reactions.retain(|_, reaction| live(reaction));
+        self.self_reaction = self.self_reaction.take().filter(live);
+        before != self.peer_reactions.len() + self.self_reaction.is_some() as usize
+    }
+
+    // Returns false if the frame is older than one already shown for that peer,
+    // or is a delta that doesn't follow on from it. Peers that predate sequence
+    // numbers send 0 and are always accepted.
+    //
+    // Deltas are only queued here and applied when the tile is drawn, so
+    // peers whose tiles aren't visible cost nothing but the bookkeeping.
+    pub fn update_frame(&mut self, frame_data: FrameData) -> bool {
+        let last_seq = self.last_frame_seq.get(&frame_data.peer_id).copied();
+        if let Some(last_seq) = last_seq {
+            if frame_data.seq != 0 && frame_data.seq <= last_seq {
+                return false;
+            }
+        }
+        let FrameData {
+            peer_id,
+            seq,
+            frame,
+            is_audio_muted,
+            is_video_muted,
+        } = frame_data;
+        match frame {
+            FrameUpdate::Key(frame) => {
+                self.pending_deltas.remove(&peer_id);
+                self.awaiting_keyframe.remove(&peer_id);
+                self.remote_frames
+                    .insert(peer_id.clone(), (frame, is_audio_muted, is_video_muted));
+            }
+            FrameUpdate::Delta(changes) => {
+                // A lost delta would leave us drawing garbage, so wait for the next keyframe.
+                if last_seq.map(|last| last + 1) != Some(seq)
+                    || self.awaiting_keyframe.contains(&peer_id)
+                {
+                    return false;
+                }
+                let Some((_, audio_muted, video_muted)) = self.remote_frames.get_mut(&peer_id)
+                else {
+                    return false;
+                };
+                *audio_muted = is_audio_muted;
+                *video_muted = is_video_muted;
+                self.pending_deltas
+                    .entry(peer_id.clone())
+                    .or_default()
+                    .push(changes);
+            }
+        }
+        // Gaps in the sequence are frames lost on the way.
+        let lost = match last_seq {
+            Some(last) if seq != 0 => seq.saturating_sub(last + 1).min(LOSS_WINDOW as u64) as u32,
+            _ => 0,
+        };
+        self.peer_links
+            .entry(peer_id.clone())
+            .or_default()
+            .on_frames(1, lost);
+        self.last_frame_seq.insert(peer_id, seq);
+        true
+    }
+
+    pub fn update_self_audio_level(&mut self, samples: &[f32]) {
+        self.self_audio_level.update(samples);
+    }
+
+    pub fn update_peer_audio_level(&mut self, peer_id: &str, samples: &[f32]) {
+        self.peer_audio_levels
+            .entry(peer_id.to_string())
+            .or_default()
+            .update(samples);
+        if audio::rms(samples) >= SPEAKING_THRESHOLD {
+            self.peer_last_spoke
+                .insert(peer_id.to_string(), Instant::now());
+        }
+    }
+
+    fn is_speaking(peer_last_spoke: &HashMap<String, Instant>, peer_id: &str) -> bool {
+        peer_last_spoke
+            .get(peer_id)
+            .is_some_and(|spoke_at| spoke_at.elapsed() < SPEAKING_HOLD)
+    }
+
+    // Returns true while any meter still shows a level, i.e. needs redrawing.
+    pub fn decay_audio_levels(&mut self) -> bool {
+        let mut active = !self.self_audio_level.is_idle();
+        self.self_audio_level.decay();
+        for meter in self.peer_audio_levels.values_mut() {
+            active |= !meter.is_idle();
+            meter.decay();
+        }
+        active
+    }
+
+    // Room for our frame at the last draw. None when there was no self view,
+    // e.g. in chat-only mode.
+    pub fn self_view_size(&self) -> Option<(u16, u16)> {
+        self.self_view_size
+    }
+
+    // Focuses a peer, or goes back to the grid if it already was.
+    // Choosing a tile by hand stops the speaker layout from moving it.
+    pub fn toggle_focus(&mut self, peer_id: &str) {
+        self.focus_locked = true;
+        if self.focused_peer.as_deref() == Some(peer_id) {
+            self.focused_peer = None;
+        } else {
+            self.focused_peer = Some(peer_id.to_string());
+        }
+    }
+
+    // What was drawn at a screen position in the last call view.
+    pub fn click_target(&self, column: u16, row: u16) -> Option<ClickTarget> {
+        self.click_areas
+            .iter()
+            .find(|(area, _)| {
+                (area.left()..area.right()).contains(&column)
+                    && (area.top()..area.bottom()).contains(&row)
+            })
+            .map(|(_, target)| target.clone())
+    }
+
+    // Steps the focus through the peers in a stable order, then back to the grid.
+    pub fn cycle_focus(&mut self) {
+        self.focus_locked = true;
+        let mut peers: Vec<&String> = self.remote_frames.keys().collect();
+        peers.sort();
+        let next = match &self.focused_peer {
+            None => peers.first(),
+            Some(current) => peers
+                .iter()
+                .position(|peer| *peer == current)
+                .and_then(|i| peers.get(i + 1)),
+        };
+        self.focused_peer = next.map(|peer| peer.to_string());
+    }
+
+    // Returns whether the focus is now locked, or None outside the speaker
+    // layout.
+    pub fn toggle_focus_lock(&mut self) -> Option<bool> {
+        if self.layout != LayoutMode::Speaker {
+            return None;
+        }
+        self.focus_locked = !self.focus_locked;
+        Some(self.focus_locked)
+    }
+
+    // Moves the focus to the loudest talker in the speaker layout. Returns
+    // true if it moved.
+    pub fn follow_speaker(&mut self, now: Instant) -> bool {
+        if self.layout != LayoutMode::Speaker || self.focus_locked || self.chat_only {
+            return false;
+        }
+        let loudest = self
+            .peer_audio_levels
+            .iter()
+            .filter(|(peer_id, _)| {
+                self.remote_frames.contains_key(*peer_id)
+                    && !self.ignored_peers.contains(*peer_id)
+                    && Self::is_speaking(&self.peer_last_spoke, peer_id)
+            })
+            .max_by(|(_, a), (_, b)| a.level().total_cmp(&b.level()))
+            .map(|(peer_id, _)| peer_id.as_str());
+        match self
+            .speaker_follow
+            .next(loudest, self.focused_peer.as_deref(), now)
+        {
+            Some(peer_id) => {
+                self.focused_peer = Some(peer_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn draw(&mut self, self_frame: &str, status: LocalStatus) -> io::Result<()> {
         let Tui {
             terminal,
             remote_frames,
+            pending_deltas,
+            awaiting_keyframe,
+            self_audio_level,
+            peer_audio_levels,
+            peer_last_spoke,
+            focused_peer,
+            focus_locked,
+            self_reaction,
+            peer_reactions,
+            raised_hands,
+            peer_links,
+            ignored_peers,
+            call_start,
+            hand_raised,
+            chat_only,
+            show_self,
+            video_drop_rate,
+            bandwidth,
+            theme,
+            layout,
+            video_style,
+            color_depth,
+            admit_prompt,
+            settings_panel,
+            max_peers,
+            selected_peer,
+            self_view_size,
+            selected_download,
+            click_areas,
             messages,
             downloads,
             input,
             input_mode,
+            flash_message,
+            snapshot,
+            recorder,
             ..
         } = self;
-        terminal.draw(|f| {
+        *self_view_size = None;
+        click_areas.clear();
+        let completed = terminal.draw(|f| {
+            let video_share = if *chat_only { 0 } else { 70 };
             let chunks = Layout::default()
                 .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+                .constraints(
+                    [
+                        Constraint::Percentage(video_share),
+                        Constraint::Percentage(100 - video_share),
+                    ]
+                    .as_ref(),
+                )
                 .split(f.size());
 
-            let video_chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-                .split(chunks[0]);
-
-            let audio_status = if is_audio_muted { " (Muted)" } else { "" };
-            let video_status = if is_video_muted { " (Video Off)" } else { "" };
-            let title = format!(
-                "My View (q: quit, i: chat, m: mute audio{}, v: mute video{}, f: send file)",
-                audio_status, video_status
-            );
+            let mut peers: Vec<String> = remote_frames.keys().cloned().collect();
+            peers.sort();
+            // Only worth mentioning once it's noticeable.
+            let uplink_status = if *video_drop_rate >= 0.01 {
+                format!(" (dropping {:.0}% of video)", *video_drop_rate * 100.0)
+            } else {
+                String::new()
+            };
+            let mut participant_items = vec![ListItem::new(format!(
+                "{}You{}",
+                hand_marker(*hand_raised),
+                uplink_status
+            ))];
+            participant_items.extend(peers.iter().map(|peer_id| {
+                let mut line = vec![Span::raw(format!(
+                    "{}{}",
+                    hand_marker(raised_hands.contains(peer_id)),
+                    short_peer_id(peer_id)
+                ))];
+                if let Some(link) = peer_links.get(peer_id) {
+                    line.push(Span::styled(
+                        format!(" {}", link.summary()),
+                        link.quality().style(theme),
+                    ));
+                }
+                if ignored_peers.contains(peer_id) {
+                    line.push(Span::styled(" (ignored)", theme.hint));
+                }
+                let item = ListItem::new(Line::from(line));
+                if selected_peer.as_ref() == Some(peer_id) {
+                    item.style(theme.selected)
+                } else {
+                    item
+                }
+            }));
 
-            let self_view = Paragraph::new(self_frame)
-                .block(Block::default().title(title).borders(Borders::ALL));
-            f.render_widget(self_view, video_chunks[0]);
-
-            if !remote_frames.is_empty() {
-                let (remote_frame_text, is_audio_muted, is_video_muted) =
-                    remote_frames.values().next().unwrap().clone();
-                let remote_peer_id = remote_frames.keys().next().unwrap().clone();
-
-                let audio_status = if is_audio_muted { " (Muted)" } else { "" };
-                let video_status = if is_video_muted { " (Video Off)" } else { "" };
-                let title = format!(
-                    "Peer: {} (Audio: {}{}, Video: {}{})",
-                    remote_peer_id,
-                    if is_audio_muted { "Off" } else { "On" },
-                    audio_status,
-                    if is_video_muted { "Off" } else { "On" },
-                    video_status
-                );
+            // Lay the tiles out first so only the visible ones get built.
+            // Only honour a focus on a peer we still have frames for.
+            let focused = focused_peer
+                .as_ref()
+                .filter(|peer| !*chat_only && remote_frames.contains_key(*peer));
+            let mut slots = Vec::new();
+            if *show_self {
+                slots.push(TileSlot::SelfView);
+            }
+            slots.extend(
+                peers
+                    .iter()
+                    .filter(|peer| Some(*peer) != focused)
+                    .map(|peer| TileSlot::Peer(peer.clone())),
+            );
+            if *chat_only {
+                slots.clear();
+            } else if peers.is_empty() {
+                slots.push(TileSlot::Waiting);
+            }
+            let mut areas = match focused {
+                // Presenter layout: the focused peer on top, everyone else in a strip.
+                Some(focused) if slots.is_empty() => {
+                    slots.push(TileSlot::Peer(focused.clone()));
+                    vec![chunks[0]]
+                }
+                Some(focused) => {
+                    let rows = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Percentage(75), Constraint::Percentage(25)].as_ref())
+                        .split(chunks[0]);
+                    let strip = Layout::default()
+                        .direction(Direction::Horizontal)
+                        .constraints(vec![Constraint::Ratio(1, slots.len() as u32); slots.len()])
+                        .split(rows[1]);
+                    slots.insert(0, TileSlot::Peer(focused.clone()));
+                    std::iter::once(rows[0]).chain(strip.iter().copied()).collect()
+                }
+                None => grid(chunks[0], slots.len()),
+            };
+            areas.truncate(slots.len());
 
-                let remote_view = Paragraph::new(remote_frame_text)
-                    .block(Block::default().title(title).borders(Borders::ALL));
-                f.render_widget(remote_view, video_chunks[1]);
-            } else {
-                let remote_view = Paragraph::new("Waiting for remote frame...")
-                    .block(Block::default().title("Remote View").borders(Borders::ALL));
-                f.render_widget(remote_view, video_chunks[1]);
+            for (slot, area) in slots.iter().zip(areas) {
+                let visible = area.width > 2 && area.height > 3;
+                let tile = match slot {
+                    TileSlot::SelfView => {
+                        *self_view_size = Some(frame_size(area));
+                        Tile {
+                            title: format!(
+                                "{}My View {}(q: quit, i: chat, m: mic, d: speakers, v: camera, r: remote video, s: hide self, f/F: send file/folder, p: paste image, S: snapshot, ,: settings, tab: focus, 1-5: react, h: raise hand)",
+                                hand_marker(*hand_raised),
+                                status.badges()
+                            ),
+                            frame: if visible { self_frame.to_string() } else { String::new() },
+                            level: Some(self_audio_level.clone()),
+                            speaking: false,
+                            reaction: self_reaction.as_ref().map(|(emoji, _)| emoji.clone()),
+                        }
+                    }
+                    TileSlot::Peer(peer_id) => {
+                        click_areas.push((area, ClickTarget::Peer(peer_id.clone())));
+                        if visible {
+                            apply_pending_deltas(
+                                remote_frames,
+                                pending_deltas,
+                                awaiting_keyframe,
+                                peer_id,
+                            );
+                        }
+                        let (frame, is_audio_muted, is_video_muted) = &remote_frames[peer_id];
+                        let link = peer_links
+                            .get(peer_id)
+                            .map(|link| format!("[{} link]", link.quality().label()))
+                            .unwrap_or_default();
+                        Tile {
+                            title: format!(
+                                "{}Peer: {} {}{}",
+                                hand_marker(raised_hands.contains(peer_id)),
+                                peer_id,
+                                status_badges(&[(*is_audio_muted, "mic off"), (*is_video_muted, "cam off")]),
+                                link
+                            )
+                            .trim_end()
+                            .to_string(),
+                            // Muted peers stop sending frames, so draw our own placeholder.
+                            frame: if !visible {
+                                String::new()
+                            } else if *is_video_muted {
+                                "Video Off".to_string()
+                            } else if status.remote_video_hidden {
+                                "Video hidden (r: show)".to_string()
+                            } else {
+                                frame.clone()
+                            },
+                            level: Some(peer_audio_levels.get(peer_id).cloned().unwrap_or_default()),
+                            speaking: Self::is_speaking(peer_last_spoke, peer_id),
+                            reaction: peer_reactions.get(peer_id).map(|(emoji, _)| emoji.clone()),
+                        }
+                    }
+                    TileSlot::Waiting => Tile {
+                        title: "Remote View".to_string(),
+                        frame: "Waiting for remote frame...".to_string(),
+                        level: None,
+                        speaking: false,
+                        reaction: None,
+                    },
+                };
+                render_tile(f, area, &tile, theme, *video_style, *color_depth);
             }
 
             let right_chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(
                     [
-                        Constraint::Percentage(50),
-                        Constraint::Percentage(40),
+                        Constraint::Length(participant_items.len().min(6) as u16 + 2),
+                        Constraint::Min(3),
+                        Constraint::Percentage(35),
                         Constraint::Length(3),
                     ]
                     .as_ref(),
                 )
                 .split(chunks[1]);
 
-            let message_items: Vec<ListItem> =
-                messages.iter().map(|m| ListItem::new(m.as_str())).collect();
+            let mut participants_title = match max_peers {
+                Some(max_peers) => format!("Participants {}/{}", peers.len(), max_peers),
+                None => "Participants".to_string(),
+            };
+            // Shown whether or not anyone has the focus yet, so the l key
+            // always has visible feedback.
+            match (*layout, *focus_locked) {
+                (LayoutMode::Speaker, false) => participants_title.push_str(" [follows speaker, l: lock]"),
+                (LayoutMode::Speaker, true) => participants_title.push_str(" [locked, l: unlock]"),
+                _ => {}
+            }
+            let mut participant_block = Block::default()
+                .borders(Borders::ALL)
+                .title(participants_title);
+            if let Some(call_start) = call_start {
+                participant_block = participant_block.title(
+                    Title::from(format_duration(call_start.elapsed())).alignment(Alignment::Right),
+                );
+            }
+            if let Some(stats) = bandwidth {
+                participant_block = participant_block.title(
+                    Title::from(format!(
+                        "\u{2193} {}/s ({})  \u{2191} {}/s ({})",
+                        format_size(stats.inbound_per_sec),
+                        format_size(stats.total_inbound),
+                        format_size(stats.outbound_per_sec),
+                        format_size(stats.total_outbound)
+                    ))
+                    .position(Position::Bottom),
+                );
+            }
+            let participant_list = List::new(participant_items).block(participant_block);
+            f.render_widget(participant_list, right_chunks[0]);
+
+            // Wrapped to the pane, and only as many of the newest messages as
+            // fit, counting the lines they wrap to.
+            let chat_width = right_chunks[1].width.saturating_sub(2) as usize;
+            let mut chat_lines_left = right_chunks[1].height.saturating_sub(2) as usize;
+            let mut message_items: Vec<ListItem> = messages
+                .iter()
+                .rev()
+                .map(|line| {
+                    let text = if line.seen {
+                        format!("{} \u{2713} seen", line.text)
+                    } else {
+                        line.text.clone()
+                    };
+                    wrap_chat_line(&text, chat_width)
+                })
+                .enumerate()
+                .take_while(|(i, wrapped)| {
+                    // The list drops a message that doesn't fit whole, unless
+                    // it's the newest and taller than the pane.
+                    let fits = *i == 0 || wrapped.len() <= chat_lines_left;
+                    chat_lines_left = chat_lines_left.saturating_sub(wrapped.len());
+                    fits
+                })
+                .map(|(_, wrapped)| wrapped)
+                .map(|wrapped| ListItem::new(wrapped.into_iter().map(Line::from).collect::<Vec<_>>()))
+                .collect();
+            message_items.reverse();
+            // The key help normally lives on the self view's title.
+            let chat_title = if *chat_only {
+                "Chat (q: quit, i: chat, f/F: send file/folder, p: paste image, S: snapshot, ,: settings, 1-5: react, h: raise hand)"
+            } else if !*show_self {
+                "Chat (q: quit, i: chat, m: mic, d: speakers, v: camera, r: remote video, s: show self, f/F: send file/folder, p: paste image, S: snapshot, ,: settings, tab: focus, 1-5: react, h: raise hand)"
+            } else {
+                "Chat"
+            };
             let message_list = List::new(message_items)
-                .block(Block::default().borders(Borders::ALL).title("Chat"));
-            f.render_widget(message_list, right_chunks[0]);
+                .block(Block::default().borders(Borders::ALL).title(chat_title));
+            f.render_widget(message_list, right_chunks[1]);
 
             let download_items: Vec<ListItem> = downloads
                 .iter()
                 .map(|d| {
                     let state_str = match &d.state {
                         FileDownloadState::Downloading => "Downloading...",
+                        FileDownloadState::Uploading(done, total) if done == total => "Sent",
+                        FileDownloadState::Uploading(done, total) => &format!(
+                            "Sending... {} of {}",
+                            format_size(*done),
+                            format_size(*total)
+                        ),
                         FileDownloadState::Completed(path) => &format!("Done -> {}", path),
-                        FileDownloadState::Failed => "Failed!",
+                        FileDownloadState::Failed(reason) => &format!("Failed: {}", reason),
+                    };
+                    let line = if d.sent {
+                        format!("{} to everyone: {}", d.file_name, state_str)
+                    } else {
+                        format!("{} from {}: {}", d.file_name, d.peer_id, state_str)
                     };
-                    let line = format!("{} from {}: {}", d.file_name, d.peer_id, state_str);
-                    ListItem::new(line)
+                    if *selected_download == Some(d.id) {
+                        ListItem::new(line).style(theme.selected)
+                    } else {
+                        ListItem::new(line)
+                    }
                 })
                 .collect();
             let download_list = List::new(download_items).block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("File Downloads"),
+                    .title("File Transfers ([/]: select, o: open)"),
             );
-            f.render_widget(download_list, right_chunks[1]);
+            f.render_widget(download_list, right_chunks[2]);
+            // One row per download inside the border, as far as they fit.
+            let download_rows = right_chunks[2].inner(&Margin::new(1, 1));
+            for (download, y) in downloads.iter().zip(download_rows.y..download_rows.bottom()) {
+                let row = Rect::new(download_rows.x, y, download_rows.width, 1);
+                click_areas.push((row, ClickTarget::Download(download.id)));
+            }
 
-            let input_paragraph = Paragraph::new(input.as_str()).block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Input (Enter to send, Esc to exit)"),
-            );
-            f.render_widget(input_paragraph, right_chunks[2]);
+            let input_title = match flash_message {
+                Some((message, _)) => Span::styled(message.as_str(), theme.highlight),
+                None => Span::raw("Input (Enter to send, Up/Down for history, Esc to exit)"),
+            };
+            let input_paragraph = Paragraph::new(input.as_str())
+                .block(Block::default().borders(Borders::ALL).title(input_title));
+            f.render_widget(input_paragraph, right_chunks[3]);
+            click_areas.push((right_chunks[3], ClickTarget::Input));
 
             if *input_mode {
                 f.set_cursor(
-                    right_chunks[2].x + input.len() as u16 + 1,
-                    right_chunks[2].y + 1,
+                    right_chunks[3].x + input.len() as u16 + 1,
+                    right_chunks[3].y + 1,
                 );
             }
+
+            if let Some(rows) = settings_panel {
+                render_settings_panel(f, rows, theme);
+            }
+            if let Some(peer_id) = admit_prompt {
+                render_admit_prompt(f, peer_id);
+            }
         })?;
+        if let Some(taken @ None) = snapshot {
+            *taken = Some(buffer_text(completed.buffer));
+        }
+        record_frame(recorder, completed.buffer);
         Ok(())
     }
 
+    // Every screen drawn from now on is appended to an asciicast file.
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        let size = self.terminal.size()?;
+        self.recorder = Some(Recorder::create(path, size.width, size.height)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // The next call view drawn is kept as text for take_snapshot.
+    pub fn request_snapshot(&mut self) {
+        self.snapshot = Some(None);
+    }
+
+    pub fn take_snapshot(&mut self) -> Option<String> {
+        match self.snapshot.take()? {
+            Some(text) => Some(text),
+            pending => {
+                self.snapshot = Some(pending);
+                None
+            }
+        }
+    }
+
     pub fn draw_waiting_for_peers(&mut self, local_peer_id: &str) -> io::Result<()> {
         let Tui {
             terminal,
             listen_addresses,
+            external_ip,
+            flash_message,
+            admit_prompt,
+            settings_panel,
+            theme,
+            recorder,
             ..
         } = self;
-        let listen_addresses_items: Vec<ListItem> = listen_addresses
-            .iter()
-            .map(|addr| ListItem::new(Span::raw(addr.to_string())))
-            .collect();
+        let mut sorted_addresses: Vec<&Multiaddr> = listen_addresses.iter().collect();
+        sorted_addresses.sort_by_key(|addr| ip_family(addr));
+        let external_addresses = external_ip
+            .map(|ip| p2p::external_addresses(listen_addresses, ip))
+            .unwrap_or_default();
+        let reachability = external_ip.map(|ip| {
+            if p2p::is_publicly_reachable(listen_addresses, ip) {
+                ListItem::new(Span::styled("Reachability: public", theme.highlight))
+            } else {
+                ListItem::new(Span::styled(
+                    "Reachability: private, behind a NAT (forward the port for peers outside your network)",
+                    theme.hint,
+                ))
+            }
+        });
+        let listen_addresses_items: Vec<ListItem> =
+            reachability
+                .into_iter()
+                .chain(external_addresses.iter().map(|addr| {
+                    ListItem::new(Span::styled(format!("External: {}", addr), theme.highlight))
+                }))
+                .chain(sorted_addresses.into_iter().map(|addr| {
+                    ListItem::new(Span::raw(format!("[{}] {}", ip_family(addr), addr)))
+                }))
+                .collect();
+        let qr_code = p2p::best_listen_address(listen_addresses)
+            .and_then(|addr| QrCode::encode(addr.to_string().as_bytes()));
 
-        terminal.draw(|f| {
+        let completed = terminal.draw(|f| {
             let size = f.size();
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(
                     [
-                        Constraint::Percentage(30),
-                        Constraint::Percentage(40),
-                        Constraint::Percentage(30),
+                        Constraint::Length(3),
+                        Constraint::Min(0),
+                        Constraint::Length(3),
                     ]
                     .as_ref(),
                 )
                 .split(size);
 
-            let title = Paragraph::new(Text::styled(
-                "Rust Meet",
-                Style::default()
-                    .fg(Color::Yellow)
-                    .add_modifier(Modifier::BOLD),
-            ))
-            .alignment(Alignment::Center);
+            let title = Paragraph::new(Text::styled("Rust Meet", theme.heading))
+                .alignment(Alignment::Center);
             f.render_widget(title, chunks[0]);
 
+            let qr_width = qr_code.as_ref().map_or(0, |qr| qr.rendered_width() as u16);
+            let body_chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(0), Constraint::Length(qr_width)].as_ref())
+                .margin(1)
+                .split(chunks[1]);
+
             let inner_chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints([Constraint::Length(3), Constraint::Min(5)].as_ref())
-                .margin(1)
-                .split(chunks[1]);
+                .split(body_chunks[0]);
 
             let block = Block::default()
                 .title("Waiting for Peers")
                 .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::Yellow));
+                .border_style(theme.border);
             f.render_widget(block.clone(), chunks[1]);
 
             let peer_id_text = Text::from(vec![Line::from(vec![
-                Span::styled("Your Peer ID: ", Style::default().fg(Color::White)),
-                Span::styled(
-                    local_peer_id,
-                    Style::default()
-                        .fg(Color::Green)
-                        .add_modifier(Modifier::BOLD),
-                ),
+                Span::styled("Your Peer ID: ", theme.text),
+                Span::styled(local_peer_id, theme.highlight),
             ])]);
             let peer_id_paragraph = Paragraph::new(peer_id_text).alignment(Alignment::Center);
             f.render_widget(peer_id_paragraph, inner_chunks[0]);
@@ -253,24 +1330,67 @@ impl Tui {
                         .title("Listening on")
                         .borders(Borders::NONE),
                 )
-                .style(Style::default().fg(Color::White))
+                .style(theme.text)
                 .highlight_style(Style::default().add_modifier(Modifier::ITALIC))
                 .highlight_symbol(">> ");
             f.render_widget(listen_list, inner_chunks[1]);
 
-            let footer = Paragraph::new(Text::styled(
-                "Users can join using your Peer ID.",
-                Style::default().fg(Color::Gray),
-            ))
-            .alignment(Alignment::Center);
+            if let Some(qr) = &qr_code {
+                let qr_area = body_chunks[1];
+                let qr_height = qr.rendered_height() as u16;
+                if qr_area.height > qr_height {
+                    let qr_chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints(
+                            [Constraint::Length(1), Constraint::Length(qr_height)].as_ref(),
+                        )
+                        .split(qr_area);
+                    let caption = Paragraph::new("Scan to join").alignment(Alignment::Center);
+                    f.render_widget(caption, qr_chunks[0]);
+                    let lines: Vec<Line> =
+                        qr.to_half_blocks().into_iter().map(Line::from).collect();
+                    // Light modules are the drawn glyphs, so pin the colors
+                    // rather than inheriting the terminal theme.
+                    let qr_paragraph = Paragraph::new(lines)
+                        .style(Style::default().fg(Color::White).bg(Color::Black));
+                    f.render_widget(qr_paragraph, qr_chunks[1]);
+                } else {
+                    let hint = Paragraph::new("Enlarge the terminal to show a QR code.")
+                        .style(theme.hint)
+                        .wrap(Wrap { trim: true });
+                    f.render_widget(hint, qr_area);
+                }
+            }
+
+            let footer_text = match flash_message {
+                Some((message, _)) => Text::styled(message.as_str(), theme.highlight),
+                None => Text::styled(
+                    "Users can join using your address. Press 'c' to copy it.",
+                    theme.hint,
+                ),
+            };
+            let footer = Paragraph::new(footer_text).alignment(Alignment::Center);
             f.render_widget(footer, chunks[2]);
+
+            if let Some(rows) = settings_panel {
+                render_settings_panel(f, rows, theme);
+            }
+            if let Some(peer_id) = admit_prompt {
+                render_admit_prompt(f, peer_id);
+            }
         })?;
+        record_frame(recorder, completed.buffer);
         Ok(())
     }
 
-    pub fn draw_joining(&mut self) -> io::Result<()> {
-        let Tui { terminal, .. } = self;
-        terminal.draw(|f| {
+    pub fn draw_joining(&mut self, status: &str) -> io::Result<()> {
+        let Tui {
+            terminal,
+            theme,
+            recorder,
+            ..
+        } = self;
+        let completed = terminal.draw(|f| {
             let size = f.size();
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
@@ -287,37 +1407,677 @@ impl Tui {
             let text = Text::from(vec![
                 Line::from(""),
                 Line::from("Joining room..."),
-                Line::from(""),
-                Line::from("Press 'q' to quit (Timeout in 10 seconds)"),
+                Line::from(status),
+                Line::from("Press 'q' to quit"),
                 Line::from(""),
             ]);
             let paragraph = Paragraph::new(text)
-                .style(
-                    Style::default()
-                        .fg(Color::Yellow)
-                        .add_modifier(Modifier::BOLD),
-                )
+                .style(theme.heading)
                 .alignment(Alignment::Center)
                 .block(
                     Block::default()
                         .borders(Borders::ALL)
-                        .border_style(Style::default().fg(Color::Yellow)),
+                        .border_style(theme.border),
                 );
             f.render_widget(paragraph, chunks[1]);
         })?;
+        record_frame(recorder, completed.buffer);
+        Ok(())
+    }
+
+    // A recorded screen with a status line under it.
+    pub fn draw_playback(&mut self, screen: Text, status: &str) -> io::Result<()> {
+        let Tui {
+            terminal, theme, ..
+        } = self;
+        terminal.draw(|f| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+                .split(f.size());
+            f.render_widget(Paragraph::new(screen), chunks[0]);
+            f.render_widget(Paragraph::new(Text::styled(status, theme.hint)), chunks[1]);
+        })?;
+        Ok(())
+    }
+
+    pub fn draw_audio_test(&mut self, meter: &LevelMeter) -> io::Result<()> {
+        let Tui {
+            terminal, theme, ..
+        } = self;
+        terminal.draw(|f| {
+            let size = f.size();
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Percentage(35),
+                        Constraint::Length(9),
+                        Constraint::Percentage(35),
+                    ]
+                    .as_ref(),
+                )
+                .split(size);
+
+            let block = Block::default()
+                .title("Audio Test")
+                .borders(Borders::ALL)
+                .border_style(theme.border);
+            f.render_widget(block, chunks[1]);
+
+            let inner_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Length(2),
+                        Constraint::Length(3),
+                        Constraint::Length(2),
+                    ]
+                    .as_ref(),
+                )
+                .margin(1)
+                .split(chunks[1]);
+
+            let help = Paragraph::new("Speak into your microphone, you should hear yourself.")
+                .alignment(Alignment::Center);
+            f.render_widget(help, inner_chunks[0]);
+
+            let gauge = level_gauge(meter, theme)
+                .block(Block::default().title("Input Level").borders(Borders::ALL));
+            f.render_widget(gauge, inner_chunks[1]);
+
+            let footer = Paragraph::new(Text::styled("Press 'q' to quit", theme.hint))
+                .alignment(Alignment::Center);
+            f.render_widget(footer, inner_chunks[2]);
+        })?;
         Ok(())
     }
 }
 
+fn ip_family(addr: &Multiaddr) -> &'static str {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(_)) => "IPv4",
+        Some(Protocol::Ip6(_)) => "IPv6",
+        _ => "Other",
+    }
+}
+
+// HH:MM:SS
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn hand_marker(raised: bool) -> &'static str {
+    if raised {
+        "✋ "
+    } else {
+        ""
+    }
+}
+
+// Centered y/n box shown over whichever screen is up.
+fn render_admit_prompt(f: &mut Frame, peer_id: &str) {
+    let text = format!("Admit {}? (y/n)", peer_id);
+    let width = (text.len() as u16 + 4).min(f.size().width);
+    let area = Rect::new(
+        f.size().x + (f.size().width - width) / 2,
+        f.size().y + f.size().height.saturating_sub(3) / 2,
+        width,
+        3.min(f.size().height),
+    );
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text)
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL).title("Waiting room")),
+        area,
+    );
+}
+
+fn render_settings_panel(f: &mut Frame, rows: &[(String, bool)], theme: &Theme) {
+    let hint = "↑/↓: pick, ←/→: change, esc: close";
+    let widest = rows
+        .iter()
+        .map(|(row, _)| row.chars().count())
+        .max()
+        .unwrap_or(0);
+    let width = (widest.max(hint.chars().count()) as u16 + 4).min(f.size().width);
+    let height = (rows.len() as u16 + 3).min(f.size().height);
+    let area = Rect::new(
+        f.size().x + (f.size().width - width) / 2,
+        f.size().y + (f.size().height - height) / 2,
+        width,
+        height,
+    );
+    let mut lines: Vec<Line> = rows
+        .iter()
+        .map(|(row, selected)| {
+            if *selected {
+                Line::styled(format!("> {}", row), theme.selected)
+            } else {
+                Line::raw(format!("  {}", row))
+            }
+        })
+        .collect();
+    lines.push(Line::styled(hint, theme.hint));
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Settings")),
+        area,
+    );
+}
+
+enum TileSlot {
+    SelfView,
+    Peer(String),
+    Waiting,
+}
+
+// Brings a peer's stored frame up to date with the deltas queued for it.
+// If one doesn't fit, the frame stays as it was and the peer's deltas are
+// refused until a keyframe arrives.
+fn apply_pending_deltas(
+    remote_frames: &mut HashMap<String, (String, bool, bool)>,
+    pending_deltas: &mut HashMap<String, Vec<Vec<(u16, char)>>>,
+    awaiting_keyframe: &mut HashSet<String>,
+    peer_id: &str,
+) {
+    let (Some(deltas), Some((frame, _, _))) = (
+        pending_deltas.remove(peer_id),
+        remote_frames.get_mut(peer_id),
+    ) else {
+        return;
+    };
+    let mut updated = frame.clone();
+    for changes in deltas {
+        match FrameUpdate::Delta(changes).apply(Some(&updated)) {
+            Some(next) => updated = next,
+            None => {
+                awaiting_keyframe.insert(peer_id.to_string());
+                return;
+            }
+        }
+    }
+    *frame = updated;
+}
+
+struct Tile {
+    title: String,
+    frame: String,
+    level: Option<LevelMeter>,
+    speaking: bool,
+    reaction: Option<String>,
+}
+
+fn render_tile(
+    f: &mut Frame,
+    area: Rect,
+    tile: &Tile,
+    theme: &Theme,
+    video_style: Style,
+    color_depth: ColorDepth,
+) {
+    let border_style = if tile.speaking {
+        theme.speaking
+    } else {
+        Style::default()
+    };
+    let block = Block::default()
+        .title(tile.title.as_str())
+        .borders(Borders::ALL)
+        .border_style(border_style);
+    let frame_area = match &tile.level {
+        Some(level) => {
+            let tile_chunks = split_tile(area);
+            f.render_widget(level_gauge(level, theme), tile_chunks[1]);
+            tile_chunks[0]
+        }
+        None => area,
+    };
+    // The tint fills the inside of the tile but leaves its border alone.
+    let inner = block.inner(frame_area);
+    f.render_widget(block, frame_area);
+    f.render_widget(
+        Paragraph::new(frame_text(&tile.frame, color_depth)).style(video_style),
+        inner,
+    );
+    // Float the reaction just inside the tile's top-right corner.
+    if let Some(reaction) = &tile.reaction {
+        let width =
+            (Line::from(reaction.as_str()).width() as u16 + 2).min(area.width.saturating_sub(2));
+        if width > 0 && area.height > 2 {
+            let badge = Rect::new(area.right() - 1 - width, area.y + 1, width, 1);
+            f.render_widget(Clear, badge);
+            f.render_widget(
+                Paragraph::new(reaction.as_str()).alignment(Alignment::Center),
+                badge,
+            );
+        }
+    }
+}
+
+// A recording that fails to write is dropped rather than failing the draw.
+fn record_frame(recorder: &mut Option<Recorder<BufWriter<File>>>, buffer: &Buffer) {
+    if let Some(Err(e)) = recorder.as_mut().map(|recorder| recorder.frame(buffer)) {
+        log::warn!("Stopped recording: {}", e);
+        *recorder = None;
+    }
+}
+
+// Splits a chat line to fit `width` columns, breaking at spaces where it
+// can. Lines after the first are indented past a short "name: " prefix so a
+// message reads as one block.
+fn wrap_chat_line(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let indent = text
+        .find(": ")
+        .map(|i| Line::from(&text[..i + 2]).width())
+        .filter(|indent| *indent <= width / 3)
+        .unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_width = 0;
+    let mut line_start = 0; // the indent, after the first line
+    let mut break_line = |line: &mut String, line_width: &mut usize, line_start: &mut usize| {
+        lines.push(line.trim_end().to_string());
+        *line = " ".repeat(indent);
+        *line_width = indent;
+        *line_start = indent;
+    };
+    for word in text.split_inclusive(' ') {
+        let word_width = Line::from(word.trim_end_matches(' ')).width();
+        if line_width + word_width > width && line_width > line_start {
+            break_line(&mut line, &mut line_width, &mut line_start);
+        }
+        // Words too long for a line of their own are split anywhere.
+        for c in word.chars() {
+            let char_width = Line::from(c.encode_utf8(&mut [0; 4]) as &str).width();
+            if line_width + char_width > width {
+                if c == ' ' {
+                    continue;
+                }
+                if line_width > line_start {
+                    break_line(&mut line, &mut line_width, &mut line_start);
+                }
+            }
+            line.push(c);
+            line_width += char_width;
+        }
+    }
+    lines.push(line.trim_end().to_string());
+    lines
+}
+
+// The screen as plain text, one line per row, without trailing blanks.
+fn buffer_text(buffer: &Buffer) -> String {
+    let mut text = String::new();
+    for y in buffer.area.top()..buffer.area.bottom() {
+        let mut line = String::new();
+        let mut x = buffer.area.left();
+        while x < buffer.area.right() {
+            let symbol = buffer.get(x, y).symbol();
+            line.push_str(symbol);
+            // A wide character covers the cells after it too.
+            x += (Line::from(symbol).width() as u16).max(1);
+        }
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+// Frames are plain text apart from halfblock cells, which are drawn as upper
+// half blocks in the cell's two colors, as near as the terminal can show them.
+fn frame_text(frame: &str, color_depth: ColorDepth) -> Text<'_> {
+    frame
+        .lines()
+        .map(|line| {
+            let mut spans = Vec::new();
+            let mut plain_start = 0;
+            for (i, c) in line.char_indices() {
+                if let Some((top, bottom)) = video::halfblock_colors(c) {
+                    if plain_start < i {
+                        spans.push(Span::raw(&line[plain_start..i]));
+                    }
+                    spans.push(halfblock_span(top, bottom, color_depth));
+                    plain_start = i + c.len_utf8();
+                }
+            }
+            if plain_start < line.len() {
+                spans.push(Span::raw(&line[plain_start..]));
+            }
+            Line::from(spans)
+        })
+        .collect()
+}
+
+fn halfblock_span(top: u8, bottom: u8, color_depth: ColorDepth) -> Span<'static> {
+    let (top, bottom) = match color_depth {
+        ColorDepth::Palette256 | ColorDepth::TrueColor => {
+            (Color::Indexed(top), Color::Indexed(bottom))
+        }
+        ColorDepth::Basic => (theme::basic_color(top), theme::basic_color(bottom)),
+        // The pair's average brightness, as a character from the default ramp.
+        ColorDepth::Mono => {
+            let luma = (video::xterm_luma(top) as usize + video::xterm_luma(bottom) as usize) / 2;
+            let ramp = video::ASCII_CHARS;
+            return Span::raw(ramp[luma * (ramp.len() - 1) / 255].to_string());
+        }
+    };
+    Span::styled("\u{2580}", Style::default().fg(top).bg(bottom))
+}
+
+// Splits an area into roughly square cells, filling row by row. Rows are
+// preferred over columns since frames are wider than they are tall.
+fn grid(area: Rect, count: usize) -> Vec<Rect> {
+    let rows = (count as f64).sqrt().ceil().max(1.0) as usize;
+    let cols = count.div_ceil(rows).max(1);
+    let row_areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Ratio(1, rows as u32); rows])
+        .split(area);
+    row_areas
+        .iter()
+        .flat_map(|row| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints(vec![Constraint::Ratio(1, cols as u32); cols])
+                .split(*row)
+                .to_vec()
+        })
+        .take(count)
+        .collect()
+}
+
+// Room left for a frame in a tile once the borders and level meter are drawn.
+fn frame_size(area: Rect) -> (u16, u16) {
+    let frame_area = split_tile(area)[0];
+    (
+        frame_area.width.saturating_sub(2),
+        frame_area.height.saturating_sub(2),
+    )
+}
+
+// Splits a video tile into the frame area and a one-line level meter below it.
+fn split_tile(area: Rect) -> std::rc::Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .split(area)
+}
+
+fn level_gauge(meter: &LevelMeter, theme: &Theme) -> Gauge<'static> {
+    Gauge::default()
+        .gauge_style(theme.meter)
+        .ratio(meter.level().clamp(0.0, 1.0) as f64)
+        .label(format!("{:.0} dB", meter.rms_db()))
+}
+
 impl Drop for Tui {
     fn drop(&mut self) {
-        disable_raw_mode().unwrap();
-        execute!(
-            self.terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )
-        .unwrap();
-        self.terminal.show_cursor().unwrap();
+        if !self.restore_on_drop {
+            return;
+        }
+        if let Err(e) = restore_terminal() {
+            log::warn!("Failed to restore terminal: {}", e);
+        }
+    }
+}
+
+// Leaves raw mode and the alternate screen. Shared by Drop and the panic
+// hook, so it must not assume a Tui still exists.
+pub fn restore_terminal() -> io::Result<()> {
+    disable_raw_mode()?;
+    if ALT_SCREEN.swap(false, Ordering::SeqCst) {
+        execute!(io::stdout(), LeaveAlternateScreen)?;
+    }
+    execute!(io::stdout(), DisableMouseCapture, cursor::Show)
+}
+
+// Restores the terminal before the panic message is printed, otherwise it
+// lands on the alternate screen and the shell is left in raw mode.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_speaker_layout_waits_before_switching() {
+        let mut follow = SpeakerFollow::default();
+        let start = Instant::now();
+        assert_eq!(follow.next(None, None, start), None);
+        // The first talker is focused at once.
+        assert_eq!(follow.next(Some("a"), None, start), Some("a".to_string()));
+        assert_eq!(follow.next(Some("a"), Some("a"), start), None);
+
+        // A brief interruption doesn't take the focus.
+        let later = start + SPEAKER_SWITCH_AFTER / 2;
+        assert_eq!(follow.next(Some("b"), Some("a"), start), None);
+        assert_eq!(follow.next(Some("a"), Some("a"), later), None);
+        assert_eq!(follow.next(Some("b"), Some("a"), later), None);
+        assert_eq!(
+            follow.next(Some("b"), Some("a"), later + SPEAKER_SWITCH_AFTER / 2),
+            None
+        );
+        assert_eq!(
+            follow.next(Some("b"), Some("a"), later + SPEAKER_SWITCH_AFTER),
+            Some("b".to_string())
+        );
+    }
+
+    #[test]
+    fn link_quality_takes_the_worst_signal() {
+        let mut link = PeerLink {
+            rtt: Some(Duration::from_millis(40)),
+            ..PeerLink::default()
+        };
+        assert_eq!(link.quality(), LinkQuality::Good);
+        assert_eq!(link.summary(), "good 40ms");
+        link.relayed = true;
+        assert_eq!(link.summary(), "fair 40ms, relayed");
+        link.on_frames(90, 10);
+        assert_eq!(link.summary(), "poor 40ms, relayed, 10% loss");
+        link.missed_heartbeats = 1;
+        assert_eq!(link.quality(), LinkQuality::Reconnecting);
+
+        // Old losses fade once enough frames arrive.
+        let mut link = PeerLink::default();
+        link.on_frames(90, 10);
+        for _ in 0..LOSS_WINDOW * 4 {
+            link.on_frames(1, 0);
+        }
+        assert_eq!(link.quality(), LinkQuality::Good);
+    }
+
+    #[test]
+    fn chat_lines_wrap_under_the_name() {
+        assert_eq!(
+            wrap_chat_line("Ann: the quick brown fox jumps", 16),
+            ["Ann: the quick", "     brown fox", "     jumps"]
+        );
+        assert_eq!(wrap_chat_line("short", 16), ["short"]);
+        // A long name isn't worth indenting past.
+        assert_eq!(
+            wrap_chat_line("Annabelle: hi there", 12),
+            ["Annabelle:", "hi there"]
+        );
+        // Words longer than a line are split.
+        assert_eq!(
+            wrap_chat_line("Ann: abcdefghijklmnop", 15),
+            ["Ann:", "     abcdefghij", "     klmnop"]
+        );
+    }
+
+    fn frame(peer_id: &str, seq: u64, frame: FrameUpdate) -> FrameData {
+        FrameData {
+            peer_id: peer_id.to_string(),
+            seq,
+            frame,
+            is_audio_muted: false,
+            is_video_muted: false,
+        }
+    }
+
+    #[test]
+    fn a_delta_that_doesnt_fit_waits_for_a_keyframe() {
+        let mut tui = Tui::headless();
+        assert!(tui.update_frame(frame("a", 1, FrameUpdate::Key("abc".into()))));
+        assert!(tui.update_frame(frame("a", 2, FrameUpdate::Delta(vec![(9, 'x')]))));
+        apply_pending_deltas(
+            &mut tui.remote_frames,
+            &mut tui.pending_deltas,
+            &mut tui.awaiting_keyframe,
+            "a",
+        );
+        assert_eq!(tui.remote_frames["a"].0, "abc");
+
+        // Follows on from the failed one, but builds on a frame we never drew.
+        assert!(!tui.update_frame(frame("a", 3, FrameUpdate::Delta(vec![(0, 'x')]))));
+        assert!(tui.update_frame(frame("a", 4, FrameUpdate::Key("def".into()))));
+        assert!(tui.update_frame(frame("a", 5, FrameUpdate::Delta(vec![(0, 'x')]))));
+        apply_pending_deltas(
+            &mut tui.remote_frames,
+            &mut tui.pending_deltas,
+            &mut tui.awaiting_keyframe,
+            "a",
+        );
+        assert_eq!(tui.remote_frames["a"].0, "xef");
+    }
+
+    #[test]
+    fn halfblock_cells_fit_what_the_terminal_can_show() {
+        let frame = format!("a{}", video::halfblock_cell(196, 231));
+        let cell = |color_depth| frame_text(&frame, color_depth).lines[0].spans[1].clone();
+        assert_eq!(
+            cell(ColorDepth::Palette256).style,
+            Style::default()
+                .fg(Color::Indexed(196))
+                .bg(Color::Indexed(231))
+        );
+        assert_eq!(
+            cell(ColorDepth::Basic).style,
+            Style::default().fg(Color::LightRed).bg(Color::White)
+        );
+        let mono = cell(ColorDepth::Mono);
+        assert_eq!(mono.content, "+");
+        assert_eq!(mono.style, Style::default());
+    }
+
+    #[test]
+    fn clicks_land_on_what_was_drawn_there() {
+        let mut tui = Tui::headless();
+        tui.click_areas = vec![
+            (Rect::new(0, 0, 40, 20), ClickTarget::Peer("a".into())),
+            (Rect::new(40, 0, 40, 20), ClickTarget::Peer("b".into())),
+            (Rect::new(0, 18, 80, 3), ClickTarget::Input),
+        ];
+        assert_eq!(tui.click_target(0, 0), Some(ClickTarget::Peer("a".into())));
+        assert_eq!(
+            tui.click_target(39, 19),
+            Some(ClickTarget::Peer("a".into()))
+        );
+        // Right and bottom edges belong to the next area.
+        assert_eq!(tui.click_target(40, 0), Some(ClickTarget::Peer("b".into())));
+        assert_eq!(tui.click_target(5, 20), Some(ClickTarget::Input));
+        // Where areas overlap, the one drawn first wins.
+        assert_eq!(tui.click_target(5, 18), Some(ClickTarget::Peer("a".into())));
+        assert_eq!(tui.click_target(80, 0), None);
+        assert_eq!(tui.click_target(0, 21), None);
+    }
+
+    #[test]
+    fn chat_keeps_the_newest_messages_up_to_the_cap() {
+        let mut tui = Tui::headless();
+        tui.chat_history = 3;
+        tui.push_own_message("first".into(), 1);
+        for i in 2..=5 {
+            tui.push_message(format!("message {}", i));
+        }
+        let texts: Vec<&str> = tui.messages.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["message 3", "message 4", "message 5"]);
+        // A receipt for a message that was trimmed has nothing to mark.
+        assert!(!tui.mark_seen(1));
+    }
+
+    #[test]
+    fn downloads_move_through_their_states_by_id() {
+        let mut tui = Tui::headless();
+        let state = |tui: &Tui, id| {
+            let download = tui.downloads.iter().find(|download| download.id == id);
+            download.map(|download| download.state.clone())
+        };
+        let first = tui.add_download("a.txt", "peer");
+        let second = tui.add_download("b.txt", "peer");
+        assert_ne!(first, second);
+        assert_eq!(state(&tui, first), Some(FileDownloadState::Downloading));
+
+        assert!(tui.update_download(second, FileDownloadState::Completed("/tmp/b.txt".into())));
+        assert!(tui.update_download(first, FileDownloadState::Failed("hash mismatch".into())));
+        assert_eq!(
+            state(&tui, first),
+            Some(FileDownloadState::Failed("hash mismatch".into()))
+        );
+        assert_eq!(
+            state(&tui, second),
+            Some(FileDownloadState::Completed("/tmp/b.txt".into()))
+        );
+        assert!(!tui.update_download(second + 1, FileDownloadState::Downloading));
+
+        let upload = tui.add_upload("c.txt", 10);
+        assert_eq!(
+            state(&tui, upload),
+            Some(FileDownloadState::Uploading(0, 10))
+        );
+        tui.finish_upload(upload, &Ok::<(), String>(()));
+        assert_eq!(
+            state(&tui, upload),
+            Some(FileDownloadState::Uploading(10, 10))
+        );
+        // Only uploads still in flight are finished.
+        tui.finish_upload(first, &Err("too late"));
+        assert_eq!(
+            state(&tui, first),
+            Some(FileDownloadState::Failed("hash mismatch".into()))
+        );
+    }
+
+    #[test]
+    fn input_history_steps_through_sent_messages_and_back_to_the_draft() {
+        let mut tui = Tui::headless();
+        tui.remember_input("one");
+        tui.remember_input("two");
+        tui.remember_input("two");
+        tui.remember_input("  ");
+        tui.input = "draft".into();
+
+        tui.history_up();
+        assert_eq!(tui.input, "two");
+        tui.history_up();
+        assert_eq!(tui.input, "one");
+        // Nothing older: stays put.
+        tui.history_up();
+        assert_eq!(tui.input, "one");
+
+        tui.history_down();
+        assert_eq!(tui.input, "two");
+        tui.history_down();
+        assert_eq!(tui.input, "draft");
+        tui.history_down();
+        assert_eq!(tui.input, "draft");
+
+        // Sending resets the cursor, so Up starts from the newest again.
+        tui.history_up();
+        tui.reset_history_cursor();
+        tui.input.clear();
+        tui.history_up();
+        assert_eq!(tui.input, "two");
     }
 }
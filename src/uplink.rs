@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+// Weight of the newest frame in the video drop rate, about the last second
+// of frames at the top frame rate.
+const DROP_RATE_SMOOTHING: f32 = 0.05;
+
+// Token bucket for --max-uplink-kbps. Audio, chat and files always go out
+// and only run the bucket into debt; video is sent only while there's room
+// for it, so it's what gets dropped when the link is busy.
+pub struct UplinkBudget {
+    limit: Option<Bucket>,
+    video_drop_rate: f32,
+}
+
+struct Bucket {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    // Holds a second's worth of budget, and owes at most as much.
+    fn capacity(&self) -> f64 {
+        self.bytes_per_sec
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity());
+        self.last_refill = now;
+    }
+}
+
+impl UplinkBudget {
+    // Without a limit everything goes through.
+    pub fn new(max_kbps: Option<u32>, now: Instant) -> Self {
+        let limit = max_kbps.map(|kbps| {
+            let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+            Bucket {
+                bytes_per_sec,
+                tokens: bytes_per_sec,
+                last_refill: now,
+            }
+        });
+        Self {
+            limit,
+            video_drop_rate: 0.0,
+        }
+    }
+
+    pub fn spend(&mut self, bytes: usize, now: Instant) {
+        if let Some(bucket) = &mut self.limit {
+            bucket.refill(now);
+            bucket.tokens = (bucket.tokens - bytes as f64).max(-bucket.capacity());
+        }
+    }
+
+    // Returns whether a video frame of this size may be sent now. A full
+    // bucket lets any frame through, so one bigger than the whole budget
+    // still goes out now and then.
+    pub fn try_spend_video(&mut self, bytes: usize, now: Instant) -> bool {
+        let allowed = match &mut self.limit {
+            Some(bucket) => {
+                bucket.refill(now);
+                let allowed = bucket.tokens >= bytes as f64 || bucket.tokens >= bucket.capacity();
+                if allowed {
+                    bucket.tokens -= bytes as f64;
+                }
+                allowed
+            }
+            None => true,
+        };
+        let dropped = if allowed { 0.0 } else { 1.0 };
+        self.video_drop_rate += (dropped - self.video_drop_rate) * DROP_RATE_SMOOTHING;
+        allowed
+    }
+
+    // Recent share of video frames dropped, from 0 to 1.
+    pub fn video_drop_rate(&self) -> f32 {
+        self.video_drop_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn video_is_dropped_once_the_budget_is_spent() {
+        let start = Instant::now();
+        // 8 kbps is 1000 bytes a second.
+        let mut uplink = UplinkBudget::new(Some(8), start);
+        assert!(uplink.try_spend_video(600, start));
+        assert!(!uplink.try_spend_video(600, start));
+        assert!(uplink.try_spend_video(600, start + Duration::from_millis(200)));
+        assert!(uplink.video_drop_rate() > 0.0);
+    }
+
+    #[test]
+    fn audio_debt_holds_back_video() {
+        let start = Instant::now();
+        let mut uplink = UplinkBudget::new(Some(8), start);
+        uplink.spend(5000, start);
+        // The debt is capped at a second's budget, which has to be paid off
+        // before any video goes out.
+        let later = start + Duration::from_millis(1500);
+        assert!(!uplink.try_spend_video(600, later));
+        assert!(uplink.try_spend_video(600, later + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn no_limit_never_drops() {
+        let start = Instant::now();
+        let mut uplink = UplinkBudget::new(None, start);
+        uplink.spend(usize::MAX, start);
+        assert!(uplink.try_spend_video(usize::MAX, start));
+        assert_eq!(uplink.video_drop_rate(), 0.0);
+    }
+}
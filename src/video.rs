@@ -1,89 +1,766 @@
 use fast_image_resize as fr;
-use image::{DynamicImage, ImageBuffer, Rgb};
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
 use nokhwa::{
     pixel_format::RgbFormat,
     utils::{
-        CameraFormat, CameraIndex, FrameFormat, RequestedFormat, RequestedFormatType, Resolution,
+        ApiBackend, CameraFormat, CameraIndex, FrameFormat, RequestedFormat, RequestedFormatType,
+        Resolution,
     },
     Camera,
 };
+use serde::Deserialize;
 use std::error::Error;
 use std::num::NonZeroU32;
 
 pub const ASCII_CHARS: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
 pub const OUTPUT_WIDTH: u32 = 80;
-pub const OUTPUT_HEIGHT: u32 = 40;
+// Just tall enough for 4:3 camera video at CHAR_ASPECT, so nothing is cropped.
+pub const OUTPUT_HEIGHT: u32 = 30;
+// Bounds for a resolution fitted to the terminal. Past the maximum, frames
+// cost more to send than they add on screen.
+pub const MIN_FIT_WIDTH: u32 = 16;
+pub const MIN_FIT_HEIGHT: u32 = 8;
+pub const MAX_FIT_WIDTH: u32 = 240;
+pub const MAX_FIT_HEIGHT: u32 = 80;
+// Terminal cells are roughly twice as tall as they are wide.
+pub const CHAR_ASPECT: f32 = 2.0;
+// Sobel magnitude below which a pixel isn't drawn as an edge.
+const EDGE_THRESHOLD: f32 = 128.0;
+
+// Filter used to shrink camera frames. Area averages every source pixel a
+// cell covers; nearest samples just one, so fine detail flickers in and out.
+#[derive(Deserialize, clap::ValueEnum, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    // Cheapest, for low-end machines.
+    #[default]
+    Nearest,
+    Area,
+    Bilinear,
+    Lanczos,
+}
+
+impl ResizeFilter {
+    fn algorithm(self) -> fr::ResizeAlg {
+        match self {
+            ResizeFilter::Nearest => fr::ResizeAlg::Nearest,
+            // A box filter scaled to the shrink factor is a plain area average.
+            ResizeFilter::Area => fr::ResizeAlg::Convolution(fr::FilterType::Box),
+            ResizeFilter::Bilinear => fr::ResizeAlg::Convolution(fr::FilterType::Bilinear),
+            ResizeFilter::Lanczos => fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3),
+        }
+    }
+}
+
+// How a frame's pixels are drawn in the terminal.
+#[derive(Deserialize, clap::ValueEnum, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    // One pixel per cell, as a character from the brightness ramp.
+    #[default]
+    Ascii,
+    // Two pixels per cell, stacked: an upper half block in the top pixel's
+    // color over the bottom pixel's color.
+    Halfblock,
+    // Eight pixels per cell, 2 wide and 4 tall, each a braille dot that's
+    // raised where the pixel is brighter than the frame's average.
+    Braille,
+}
+
+impl RenderMode {
+    // Pixels across and down that one cell shows.
+    fn pixels_per_cell(self) -> (u32, u32) {
+        match self {
+            RenderMode::Ascii => (1, 1),
+            RenderMode::Halfblock => (1, 2),
+            RenderMode::Braille => (2, 4),
+        }
+    }
+}
+
+// A halfblock cell travels as a single character from the supplementary
+// private use area carrying the two colors' xterm-256 indices, so frames stay
+// strings and deltas and compression work unchanged.
+const HALFBLOCK_BASE: u32 = 0xF0000;
+// The six levels of each channel in the xterm color cube (indices 16-231).
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+// The 16 basic colors as xterm draws them by default. Terminals retheme
+// them, so this only picks the closest for terminals without the cube.
+const BASIC_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [205, 0, 0],
+    [0, 205, 0],
+    [205, 205, 0],
+    [0, 0, 238],
+    [205, 0, 205],
+    [0, 205, 205],
+    [229, 229, 229],
+    [127, 127, 127],
+    [255, 0, 0],
+    [0, 255, 0],
+    [255, 255, 0],
+    [92, 92, 255],
+    [255, 0, 255],
+    [0, 255, 255],
+    [255, 255, 255],
+];
+
+const BRAILLE_BASE: u32 = 0x2800;
+// Bit of each dot in a braille code point, by row then column.
+const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+// How captured frames are turned into text.
+#[derive(Debug, Clone)]
+pub struct AsciiOptions {
+    pub width: u32,
+    pub height: u32,
+    pub ramp: Vec<char>, // darkest to brightest
+    pub dither: bool,
+    pub invert: bool,     // map dark pixels to dense characters
+    pub edges: bool,      // draw outlines instead of brightness
+    pub char_aspect: f32, // cell height over cell width
+    pub resize: ResizeFilter,
+    pub render: RenderMode,
+}
+
+// Resolution that fills a video pane of the given size in cells.
+pub fn fit_resolution(width: u16, height: u16) -> (u32, u32) {
+    (
+        (width as u32).clamp(MIN_FIT_WIDTH, MAX_FIT_WIDTH),
+        (height as u32).clamp(MIN_FIT_HEIGHT, MAX_FIT_HEIGHT),
+    )
+}
+
+// Formats asked for in turn until the camera takes one: what we'd like, then
+// progressively less picky. Whatever it delivers is decoded to RGB.
+fn camera_requests() -> [RequestedFormatType; 5] {
+    let preferred = Resolution::new(640, 480);
+    [
+        RequestedFormatType::Closest(CameraFormat::new(preferred, FrameFormat::MJPEG, 30)),
+        RequestedFormatType::Closest(CameraFormat::new(preferred, FrameFormat::YUYV, 30)),
+        RequestedFormatType::AbsoluteHighestFrameRate,
+        RequestedFormatType::AbsoluteHighestResolution,
+        RequestedFormatType::None,
+    ]
+}
 
 pub fn initialize_camera() -> Result<Camera, Box<dyn Error>> {
-    let index = CameraIndex::Index(0);
-    let requested = RequestedFormat::new::<RgbFormat>(RequestedFormatType::Closest(
-        CameraFormat::new(Resolution::new(640, 480), FrameFormat::MJPEG, 30),
-    ));
-    let camera = Camera::new(index, requested)?;
-    Ok(camera)
+    let mut last_error = None;
+    for request in camera_requests() {
+        match Camera::new(
+            CameraIndex::Index(0),
+            RequestedFormat::new::<RgbFormat>(request),
+        ) {
+            Ok(camera) => {
+                log::info!(
+                    "Camera format {} (asked for {})",
+                    camera.camera_format(),
+                    request
+                );
+                return Ok(camera);
+            }
+            Err(e) => {
+                log::debug!("Camera refused {}: {}", request, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.map_or_else(|| "No camera format to try".into(), Into::into))
 }
 
-pub fn capture_and_process_frame(camera: &mut Camera) -> Result<String, Box<dyn Error>> {
+// Human-readable names of the cameras the platform backend can see.
+pub fn list_cameras() -> Result<Vec<String>, Box<dyn Error>> {
+    let cameras = nokhwa::query(ApiBackend::Auto)?;
+    Ok(cameras
+        .iter()
+        .map(|info| format!("[{}] {}", info.index(), info.human_name()))
+        .collect())
+}
+
+pub fn capture_and_process_frame(
+    camera: &mut Camera,
+    options: &AsciiOptions,
+) -> Result<String, Box<dyn Error>> {
     let frame = camera.frame()?;
     let decoded = frame.decode_image::<RgbFormat>()?;
+    image_to_ascii(&DynamicImage::ImageRgb8(decoded), options)
+}
 
-    let original_image = DynamicImage::ImageRgb8(decoded);
+// The whole conversion without the camera: crop to the shape the output
+// cells will show on screen, downscale to the output size, then render with
+// whichever mode the options ask for.
+pub fn image_to_ascii(
+    image: &DynamicImage,
+    options: &AsciiOptions,
+) -> Result<String, Box<dyn Error>> {
     let src_image = fr::Image::from_vec_u8(
-        NonZeroU32::new(original_image.width()).unwrap(),
-        NonZeroU32::new(original_image.height()).unwrap(),
-        original_image.to_rgb8().into_raw(),
+        NonZeroU32::new(image.width()).ok_or("Input image is empty")?,
+        NonZeroU32::new(image.height()).ok_or("Input image is empty")?,
+        image.to_rgb8().into_raw(),
         fr::PixelType::U8x3,
     )?;
 
+    let width = NonZeroU32::new(options.width).ok_or("Output width must be non-zero")?;
+    let height = NonZeroU32::new(options.height).ok_or("Output height must be non-zero")?;
+    let (cell_width, cell_height) = options.render.pixels_per_cell();
     let mut dst_image = fr::Image::new(
-        NonZeroU32::new(OUTPUT_WIDTH).unwrap(),
-        NonZeroU32::new(OUTPUT_HEIGHT).unwrap(),
+        width.saturating_mul(NonZeroU32::new(cell_width).unwrap()),
+        height.saturating_mul(NonZeroU32::new(cell_height).unwrap()),
         fr::PixelType::U8x3,
     );
 
-    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Nearest);
-    resizer.resize(&src_image.view(), &mut dst_image.view_mut())?;
+    // W x H cells look like a W by H * char_aspect box, so crop the source to
+    // that rather than squashing it.
+    let mut src_view = src_image.view();
+    let shown_height = (options.height as f32 * options.char_aspect).round() as u32;
+    src_view.set_crop_box_to_fit_dst_size(
+        width,
+        NonZeroU32::new(shown_height.max(1)).unwrap(),
+        None,
+    );
+
+    let mut resizer = fr::Resizer::new(options.resize.algorithm());
+    resizer.resize(&src_view, &mut dst_image.view_mut())?;
 
-    let image_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> =
-        ImageBuffer::from_vec(OUTPUT_WIDTH, OUTPUT_HEIGHT, dst_image.buffer().to_vec())
-            .ok_or("Failed to create image buffer")?;
+    let image_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_vec(
+        dst_image.width().get(),
+        dst_image.height().get(),
+        dst_image.buffer().to_vec(),
+    )
+    .ok_or("Failed to create image buffer")?;
 
-    Ok(to_ascii(&DynamicImage::ImageRgb8(image_buffer)))
+    let image = match options.render {
+        RenderMode::Ascii => DynamicImage::ImageRgb8(image_buffer),
+        RenderMode::Halfblock => return Ok(to_halfblock(&image_buffer)),
+        RenderMode::Braille => {
+            return Ok(to_braille(
+                &DynamicImage::ImageRgb8(image_buffer),
+                options.invert,
+            ))
+        }
+    };
+    if options.edges {
+        return Ok(to_edge_ascii(&image, &options.ramp));
+    }
+    Ok(to_ascii(
+        &image,
+        &options.ramp,
+        options.dither,
+        options.invert,
+    ))
 }
 
-pub fn create_no_camera_frame() -> Result<String, Box<dyn Error>> {
+// Brightness used to draw placeholder shapes; dim so the label stands out.
+const PLACEHOLDER_INTENSITY: u8 = 96;
+
+// Shown when there's no camera to capture from: an empty outline.
+pub fn create_no_camera_frame(options: &AsciiOptions) -> Result<String, Box<dyn Error>> {
+    placeholder_frame(options, "No camera", |x, y, w, h| {
+        x == 0 || y == 0 || x + 1 == w || y + 1 == h
+    })
+}
+
+// Shown when the user turned their video off: a cross over the tile, so it
+// can't be mistaken for a missing camera.
+pub fn create_video_off_frame(options: &AsciiOptions) -> Result<String, Box<dyn Error>> {
+    placeholder_frame(options, "Video Off", |x, y, w, h| {
+        // Compare in unit coordinates so the cross spans non-square tiles.
+        let u = x as f32 / w as f32;
+        let v = y as f32 / h as f32;
+        let thickness = 1.0 / h as f32;
+        (u - v).abs() < thickness || (u + v - 1.0).abs() < thickness
+    })
+}
+
+fn placeholder_frame(
+    options: &AsciiOptions,
+    label: &str,
+    shape: impl Fn(u32, u32, u32, u32) -> bool,
+) -> Result<String, Box<dyn Error>> {
+    let (width, height) = (options.width, options.height);
+    let image = ImageBuffer::from_fn(width, height, |x, y| {
+        if shape(x, y, width, height) {
+            Luma([PLACEHOLDER_INTENSITY])
+        } else {
+            Luma([0])
+        }
+    });
+    // Placeholders are laid out in character space (blank background, faint
+    // shape, plain label), so they skip dithering and inversion. Otherwise an
+    // inverted ramp would turn the background into a wall of dense glyphs
+    // around the label.
+    let ascii_art = to_ascii(
+        &DynamicImage::ImageLuma8(image),
+        &options.ramp,
+        false,
+        false,
+    );
+
+    // Write the label over the middle row.
+    let label: Vec<char> = label.chars().take(width as usize).collect();
+    let padding = (width as usize - label.len()) / 2;
+    let mut lines: Vec<String> = ascii_art.lines().map(str::to_string).collect();
+    if let Some(line) = lines.get_mut(height as usize / 2) {
+        let mut chars: Vec<char> = line.chars().collect();
+        chars[padding..padding + label.len()].copy_from_slice(&label);
+        *line = chars.into_iter().collect();
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+fn to_ascii(image: &DynamicImage, ramp: &[char], dither: bool, invert: bool) -> String {
+    let gray_image = image.to_luma8();
+    let (width, height) = gray_image.dimensions();
+    let levels = ramp.len() - 1;
+    let intensity_of = |luma: u8| if invert { 255 - luma } else { luma };
     let mut ascii_art = String::new();
-    let no_camera_text = "No camera";
-    let padding = (OUTPUT_WIDTH - no_camera_text.len() as u32) / 2;
-    for y in 0..OUTPUT_HEIGHT {
-        if y == OUTPUT_HEIGHT / 2 {
-            ascii_art.push_str(&" ".repeat(padding as usize));
-            ascii_art.push_str(no_camera_text);
-            ascii_art.push_str(&" ".repeat(padding as usize));
-            if (OUTPUT_WIDTH - no_camera_text.len() as u32) % 2 != 0 {
-                ascii_art.push(' ');
+
+    if !dither {
+        for y in 0..height {
+            for x in 0..width {
+                let intensity = intensity_of(gray_image.get_pixel(x, y)[0]) as usize;
+                ascii_art.push(ramp[(intensity * levels) / 255]);
+            }
+            ascii_art.push('\n');
+        }
+        return ascii_art;
+    }
+
+    // Floyd-Steinberg: quantize each pixel to the nearest ramp level and push
+    // the rounding error onto the neighbours that haven't been visited yet.
+    let (w, h) = (width as usize, height as usize);
+    let mut luma: Vec<f32> = gray_image
+        .pixels()
+        .map(|p| intensity_of(p[0]) as f32)
+        .collect();
+    let step = 255.0 / levels as f32;
+    for y in 0..h {
+        for x in 0..w {
+            let old = luma[y * w + x].clamp(0.0, 255.0);
+            let index = ((old / step).round() as usize).min(levels);
+            let error = old - index as f32 * step;
+            ascii_art.push(ramp[index]);
+
+            if x + 1 < w {
+                luma[y * w + x + 1] += error * 7.0 / 16.0;
+            }
+            if y + 1 < h {
+                if x > 0 {
+                    luma[(y + 1) * w + x - 1] += error * 3.0 / 16.0;
+                }
+                luma[(y + 1) * w + x] += error * 5.0 / 16.0;
+                if x + 1 < w {
+                    luma[(y + 1) * w + x + 1] += error / 16.0;
+                }
             }
-        } else {
-            ascii_art.push_str(&" ".repeat(OUTPUT_WIDTH as usize));
         }
         ascii_art.push('\n');
     }
-    Ok(ascii_art)
+    ascii_art
 }
 
-fn to_ascii(image: &DynamicImage) -> String {
+fn to_halfblock(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> String {
+    let (width, height) = image.dimensions();
+    let mut frame = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let top = xterm_index(*image.get_pixel(x, y));
+            let bottom = xterm_index(*image.get_pixel(x, (y + 1).min(height - 1)));
+            frame.push(halfblock_cell(top, bottom));
+        }
+        frame.push('\n');
+    }
+    frame
+}
+
+fn to_braille(image: &DynamicImage, invert: bool) -> String {
     let gray_image = image.to_luma8();
+    let (width, height) = gray_image.dimensions();
+    // A fixed threshold would leave a dim room blank, so split at the mean.
+    let threshold = gray_image.pixels().map(|p| p[0] as u64).sum::<u64>()
+        / (width as u64 * height as u64).max(1);
+    let mut frame = String::new();
+    for y in (0..height).step_by(4) {
+        for x in (0..width).step_by(2) {
+            let mut bits = 0;
+            for (dy, row) in BRAILLE_DOTS.iter().enumerate() {
+                for (dx, bit) in row.iter().enumerate() {
+                    let (px, py) = (x + dx as u32, y + dy as u32);
+                    if px >= width || py >= height {
+                        continue;
+                    }
+                    let bright = gray_image.get_pixel(px, py)[0] as u64 > threshold;
+                    if bright != invert {
+                        bits |= bit;
+                    }
+                }
+            }
+            frame.push(char::from_u32(BRAILLE_BASE + bits as u32).expect("braille code point"));
+        }
+        frame.push('\n');
+    }
+    frame
+}
+
+pub fn halfblock_cell(top: u8, bottom: u8) -> char {
+    char::from_u32(HALFBLOCK_BASE + ((top as u32) << 8 | bottom as u32))
+        .expect("private use area code point")
+}
+
+// The xterm-256 indices of a halfblock cell's top and bottom pixels, or None
+// for an ordinary character.
+pub fn halfblock_colors(c: char) -> Option<(u8, u8)> {
+    let code = (c as u32).checked_sub(HALFBLOCK_BASE)?;
+    (code <= 0xFFFF).then_some(((code >> 8) as u8, code as u8))
+}
+
+// Nearest xterm-256 color: the 6x6x6 cube or the 24-step gray ramp,
+// whichever is closer. The first 16 are left alone since terminals retheme
+// them.
+pub fn xterm_index(pixel: Rgb<u8>) -> u8 {
+    let nearest_level = |value: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - value as i32).abs())
+            .unwrap()
+    };
+    let [r, g, b] = pixel.0;
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube = [CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]];
+    // Gray ramp entries are 8, 18, ..., 238.
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_step = (average.saturating_sub(3) / 10).min(23);
+    let gray_level = (8 + gray_step * 10) as u8;
+    if color_distance([gray_level; 3], pixel.0) < color_distance(cube, pixel.0) {
+        232 + gray_step as u8
+    } else {
+        16 + (36 * ri + 6 * gi + bi) as u8
+    }
+}
+
+// The color an xterm-256 index stands for.
+fn xterm_rgb(index: u8) -> [u8; 3] {
+    match index {
+        0..=15 => BASIC_COLORS[index as usize],
+        16..=231 => {
+            let i = (index - 16) as usize;
+            [
+                CUBE_LEVELS[i / 36],
+                CUBE_LEVELS[i / 6 % 6],
+                CUBE_LEVELS[i % 6],
+            ]
+        }
+        _ => [8 + (index - 232) * 10; 3],
+    }
+}
+
+// The closest of the 16 basic colors to an xterm-256 one.
+pub fn basic_color(index: u8) -> u8 {
+    let color = xterm_rgb(index);
+    (0..BASIC_COLORS.len())
+        .min_by_key(|&i| color_distance(BASIC_COLORS[i], color))
+        .unwrap() as u8
+}
+
+// How bright an xterm-256 color is, from 0 to 255.
+pub fn xterm_luma(index: u8) -> u8 {
+    let [r, g, b] = xterm_rgb(index);
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+fn color_distance(a: [u8; 3], b: [u8; 3]) -> i32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+        .sum()
+}
+
+// Line-art rendering: a Sobel filter finds edges, and each edge pixel gets a
+// glyph matching the edge's direction. Everything else is the ramp's
+// blank character.
+fn to_edge_ascii(image: &DynamicImage, ramp: &[char]) -> String {
+    let gray_image = image.to_luma8();
+    let (width, height) = gray_image.dimensions();
+    // Clamp at the borders so the frame edge itself isn't an edge.
+    let luma = |x: i64, y: i64| {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        gray_image.get_pixel(x, y)[0] as f32
+    };
     let mut ascii_art = String::new();
 
-    for y in 0..gray_image.height() {
-        for x in 0..gray_image.width() {
-            let pixel = gray_image.get_pixel(x, y);
-            let intensity = pixel[0] as usize;
-            let char_index = (intensity * (ASCII_CHARS.len() - 1)) / 255;
-            ascii_art.push(ASCII_CHARS[char_index]);
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let gx = (luma(x + 1, y - 1) + 2.0 * luma(x + 1, y) + luma(x + 1, y + 1))
+                - (luma(x - 1, y - 1) + 2.0 * luma(x - 1, y) + luma(x - 1, y + 1));
+            let gy = (luma(x - 1, y + 1) + 2.0 * luma(x, y + 1) + luma(x + 1, y + 1))
+                - (luma(x - 1, y - 1) + 2.0 * luma(x, y - 1) + luma(x + 1, y - 1));
+            if gx.hypot(gy) < EDGE_THRESHOLD {
+                ascii_art.push(ramp[0]);
+                continue;
+            }
+            // The edge runs perpendicular to the gradient. y grows downwards,
+            // so a gradient towards the bottom right is a '/' edge.
+            let angle = gy.atan2(gx).to_degrees().rem_euclid(180.0);
+            ascii_art.push(match angle {
+                a if !(22.5..157.5).contains(&a) => '|',
+                a if a < 67.5 => '/',
+                a if a < 112.5 => '-',
+                _ => '\\',
+            });
         }
         ascii_art.push('\n');
     }
-
     ascii_art
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(width: u32, height: u32) -> AsciiOptions {
+        AsciiOptions {
+            width,
+            height,
+            ramp: ASCII_CHARS.to_vec(),
+            dither: false,
+            invert: false,
+            edges: false,
+            char_aspect: CHAR_ASPECT,
+            resize: ResizeFilter::Nearest,
+            render: RenderMode::Ascii,
+        }
+    }
+
+    // A camera-sized image so the pipeline has to downscale.
+    fn solid(luma: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_pixel(640, 480, Rgb([luma; 3])))
+    }
+
+    #[test]
+    fn solid_images_map_to_the_ends_of_the_ramp() {
+        let black = image_to_ascii(&solid(0), &options(80, 40)).unwrap();
+        let white = image_to_ascii(&solid(255), &options(80, 40)).unwrap();
+        assert_eq!(black, format!("{}\n", " ".repeat(80)).repeat(40));
+        assert_eq!(white, format!("{}\n", "@".repeat(80)).repeat(40));
+    }
+
+    #[test]
+    fn gradient_steps_through_the_ramp() {
+        // Ten bands, one per output column and ramp character.
+        let gradient =
+            ImageBuffer::from_fn(
+                640,
+                480,
+                |x, _| Rgb([((x / 64) * 255).div_ceil(9) as u8; 3]),
+            );
+        let ascii_art =
+            image_to_ascii(&DynamicImage::ImageRgb8(gradient), &options(10, 2)).unwrap();
+        let row: String = ASCII_CHARS.iter().collect();
+        assert_eq!(ascii_art, format!("{}\n{}\n", row, row));
+    }
+
+    #[test]
+    fn every_resize_filter_keeps_solid_colours() {
+        for resize in [
+            ResizeFilter::Nearest,
+            ResizeFilter::Area,
+            ResizeFilter::Bilinear,
+            ResizeFilter::Lanczos,
+        ] {
+            let options = AsciiOptions {
+                resize,
+                ..options(8, 4)
+            };
+            let white = image_to_ascii(&solid(255), &options).unwrap();
+            assert_eq!(white, "@@@@@@@@\n".repeat(4), "{:?}", resize);
+        }
+    }
+
+    #[test]
+    fn area_averages_detail_that_nearest_samples() {
+        // One-pixel stripes, far finer than a cell: on average mid grey.
+        let stripes = DynamicImage::ImageRgb8(ImageBuffer::from_fn(640, 480, |x, _| {
+            Rgb([if x % 2 == 0 { 0 } else { 255 }; 3])
+        }));
+        let render = |resize| {
+            let options = AsciiOptions {
+                resize,
+                ..options(8, 4)
+            };
+            image_to_ascii(&stripes, &options).unwrap()
+        };
+        let nearest = render(ResizeFilter::Nearest);
+        assert!(nearest.chars().all(|c| matches!(c, ' ' | '@' | '\n')));
+        let area = render(ResizeFilter::Area);
+        let middle = &ASCII_CHARS[ASCII_CHARS.len() / 2 - 1..=ASCII_CHARS.len() / 2];
+        assert!(
+            area.chars().all(|c| c == '\n' || middle.contains(&c)),
+            "{}",
+            area
+        );
+    }
+
+    #[test]
+    fn halfblock_cells_carry_the_top_and_bottom_colors() {
+        // Red over blue, split exactly at the middle row of pixels.
+        let split = DynamicImage::ImageRgb8(ImageBuffer::from_fn(640, 480, |_, y| {
+            if y < 240 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        }));
+        let options = AsciiOptions {
+            render: RenderMode::Halfblock,
+            ..options(4, 2)
+        };
+        let frame = image_to_ascii(&split, &options).unwrap();
+        let rows: Vec<Vec<(u8, u8)>> = frame
+            .lines()
+            .map(|line| line.chars().map(|c| halfblock_colors(c).unwrap()).collect())
+            .collect();
+        assert_eq!(rows, vec![vec![(196, 196); 4], vec![(21, 21); 4]]);
+        assert_eq!(halfblock_colors('@'), None);
+    }
+
+    #[test]
+    fn braille_dots_follow_the_bright_pixels() {
+        // Bright left half: every dot is raised in the two cells on the
+        // left, none in the two on the right.
+        let left = DynamicImage::ImageRgb8(ImageBuffer::from_fn(640, 480, |x, _| {
+            Rgb([if x < 320 { 255 } else { 0 }; 3])
+        }));
+        let options = AsciiOptions {
+            render: RenderMode::Braille,
+            ..options(4, 2)
+        };
+        let frame = image_to_ascii(&left, &options).unwrap();
+        assert_eq!(frame, "\u{28FF}\u{28FF}\u{2800}\u{2800}\n".repeat(2));
+
+        let inverted = AsciiOptions {
+            invert: true,
+            ..options
+        };
+        let frame = image_to_ascii(&left, &inverted).unwrap();
+        assert_eq!(frame, "\u{2800}\u{2800}\u{28FF}\u{28FF}\n".repeat(2));
+    }
+
+    #[test]
+    fn xterm_colors_prefer_the_gray_ramp_for_grays() {
+        assert_eq!(xterm_index(Rgb([0, 0, 0])), 16);
+        assert_eq!(xterm_index(Rgb([255, 255, 255])), 231);
+        assert_eq!(xterm_index(Rgb([128, 128, 128])), 244);
+        assert_eq!(xterm_index(Rgb([0, 255, 0])), 46);
+    }
+
+    #[test]
+    fn palette_colors_fall_back_to_basic_colors_and_brightness() {
+        assert_eq!(basic_color(16), 0);
+        assert_eq!(basic_color(231), 15);
+        assert_eq!(basic_color(196), 9);
+        assert_eq!(basic_color(244), 8);
+        assert_eq!(basic_color(4), 4);
+        assert_eq!(xterm_luma(16), 0);
+        assert_eq!(xterm_luma(231), 255);
+        assert_eq!(xterm_luma(244), 128);
+    }
+
+    #[test]
+    fn invert_swaps_the_ends_of_the_ramp() {
+        let inverted = AsciiOptions {
+            invert: true,
+            ..options(4, 2)
+        };
+        assert_eq!(
+            image_to_ascii(&solid(0), &inverted).unwrap(),
+            "@@@@\n@@@@\n"
+        );
+    }
+
+    #[test]
+    fn fitted_resolution_stays_within_bounds() {
+        assert_eq!(fit_resolution(100, 30), (100, 30));
+        assert_eq!(fit_resolution(2, 1), (MIN_FIT_WIDTH, MIN_FIT_HEIGHT));
+        assert_eq!(fit_resolution(500, 200), (MAX_FIT_WIDTH, MAX_FIT_HEIGHT));
+    }
+
+    #[test]
+    fn crops_to_the_shape_of_the_cells() {
+        // Bright bars either side of a centred black square.
+        let bars = ImageBuffer::from_fn(640, 480, |x, _| {
+            Rgb([if (80..560).contains(&x) { 0 } else { 255 }; 3])
+        });
+        let bars = DynamicImage::ImageRgb8(bars);
+        // 10x5 cells at 2:1 show as a square, so only the black part is kept.
+        let ascii_art = image_to_ascii(&bars, &options(10, 5)).unwrap();
+        assert_eq!(ascii_art, format!("{}\n", " ".repeat(10)).repeat(5));
+
+        let square_cells = AsciiOptions {
+            char_aspect: 1.0,
+            ..options(10, 5)
+        };
+        let ascii_art = image_to_ascii(&bars, &square_cells).unwrap();
+        assert!(ascii_art
+            .lines()
+            .all(|row| row.starts_with('@') && row.ends_with('@')));
+    }
+
+    // Average distance between each column's true brightness and the
+    // brightness its characters stand for. Banding shows up as large steps.
+    fn column_error(ascii_art: &str, ramp: &[char], gradient: &[u8]) -> f32 {
+        let rows: Vec<Vec<char>> = ascii_art.lines().map(|l| l.chars().collect()).collect();
+        let levels = (ramp.len() - 1) as f32;
+        let total: f32 = gradient
+            .iter()
+            .enumerate()
+            .map(|(x, &expected)| {
+                let mean = rows
+                    .iter()
+                    .map(|row| ramp.iter().position(|&c| c == row[x]).unwrap() as f32)
+                    .sum::<f32>()
+                    / rows.len() as f32;
+                (mean * 255.0 / levels - expected as f32).abs()
+            })
+            .sum();
+        total / gradient.len() as f32
+    }
+
+    #[test]
+    fn dithering_reduces_banding_on_a_gradient() {
+        let ramp = [' ', '+', '@'];
+        let gradient: Vec<u8> = (0..=255).collect();
+        let image = ImageBuffer::from_fn(gradient.len() as u32, 16, |x, _| {
+            Luma([gradient[x as usize]])
+        });
+        let image = DynamicImage::ImageLuma8(image);
+
+        let banded = column_error(&to_ascii(&image, &ramp, false, false), &ramp, &gradient);
+        let dithered = column_error(&to_ascii(&image, &ramp, true, false), &ramp, &gradient);
+        assert!(
+            dithered < banded / 2.0,
+            "dithered error {} should be well below banded error {}",
+            dithered,
+            banded
+        );
+    }
+
+    #[test]
+    fn edges_follow_the_edge_direction() {
+        let vertical = ImageBuffer::from_fn(8, 4, |x, _| Luma([if x < 4 { 0 } else { 255 }]));
+        let horizontal = ImageBuffer::from_fn(8, 4, |_, y| Luma([if y < 2 { 0 } else { 255 }]));
+
+        let vertical = to_edge_ascii(&DynamicImage::ImageLuma8(vertical), ASCII_CHARS);
+        let horizontal = to_edge_ascii(&DynamicImage::ImageLuma8(horizontal), ASCII_CHARS);
+        assert!(vertical.lines().all(|line| &line[3..5] == "||"));
+        assert!(horizontal.lines().nth(1).unwrap().chars().all(|c| c == '-'));
+        assert!(horizontal.lines().next().unwrap().trim().is_empty());
+    }
+
+    #[test]
+    fn dithering_keeps_the_frame_size() {
+        let image = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(7, 3, Luma([128])));
+        let ascii_art = to_ascii(&image, ASCII_CHARS, true, false);
+        assert_eq!(ascii_art.lines().count(), 3);
+        assert!(ascii_art.lines().all(|line| line.chars().count() == 7));
+    }
+}